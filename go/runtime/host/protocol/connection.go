@@ -27,9 +27,12 @@ const (
 	connWriteTimeout = 5 * time.Second
 )
 
-// ErrNotReady is the error reported when the Runtime Host Protocol is not initialized.
 var (
+	// ErrNotReady is the error reported when the Runtime Host Protocol is not initialized.
 	ErrNotReady = errors.New(moduleName, 1, "rhp: not ready")
+	// ErrAttestationUnavailable is the error reported by the runtime when it cannot sign
+	// results as its attestation is unavailable (e.g., re-attestation is in progress).
+	ErrAttestationUnavailable = errors.New(moduleName, 2, "rhp: attestation unavailable")
 
 	rhpLatency = prometheus.NewSummaryVec(
 		prometheus.SummaryOpts{
@@ -78,7 +78,9 @@ type Body struct {
 	RuntimeAbortRequest                   *Empty                                 `json:",omitempty"`
 	RuntimeAbortResponse                  *Empty                                 `json:",omitempty"`
 	RuntimeKeyManagerPolicyUpdateRequest  *RuntimeKeyManagerPolicyUpdateRequest  `json:",omitempty"`
-	RuntimeKeyManagerPolicyUpdateResponse *Empty                                 `json:",omitempty"`
+	RuntimeKeyManagerPolicyUpdateResponse *RuntimeKeyManagerPolicyUpdateResponse `json:",omitempty"`
+	RuntimeConsensusSyncRequest           *RuntimeConsensusSyncRequest           `json:",omitempty"`
+	RuntimeConsensusSyncResponse          *Empty                                 `json:",omitempty"`
 
 	// Host interface.
 	HostRPCCallRequest          *HostRPCCallRequest          `json:",omitempty"`
@@ -195,6 +197,10 @@ type ComputedBatch struct {
 	// If this runtime uses a TEE, then this is the signature of Header with
 	// node's RAK for this runtime.
 	RakSig signature.RawSignature `json:"rak_sig"`
+	// Partial is true iff the batch was aborted and only contains the
+	// results of a prefix of the input batch. In this case both the I/O and
+	// the state root only reflect the transactions in that prefix.
+	Partial bool `json:"partial,omitempty"`
 }
 
 // String returns a string representation of a computed batch.
@@ -211,6 +217,9 @@ type RuntimeExecuteTxBatchRequest struct {
 	Inputs transaction.RawBatch `json:"inputs"`
 	// Block on which the batch computation should be based.
 	Block roothash.Block `json:"block"`
+	// InputsHash is the optional hash of the inputs in the order used by the scheduler.
+	// It allows the runtime to reject misordered inputs before executing them.
+	InputsHash *hash.Hash `json:"inputs_hash,omitempty"`
 }
 
 // RuntimeExecuteTxBatchResponse is a worker execute tx batch response message body.
@@ -224,6 +233,20 @@ type RuntimeKeyManagerPolicyUpdateRequest struct {
 	SignedPolicyRaw []byte `json:"signed_policy_raw"`
 }
 
+// RuntimeKeyManagerPolicyUpdateResponse is a runtime key manager policy
+// response message body.
+type RuntimeKeyManagerPolicyUpdateResponse struct {
+	// Unchanged is true iff the policy was identical to the currently
+	// applied policy and was therefore not applied again.
+	Unchanged bool `json:"unchanged,omitempty"`
+}
+
+// RuntimeConsensusSyncRequest is a runtime consensus block synchronization
+// request message body.
+type RuntimeConsensusSyncRequest struct {
+	Height uint64 `json:"height"`
+}
+
 // HostRPCCallRequest is a host RPC call request message body.
 type HostRPCCallRequest struct {
 	Endpoint string `json:"endpoint"`
@@ -108,10 +108,11 @@ func (c *cache) newLeafNodePtr(n *node.LeafNode) *node.Pointer {
 	}
 }
 
-func (c *cache) newLeafNode(key node.Key, val []byte) *node.Pointer {
+func (c *cache) newLeafNode(key node.Key, val []byte, marker bool) *node.Pointer {
 	return c.newLeafNodePtr(&node.LeafNode{
-		Key:   key[:],
-		Value: val,
+		Key:    key[:],
+		Value:  val,
+		Marker: marker,
 	})
 }
 
@@ -109,8 +109,8 @@ func (t *tree) commitWithHooks(
 			continue
 		}
 
-		log = append(log, writelog.LogEntry{Key: entry.key, Value: entry.value})
-		if len(entry.value) == 0 {
+		log = append(log, writelog.LogEntry{Key: entry.key, Value: entry.value, Marker: entry.marker})
+		if len(entry.value) == 0 && !entry.marker {
 			logAnns = append(logAnns, writelog.LogEntryAnnotation{InsertedNode: nil})
 		} else {
 			logAnns = append(logAnns, writelog.LogEntryAnnotation{InsertedNode: entry.insertedLeaf})
@@ -84,8 +84,9 @@ func ReviveHashedDBWriteLogs(
 						return
 					}
 					newEntry = &writelog.LogEntry{
-						Key:   entry.Key,
-						Value: node.Value,
+						Key:    entry.Key,
+						Value:  node.Value,
+						Marker: node.Marker,
 					}
 				}
 				if err := pipe.Put(newEntry); err != nil {
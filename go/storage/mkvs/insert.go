@@ -10,6 +10,10 @@ import (
 
 // Implements Tree.
 func (t *tree) Insert(ctx context.Context, key, value []byte) error {
+	return t.insert(ctx, key, value, false)
+}
+
+func (t *tree) insert(ctx context.Context, key, value []byte, marker bool) error {
 	if value == nil {
 		value = []byte{}
 	}
@@ -25,7 +29,7 @@ func (t *tree) Insert(ctx context.Context, key, value []byte) error {
 	t.cache.markPosition()
 
 	var result insertResult
-	result, err := t.doInsert(ctx, t.cache.pendingRoot, 0, key, value, 0)
+	result, err := t.doInsert(ctx, t.cache.pendingRoot, 0, key, value, marker, 0)
 	if err != nil {
 		return err
 	}
@@ -37,11 +41,13 @@ func (t *tree) Insert(ctx context.Context, key, value []byte) error {
 			t.pendingWriteLog[node.ToMapKey(key)] = &pendingEntry{
 				key:          key,
 				value:        value,
+				marker:       marker,
 				existed:      result.existed,
 				insertedLeaf: result.insertedLeaf,
 			}
 		} else {
 			entry.value = value
+			entry.marker = marker
 		}
 	}
 
@@ -61,6 +67,7 @@ func (t *tree) doInsert(
 	bitDepth node.Depth,
 	key node.Key,
 	val []byte,
+	marker bool,
 	depth node.Depth,
 ) (insertResult, error) {
 	if ctx.Err() != nil {
@@ -78,7 +85,7 @@ func (t *tree) doInsert(
 	switch n := nd.(type) {
 	case nil:
 		// Insert into nil node, create a new leaf node.
-		newLeaf := t.cache.newLeafNode(key, val)
+		newLeaf := t.cache.newLeafNode(key, val, marker)
 		result := insertResult{
 			newRoot:      newLeaf,
 			insertedLeaf: newLeaf,
@@ -96,12 +103,12 @@ func (t *tree) doInsert(
 			if key.BitLength() == bitLength {
 				// Key to insert ends exactly at this node. Add it to the
 				// existing internal node as LeafNode.
-				result, err = t.doInsert(ctx, n.LeafNode, bitLength, key, val, depth)
+				result, err = t.doInsert(ctx, n.LeafNode, bitLength, key, val, marker, depth)
 			} else if key.GetBit(bitLength) {
 				// Insert recursively based on the bit value.
-				result, err = t.doInsert(ctx, n.Right, bitLength, key, val, depth+1)
+				result, err = t.doInsert(ctx, n.Right, bitLength, key, val, marker, depth+1)
 			} else {
-				result, err = t.doInsert(ctx, n.Left, bitLength, key, val, depth+1)
+				result, err = t.doInsert(ctx, n.Left, bitLength, key, val, marker, depth+1)
 			}
 
 			if err != nil {
@@ -148,7 +155,7 @@ func (t *tree) doInsert(
 		// No longer eligible for eviction as it is dirty.
 		t.cache.rollbackNode(ptr)
 
-		newLeaf := t.cache.newLeafNode(key, val)
+		newLeaf := t.cache.newLeafNode(key, val, marker)
 		var leafNode, left, right *node.Pointer
 
 		if key.BitLength()-bitDepth == cpLength {
@@ -176,7 +183,7 @@ func (t *tree) doInsert(
 	case *node.LeafNode:
 		// If the key matches, we can just update the value.
 		if n.Key.Equal(key) {
-			if bytes.Equal(n.Value, val) {
+			if bytes.Equal(n.Value, val) && n.Marker == marker {
 				return insertResult{
 					newRoot:      ptr,
 					insertedLeaf: ptr,
@@ -190,6 +197,7 @@ func (t *tree) doInsert(
 			}
 
 			n.Value = val
+			n.Marker = marker
 			n.Clean = false
 			ptr.Clean = false
 			// No longer eligible for eviction as it is dirty.
@@ -207,7 +215,7 @@ func (t *tree) doInsert(
 
 		// Key mismatches the label at position cpLength. Split the edge.
 		labelPrefix, _ := leafKeyRemainder.Split(cpLength, leafKeyRemainder.BitLength())
-		newLeaf := t.cache.newLeafNode(key, val)
+		newLeaf := t.cache.newLeafNode(key, val, marker)
 		result.insertedLeaf = newLeaf
 		var leafNode, left, right *node.Pointer
 
@@ -20,6 +20,10 @@ func (t *tree) Get(ctx context.Context, key []byte) ([]byte, error) {
 	// If the key has been modified locally, no need to perform any lookups.
 	if !t.withoutWriteLog {
 		if entry := t.pendingWriteLog[node.ToMapKey(key)]; entry != nil {
+			if entry.marker {
+				// Presence markers have no value.
+				return nil, nil
+			}
 			return entry.value, nil
 		}
 	}
@@ -188,6 +192,10 @@ func (t *tree) doGet(
 	case *node.LeafNode:
 		// Reached a leaf node, check if key matches.
 		if n.Key.Equal(key) {
+			if n.Marker {
+				// Presence markers have no value.
+				return nil, nil
+			}
 			return n.Value, nil
 		}
 	default:
@@ -26,6 +26,9 @@ var (
 const (
 	// Prefix used in hash computations of leaf nodes.
 	PrefixLeafNode byte = 0x00
+	// Flag set in the prefix of presence marker leaf nodes, distinguishing
+	// them from leaf nodes with an empty value.
+	PrefixLeafMarkerFlag byte = 0x80
 	// Prefix used in hash computations of internal nodes.
 	PrefixInternalNode byte = 0x01
 	// Prefix used to mark a nil pointer in a subtree serialization.
@@ -523,6 +526,17 @@ type LeafNode struct {
 	Hash    hash.Hash
 	Key     Key
 	Value   []byte
+	// Marker is true if the leaf is a presence marker without a value, in
+	// which case the value is always empty.
+	Marker bool
+}
+
+// prefix returns the prefix used in the encoding and hash of the node.
+func (n *LeafNode) prefix() byte {
+	if n.Marker {
+		return PrefixLeafNode | PrefixLeafMarkerFlag
+	}
+	return PrefixLeafNode
 }
 
 // IsClean returns true if the node is non-dirty.
@@ -555,7 +569,7 @@ func (n *LeafNode) UpdateHash() {
 	var version [VersionSize]byte
 	binary.LittleEndian.PutUint64(version[:], n.Version)
 
-	n.Hash.FromBytes([]byte{PrefixLeafNode}, version[:], n.Key[:], n.Value[:])
+	n.Hash.FromBytes([]byte{n.prefix()}, version[:], n.Key[:], n.Value[:])
 }
 
 // Extract makes a copy of the node containing only hash references.
@@ -575,6 +589,7 @@ func (n *LeafNode) ExtractUnchecked() Node {
 		Hash:    n.Hash,
 		Key:     n.Key,
 		Value:   n.Value,
+		Marker:  n.Marker,
 	}
 }
 
@@ -585,14 +600,22 @@ func (n *LeafNode) CompactMarshalBinary() (data []byte, err error) {
 		return nil, err
 	}
 
-	data = make([]byte, 1+VersionSize+len(keyData)+ValueLengthSize+len(n.Value))
+	if n.Marker {
+		// Presence markers have no value, not even an empty one.
+		data = make([]byte, 1+VersionSize+len(keyData))
+	} else {
+		data = make([]byte, 1+VersionSize+len(keyData)+ValueLengthSize+len(n.Value))
+	}
 	pos := 0
-	data[pos] = PrefixLeafNode
+	data[pos] = n.prefix()
 	pos++
 	binary.LittleEndian.PutUint64(data[pos:pos+VersionSize], n.Version)
 	pos += VersionSize
 	copy(data[pos:pos+len(keyData)], keyData)
 	pos += len(keyData)
+	if n.Marker {
+		return
+	}
 	binary.LittleEndian.PutUint32(data[pos:pos+ValueLengthSize], uint32(len(n.Value)))
 	pos += ValueLengthSize
 	copy(data[pos:], n.Value)
@@ -612,7 +635,15 @@ func (n *LeafNode) UnmarshalBinary(data []byte) error {
 
 // SizedUnmarshalBinary decodes a binary marshaled leaf node.
 func (n *LeafNode) SizedUnmarshalBinary(data []byte) (int, error) {
-	if len(data) < 1+VersionSize+DepthSize+ValueLengthSize || data[0] != PrefixLeafNode {
+	if len(data) < 1 || data[0]&^PrefixLeafMarkerFlag != PrefixLeafNode {
+		return 0, ErrMalformedNode
+	}
+	marker := data[0]&PrefixLeafMarkerFlag != 0
+	valueLengthSize := ValueLengthSize
+	if marker {
+		valueLengthSize = 0
+	}
+	if len(data) < 1+VersionSize+DepthSize+valueLengthSize {
 		return 0, ErrMalformedNode
 	}
 
@@ -626,23 +657,28 @@ func (n *LeafNode) SizedUnmarshalBinary(data []byte) (int, error) {
 		return 0, err
 	}
 	pos += keySize
-	if pos+ValueLengthSize > len(data) {
+	if pos+valueLengthSize > len(data) {
 		return 0, ErrMalformedNode
 	}
 
-	valueSize := int(binary.LittleEndian.Uint32(data[pos : pos+ValueLengthSize]))
-	pos += ValueLengthSize
-	if pos+valueSize > len(data) {
-		return 0, ErrMalformedNode
-	}
+	// Presence markers have no value, not even an empty one.
+	value := []byte{}
+	if !marker {
+		valueSize := int(binary.LittleEndian.Uint32(data[pos : pos+ValueLengthSize]))
+		pos += ValueLengthSize
+		if pos+valueSize > len(data) {
+			return 0, ErrMalformedNode
+		}
 
-	value := make([]byte, valueSize)
-	copy(value, data[pos:pos+valueSize])
-	pos += valueSize
+		value = make([]byte, valueSize)
+		copy(value, data[pos:pos+valueSize])
+		pos += valueSize
+	}
 
 	n.Clean = true
 	n.Key = key
 	n.Value = value
+	n.Marker = marker
 
 	n.UpdateHash()
 
@@ -663,7 +699,8 @@ func (n *LeafNode) Equal(other Node) bool {
 		}
 		return n.Version == other.Version &&
 			n.Key.Equal(other.Key) &&
-			bytes.Equal(n.Value, other.Value)
+			bytes.Equal(n.Value, other.Value) &&
+			n.Marker == other.Marker
 	}
 	return false
 }
@@ -675,7 +712,7 @@ func UnmarshalBinary(bytes []byte) (Node, error) {
 	var node Node
 	if len(bytes) > 1 {
 		switch bytes[0] {
-		case PrefixLeafNode:
+		case PrefixLeafNode, PrefixLeafNode | PrefixLeafMarkerFlag:
 			var leaf LeafNode
 			if err := leaf.UnmarshalBinary(bytes); err != nil {
 				return nil, err
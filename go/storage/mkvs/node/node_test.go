@@ -32,6 +32,39 @@ func TestSerializationLeafNode(t *testing.T) {
 	}
 }
 
+func TestSerializationMarkerLeafNode(t *testing.T) {
+	leafNode := &LeafNode{
+		Version: 0xDEADBEEF,
+		Key:     []byte("a golden key"),
+		Value:   []byte{},
+		Marker:  true,
+	}
+	leafNode.UpdateHash()
+
+	rawLeafNode, err := leafNode.MarshalBinary()
+	require.NoError(t, err, "MarshalBinary")
+
+	decodedNode, err := UnmarshalBinary(rawLeafNode)
+	require.NoError(t, err, "UnmarshalBinary")
+	decodedLeafNode, ok := decodedNode.(*LeafNode)
+	require.True(t, ok, "decoded node should be a leaf node")
+	require.True(t, decodedLeafNode.Marker)
+	require.Equal(t, leafNode.Key, decodedLeafNode.Key)
+	require.Equal(t, leafNode.Hash, decodedLeafNode.Hash)
+
+	// A leaf with an empty value must be distinct from a marker.
+	emptyLeafNode := &LeafNode{
+		Version: 0xDEADBEEF,
+		Key:     []byte("a golden key"),
+		Value:   []byte{},
+	}
+	emptyLeafNode.UpdateHash()
+	require.NotEqual(t, leafNode.Hash, emptyLeafNode.Hash)
+	rawEmptyLeafNode, err := emptyLeafNode.MarshalBinary()
+	require.NoError(t, err, "MarshalBinary")
+	require.NotEqual(t, rawLeafNode, rawEmptyLeafNode)
+}
+
 func TestSerializationInternalNode(t *testing.T) {
 	leafNode := &LeafNode{
 		Key:   []byte("a golden key"),
@@ -95,6 +128,18 @@ func TestHashLeafNode(t *testing.T) {
 	require.Equal(t, leafNode.Hash.String(), "1bf37ec60c5494775e7029ec2a888c42d14f9710852c86ffe0afab8e3c43b782")
 }
 
+func TestHashMarkerLeafNode(t *testing.T) {
+	leafNode := &LeafNode{
+		Version: 0xDEADBEEF,
+		Key:     []byte("a golden key"),
+		Marker:  true,
+	}
+
+	leafNode.UpdateHash()
+
+	require.Equal(t, leafNode.Hash.String(), "5c42cd1602f08107c2438f5579ff7d308ed391161b33d0ffc122d776286b7374")
+}
+
 func TestHashInternalNode(t *testing.T) {
 	leafNodeHash := hash.NewFromBytes([]byte("everyone stop here"))
 	leftHash := hash.NewFromBytes([]byte("everyone move to the left"))
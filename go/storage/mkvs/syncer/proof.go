@@ -9,6 +9,10 @@ import (
 	"github.com/oasisprotocol/oasis-core/go/storage/mkvs/node"
 )
 
+// ProofVersion is the version of the proof format produced by this
+// implementation.
+const ProofVersion = 0
+
 const (
 	// proofEntryFull is the proof entry type for full nodes.
 	proofEntryFull byte = 0x01
@@ -18,6 +22,8 @@ const (
 
 // Proof is a Merkle proof for a subtree.
 type Proof struct {
+	// V is the proof format version.
+	V uint16 `json:"v,omitempty"`
 	// UntrustedRoot is the root hash this proof is for. This should only be
 	// used as a quick sanity check and proof verification MUST use an
 	// independently obtained root hash as the prover can provide any root.
@@ -161,6 +167,12 @@ type ProofVerifier struct {
 // VerifyProof verifies a proof and generates an in-memory subtree representing
 // the nodes which are included in the proof.
 func (pv *ProofVerifier) VerifyProof(ctx context.Context, root hash.Hash, proof *Proof) (*node.Pointer, error) {
+	if proof.V != ProofVersion {
+		return nil, fmt.Errorf("verifier: unsupported proof version (expected: %d got: %d)",
+			ProofVersion,
+			proof.V,
+		)
+	}
 	// Sanity check that the proof is for the correct root (as otherwise it
 	// makes no sense to verify the proof).
 	if !proof.UntrustedRoot.Equal(&root) {
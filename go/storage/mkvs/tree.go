@@ -26,6 +26,7 @@ type tree struct {
 type pendingEntry struct {
 	key     []byte
 	value   []byte
+	marker  bool
 	existed bool
 
 	insertedLeaf *node.Pointer
@@ -130,7 +131,7 @@ func (t *tree) ApplyWriteLog(ctx context.Context, wl writelog.Iterator) error {
 		if entry.Value == nil {
 			err = t.Remove(ctx, entry.Key)
 		} else {
-			err = t.Insert(ctx, entry.Key, entry.Value)
+			err = t.insert(ctx, entry.Key, entry.Value, entry.Marker)
 		}
 		if err != nil {
 			return err
@@ -3,7 +3,9 @@ package writelog
 import (
 	"bytes"
 	"encoding/json"
+	"fmt"
 
+	"github.com/oasisprotocol/oasis-core/go/common/cbor"
 	"github.com/oasisprotocol/oasis-core/go/storage/mkvs/node"
 )
 
@@ -27,10 +29,14 @@ func (wl WriteLog) Equal(cmp WriteLog) bool {
 
 // LogEntry is a write log entry.
 type LogEntry struct {
-	_ struct{} `cbor:",toarray"` // nolint
-
 	Key   []byte
 	Value []byte
+	// Marker is true if the key was inserted as a presence marker without
+	// a value.
+	//
+	// It is only encoded when set, so write logs without markers are
+	// unchanged.
+	Marker bool
 }
 
 // Equal compares vs another log entry for equality.
@@ -41,21 +47,77 @@ func (k *LogEntry) Equal(cmp *LogEntry) bool {
 	if !bytes.Equal(k.Value, cmp.Value) {
 		return false
 	}
+	if k.Marker != cmp.Marker {
+		return false
+	}
 	return true
 }
 
+// MarshalCBOR encodes the log entry as a CBOR array, with the marker flag as
+// an optional third element.
+func (k *LogEntry) MarshalCBOR() ([]byte, error) {
+	if k.Marker {
+		return cbor.Marshal([]interface{}{k.Key, k.Value, true}), nil
+	}
+	return cbor.Marshal([]interface{}{k.Key, k.Value}), nil
+}
+
+// UnmarshalCBOR decodes a CBOR-encoded log entry.
+func (k *LogEntry) UnmarshalCBOR(data []byte) error {
+	var elems []cbor.RawMessage
+	if err := cbor.Unmarshal(data, &elems); err != nil {
+		return err
+	}
+	if len(elems) != 2 && len(elems) != 3 {
+		return fmt.Errorf("writelog: malformed log entry with %d elements", len(elems))
+	}
+
+	var entry LogEntry
+	if err := cbor.Unmarshal(elems[0], &entry.Key); err != nil {
+		return err
+	}
+	if err := cbor.Unmarshal(elems[1], &entry.Value); err != nil {
+		return err
+	}
+	if len(elems) == 3 {
+		if err := cbor.Unmarshal(elems[2], &entry.Marker); err != nil {
+			return err
+		}
+	}
+	*k = entry
+
+	return nil
+}
+
 func (k *LogEntry) MarshalJSON() ([]byte, error) {
+	if k.Marker {
+		return json.Marshal([]interface{}{k.Key, k.Value, true})
+	}
 	return json.Marshal([2][]byte{k.Key, k.Value})
 }
 
 func (k *LogEntry) UnmarshalJSON(src []byte) error {
-	var kv [2][]byte
-	if err := json.Unmarshal(src, &kv); err != nil {
+	var elems []json.RawMessage
+	if err := json.Unmarshal(src, &elems); err != nil {
 		return err
 	}
+	if len(elems) != 2 && len(elems) != 3 {
+		return fmt.Errorf("writelog: malformed log entry with %d elements", len(elems))
+	}
 
-	k.Key = kv[0]
-	k.Value = kv[1]
+	var entry LogEntry
+	if err := json.Unmarshal(elems[0], &entry.Key); err != nil {
+		return err
+	}
+	if err := json.Unmarshal(elems[1], &entry.Value); err != nil {
+		return err
+	}
+	if len(elems) == 3 {
+		if err := json.Unmarshal(elems[2], &entry.Marker); err != nil {
+			return err
+		}
+	}
+	*k = entry
 
 	return nil
 }
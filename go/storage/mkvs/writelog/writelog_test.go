@@ -0,0 +1,33 @@
+package writelog
+
+import (
+	"testing"
+
+	"github.com/stretchr/testify/require"
+
+	"github.com/oasisprotocol/oasis-core/go/common/cbor"
+)
+
+func TestLogEntrySerialization(t *testing.T) {
+	wl := WriteLog{
+		{Key: []byte("foo"), Value: []byte("bar")},
+		{Key: []byte("deleted"), Value: nil},
+		{Key: []byte("marker"), Value: []byte{}, Marker: true},
+	}
+
+	// Entries without a marker keep the two element encoding.
+	require.Equal(t, []byte{0x82, 0x43, 'f', 'o', 'o', 0x43, 'b', 'a', 'r'}, cbor.Marshal(&wl[0]))
+	// Markers are encoded as an additional element.
+	require.Equal(t, []byte{0x83, 0x46, 'm', 'a', 'r', 'k', 'e', 'r', 0x40, 0xf5}, cbor.Marshal(&wl[2]))
+
+	var decoded WriteLog
+	err := cbor.Unmarshal(cbor.Marshal(wl), &decoded)
+	require.NoError(t, err, "Unmarshal")
+	require.True(t, wl.Equal(decoded), "decoded write log should be equal")
+	require.Nil(t, decoded[1].Value, "deletes should decode to a nil value")
+	require.True(t, decoded[2].Marker, "markers should round-trip")
+
+	var entry LogEntry
+	err = cbor.Unmarshal([]byte{0x81, 0x43, 'f', 'o', 'o'}, &entry)
+	require.Error(t, err, "Unmarshal should fail for malformed entries")
+}
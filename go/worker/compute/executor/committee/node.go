@@ -987,11 +987,13 @@ func (n *Node) startProcessingBatchLocked(batch *unresolvedBatch) {
 			)
 			return
 		}
+		inputsHash := hash.NewFrom(resolvedBatch)
 		rq := &protocol.Body{
 			RuntimeExecuteTxBatchRequest: &protocol.RuntimeExecuteTxBatchRequest{
-				IORoot: batch.ioRoot.Hash,
-				Inputs: resolvedBatch,
-				Block:  *blk,
+				IORoot:     batch.ioRoot.Hash,
+				Inputs:     resolvedBatch,
+				Block:      *blk,
+				InputsHash: &inputsHash,
 			},
 		}
 		batchReadTime.With(n.getMetricLabels()).Observe(time.Since(readStartTime).Seconds())
@@ -1037,6 +1039,12 @@ func (n *Node) startProcessingBatchLocked(batch *unresolvedBatch) {
 			)
 			return
 		}
+		if rsp.RuntimeExecuteTxBatchResponse.Batch.Partial {
+			// Commitments always cover the whole batch, so results for only a
+			// prefix of the batch cannot be submitted.
+			n.logger.Error("runtime returned partial batch results, discarding")
+			return
+		}
 
 		// Submit response to the executor worker.
 		done <- &processedBatch{
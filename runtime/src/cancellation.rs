@@ -0,0 +1,148 @@
+//! Cancellation helpers.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use io_context::Context;
+
+const CANCEL_SIGNALS_KEY: &'static str = "OASIS_CANCEL_SIGNALS";
+const DEADLINE_KEY: &'static str = "OASIS_DEADLINE";
+
+/// A signal that can be used to cancel all operations running under a
+/// `Context` (and any of its children).
+#[derive(Clone, Debug, Default)]
+pub struct CancelSignal(Arc<AtomicBool>);
+
+impl CancelSignal {
+    /// Create a new (not yet cancelled) signal.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel all operations using this signal.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Return true iff the signal has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Add a new cancellation signal to the provided `Context` and return it.
+///
+/// Signals attached to any of the parent contexts remain in effect, so
+/// cancelling a parent also cancels the child.
+pub fn add_cancel_signal(ctx: &mut Context) -> CancelSignal {
+    let signal = CancelSignal::new();
+    let mut signals: Vec<CancelSignal> = ctx
+        .get_value(CANCEL_SIGNALS_KEY)
+        .cloned()
+        .unwrap_or_default();
+    signals.push(signal.clone());
+    ctx.add_value(CANCEL_SIGNALS_KEY, signals);
+
+    signal
+}
+
+/// Check whether the provided `Context` (or any of its parents) has been
+/// cancelled.
+pub fn is_cancelled(ctx: &Context) -> bool {
+    let signals: Option<&Vec<CancelSignal>> = ctx.get_value(CANCEL_SIGNALS_KEY);
+    signals
+        .map(|signals| signals.iter().any(|s| s.is_cancelled()))
+        .unwrap_or(false)
+}
+
+/// Set a deadline on the provided `Context`, after which all operations
+/// running under it (and any of its children) should be abandoned.
+///
+/// A deadline inherited from a parent context is only replaced if the new
+/// deadline is earlier.
+pub fn set_deadline(ctx: &mut Context, deadline: Instant) {
+    let deadline = match get_deadline(ctx) {
+        Some(current) if current < deadline => current,
+        _ => deadline,
+    };
+    ctx.add_value(DEADLINE_KEY, deadline);
+}
+
+/// Return the deadline of the provided `Context`, if any.
+pub fn get_deadline(ctx: &Context) -> Option<Instant> {
+    ctx.get_value(DEADLINE_KEY).cloned()
+}
+
+/// Check whether the provided `Context` has been cancelled or its deadline
+/// has been exceeded.
+pub fn is_done(ctx: &Context) -> bool {
+    if is_cancelled(ctx) {
+        return true;
+    }
+    get_deadline(ctx)
+        .map(|deadline| Instant::now() >= deadline)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_cancel_propagation() {
+        let mut ctx = Context::background();
+        assert!(!is_cancelled(&ctx), "no signal means not cancelled");
+
+        let parent_signal = add_cancel_signal(&mut ctx);
+        let ctx = ctx.freeze();
+        let mut child = Context::create_child(&ctx);
+        let child_signal = add_cancel_signal(&mut child);
+        assert!(!is_cancelled(&child));
+
+        child_signal.cancel();
+        assert!(is_cancelled(&child), "child should be cancelled");
+        assert!(!is_cancelled(&ctx), "parent should not be cancelled");
+
+        let mut child = Context::create_child(&ctx);
+        add_cancel_signal(&mut child);
+        parent_signal.cancel();
+        assert!(
+            is_cancelled(&child),
+            "cancelling the parent should cancel the child"
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut ctx = Context::background();
+        assert!(get_deadline(&ctx).is_none());
+        assert!(!is_done(&ctx), "no deadline means not done");
+
+        let later = Instant::now() + Duration::from_secs(3600);
+        set_deadline(&mut ctx, later);
+        assert_eq!(get_deadline(&ctx), Some(later));
+        assert!(!is_done(&ctx));
+
+        // A child can only make the deadline earlier.
+        let ctx = ctx.freeze();
+        let mut child = Context::create_child(&ctx);
+        set_deadline(&mut child, later + Duration::from_secs(1));
+        assert_eq!(get_deadline(&child), Some(later));
+
+        let mut child = Context::create_child(&ctx);
+        set_deadline(&mut child, Instant::now());
+        assert!(is_done(&child), "exceeded deadline means done");
+        assert!(!is_done(&ctx), "parent should not be done");
+
+        // Cancellation also counts as done.
+        let mut child = Context::create_child(&ctx);
+        add_cancel_signal(&mut child).cancel();
+        assert!(is_done(&child));
+    }
+}
@@ -1,5 +1,9 @@
 //! Logging subsystem for runtimes.
-use std::sync::{Mutex, Once};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, Once},
+    time::Instant,
+};
 
 use lazy_static::lazy_static;
 use log::Level;
@@ -36,3 +40,92 @@ pub fn init_logger(level: Level) {
         let _log_guard = slog_stdlog::init_with_level(level).unwrap();
     });
 }
+
+/// Rate limiter for repetitive log messages, e.g., errors caused by a
+/// misbehaving client.
+///
+/// Each kind of message has its own token bucket, holding up to `burst`
+/// tokens and refilled at `burst` tokens per second. A message may only be
+/// logged if a token is available. Suppressed messages are counted and the
+/// count is reported with the next message of the same kind that is logged.
+pub struct LogRateLimiter {
+    burst: u32,
+    buckets: Mutex<HashMap<&'static str, LogBucket>>,
+}
+
+struct LogBucket {
+    tokens: f64,
+    last_refill: Instant,
+    suppressed: u64,
+}
+
+impl LogRateLimiter {
+    /// Create a new rate limiter allowing up to `burst` messages of each
+    /// kind per second.
+    pub fn new(burst: u32) -> Self {
+        Self {
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a message of the given kind may be logged.
+    ///
+    /// Returns the number of messages of this kind suppressed since the last
+    /// one that was logged, or `None` if this message should be suppressed.
+    pub fn check(&self, kind: &'static str) -> Option<u64> {
+        self.check_at(kind, Instant::now())
+    }
+
+    fn check_at(&self, kind: &'static str, now: Instant) -> Option<u64> {
+        let burst = self.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(kind).or_insert_with(|| LogBucket {
+            tokens: burst,
+            last_refill: now,
+            suppressed: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * burst).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            bucket.suppressed += 1;
+            return None;
+        }
+        bucket.tokens -= 1.0;
+        Some(std::mem::take(&mut bucket.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_log_rate_limiter() {
+        let limiter = LogRateLimiter::new(2);
+        let start = Instant::now();
+
+        // The burst is allowed, further messages are suppressed.
+        assert_eq!(limiter.check_at("a", start), Some(0));
+        assert_eq!(limiter.check_at("a", start), Some(0));
+        assert_eq!(limiter.check_at("a", start), None);
+        assert_eq!(limiter.check_at("a", start), None);
+
+        // Kinds are limited independently.
+        assert_eq!(limiter.check_at("b", start), Some(0));
+
+        // Tokens are refilled over time and suppressed messages reported.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at("a", later), Some(2));
+        assert_eq!(limiter.check_at("a", later), None);
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(limiter.check_at("a", much_later), Some(1));
+        assert_eq!(limiter.check_at("a", much_later), Some(0));
+        assert_eq!(limiter.check_at("a", much_later), None);
+    }
+}
@@ -170,4 +170,40 @@ mod tests {
             Hash::from("374021bcba44f1014d0d9919e876a1ecd7fe5ec1a92ecf9c8b313cd4976fbc01")
         );
     }
+
+    #[test]
+    fn test_canonical_encoding_compute_results_header() {
+        // Same fields as ComputeResultsHeader, declared in a different order.
+        #[derive(Serialize)]
+        struct ReorderedHeader {
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            messages: Vec<Message>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            state_root: Option<Hash>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            io_root: Option<Hash>,
+            previous_hash: Hash,
+            round: u64,
+        }
+
+        let header = ComputeResultsHeader {
+            round: 42,
+            previous_hash: Hash::empty_hash(),
+            io_root: Some(Hash::empty_hash()),
+            state_root: Some(Hash::digest_bytes(b"state")),
+            messages: Vec::new(),
+        };
+        let reordered = ReorderedHeader {
+            messages: Vec::new(),
+            state_root: Some(Hash::digest_bytes(b"state")),
+            io_root: Some(Hash::empty_hash()),
+            previous_hash: Hash::empty_hash(),
+            round: 42,
+        };
+        assert_eq!(
+            cbor::to_vec(&header),
+            cbor::to_vec(&reordered),
+            "signed header encoding must not depend on field order"
+        );
+    }
 }
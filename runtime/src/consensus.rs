@@ -0,0 +1,19 @@
+//! Consensus layer state verification.
+use anyhow::Result;
+
+/// Verifier of consensus layer state.
+///
+/// A consensus verifier can be installed via the `Initializer`, in which case
+/// the dispatcher routes consensus sync requests from the host to it. The
+/// verifier is also made available to transactions via the transaction
+/// context so that they can read the verified consensus state.
+pub trait ConsensusVerifier: Send + Sync {
+    /// Verify and store the consensus state up to the given height.
+    ///
+    /// An error is returned in case the state at the given height can not be
+    /// verified, in which case the previously verified state is kept.
+    fn sync(&self, height: u64) -> Result<()>;
+
+    /// Return the latest verified height, if any.
+    fn latest_height(&self) -> Option<u64>;
+}
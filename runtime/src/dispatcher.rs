@@ -1,55 +1,127 @@
 //! Runtime call dispatcher.
+#[cfg(not(target_env = "sgx"))]
 use std::{
+    any::Any,
+    backtrace::Backtrace,
+    panic::{self, PanicInfo},
+    sync::Once,
+};
+use std::{
+    collections::{HashSet, VecDeque},
     convert::TryInto,
     process,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Condvar, Mutex,
     },
     thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as AnyContext, Result};
 use crossbeam::channel;
 use io_context::Context;
 use slog::Logger;
+use thiserror::Error;
 
 use crate::{
+    cancellation::{self, CancelSignal},
     common::{
         cbor,
         crypto::{
             hash::Hash,
             signature::{Signature, Signer},
         },
-        logger::get_logger,
-        roothash::{Block, ComputeResultsHeader, COMPUTE_RESULTS_HEADER_CONTEXT},
+        logger::{get_logger, LogRateLimiter},
+        roothash::{
+            Block, ComputeResultsHeader, Message as RoothashMessage, Namespace,
+            COMPUTE_RESULTS_HEADER_CONTEXT,
+        },
     },
+    consensus::ConsensusVerifier,
     enclave_rpc::{
+        codec::{CborCodec, Codec as RpcCodec},
         demux::Demux as RpcDemux,
-        dispatcher::Dispatcher as RpcDispatcher,
-        types::{Message as RpcMessage, Request as RpcRequest},
+        dispatcher::{Dispatcher as RpcDispatcher, KmPolicyInfo},
+        types::Message as RpcMessage,
         Context as RpcContext,
     },
-    protocol::{Protocol, ProtocolUntrustedLocalStorage},
+    protocol::{Protocol, ProtocolUntrustedLocalStorage, MAX_MESSAGE_SIZE},
     rak::RAK,
     storage::{
         mkvs::{
             sync::{HostReadSyncer, NoopReadSyncer},
-            Root, Tree,
+            CacheStats, Root, RootReceiver, RootWatchers, Tree, WriteLog,
         },
         StorageContext,
     },
+    tracing,
     transaction::{
-        dispatcher::{Dispatcher as TxnDispatcher, NoopDispatcher as TxnNoopDispatcher},
+        dispatcher::{Dispatcher as TxnDispatcher, GasMeter, NoopDispatcher as TxnNoopDispatcher},
+        tags::Tags,
         tree::Tree as TxnTree,
         types::TxnBatch,
-        Context as TxnContext,
+        Context as TxnContext, PreviousStateSource,
     },
     types::{Body, ComputedBatch},
 };
 
-/// Maximum amount of requests that can be in the dispatcher queue.
+/// Default maximum amount of requests that can be in the dispatcher queue.
 const BACKLOG_SIZE: usize = 10;
+/// Number of consecutive requests queued while the queue was near capacity
+/// after which a warning is logged.
+const QUEUE_PRESSURE_WARN_INTERVAL: usize = 100;
+/// Maximum number of repetitive warnings or errors of each kind logged per
+/// second, see `LogRateLimiter`.
+const LOG_RATE_LIMIT: u32 = 10;
+/// Number of rounds between checks of the state tree depth, see
+/// `DispatcherBuilder::tree_depth_warning`.
+const TREE_DEPTH_SAMPLE_INTERVAL: u64 = 100;
+
+/// Capacity of a state cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheCapacity {
+    /// Maximum number of nodes held by the cache (0 means unlimited).
+    pub node_capacity: usize,
+    /// Maximum total size of values held by the cache, in bytes (0 means
+    /// unlimited).
+    pub value_capacity: usize,
+}
+
+impl Default for CacheCapacity {
+    fn default() -> Self {
+        Self {
+            node_capacity: 100_000,
+            value_capacity: 10_000_000,
+        }
+    }
+}
+
+/// Capacities of the dispatcher's state caches.
+///
+/// By default all caches hold up to 100_000 nodes and 10MB of values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheCapacities {
+    /// Capacity of the cache used when executing batches.
+    pub execute: CacheCapacity,
+    /// Capacity of the cache used when checking transactions.
+    pub check: CacheCapacity,
+}
+
+/// A snapshot of the dispatcher's metrics, see `Dispatcher::metrics_snapshot`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuntimeMetrics {
+    /// Number of requests waiting in the dispatcher queue.
+    pub queue_depth: usize,
+    /// Number of requests currently being dispatched.
+    pub in_flight: usize,
+    /// Round of the last committed executed batch, if any.
+    pub last_round: Option<u64>,
+    /// Statistics of the cache used when executing batches.
+    pub execute_cache: CacheStats,
+    /// Statistics of the cache used when checking transactions.
+    pub check_cache: CacheStats,
+}
 
 /// Interface for dispatcher initializers.
 pub trait Initializer: Send + Sync {
@@ -61,6 +133,50 @@ pub trait Initializer: Send + Sync {
         rpc_demux: &mut RpcDemux,
         rpc_dispatcher: &mut RpcDispatcher,
     ) -> Option<Box<dyn TxnDispatcher>>;
+
+    /// Returns an optional hook to be invoked after each executed batch has
+    /// been committed.
+    fn commit_hook(&self) -> Option<Box<dyn CommitHook>> {
+        None
+    }
+
+    /// Returns an optional sink to be invoked with each response just before
+    /// it is sent to the host.
+    fn response_sink(&self) -> Option<Box<dyn ResponseSink>> {
+        None
+    }
+
+    /// Returns an optional gas meter to be consulted for each transaction
+    /// in a batch. See `GasMeter` for details.
+    fn gas_meter(&self) -> Option<Arc<dyn GasMeter>> {
+        None
+    }
+
+    /// Returns an optional verifier of consensus layer state, which handles
+    /// consensus sync requests and is made available to transactions.
+    fn consensus_verifier(&self) -> Option<Arc<dyn ConsensusVerifier>> {
+        None
+    }
+
+    /// Called on the dispatch thread once the dispatchers and caches have
+    /// been set up, just before the first request is processed.
+    fn on_ready(&self) {}
+
+    /// Returns the capacities of the state caches, which can be used to size
+    /// them according to the workload (e.g., transaction checks usually touch
+    /// fewer and smaller values than batch execution).
+    fn cache_capacities(&self) -> CacheCapacities {
+        CacheCapacities::default()
+    }
+
+    /// Returns an optional codec to use for local RPC calls instead of CBOR.
+    ///
+    /// Remote RPC calls always use CBOR. Local RPC calls come from the host
+    /// and must be side-effect free, so an alternative format (e.g., JSON
+    /// for debugging tools) does not weaken any security guarantees.
+    fn local_rpc_codec(&self) -> Option<Arc<dyn RpcCodec>> {
+        None
+    }
 }
 
 impl<F> Initializer for F
@@ -85,6 +201,43 @@ where
     }
 }
 
+/// Hook invoked after the results of an executed batch have been committed.
+///
+/// The hook runs on the dispatch thread after the new state and I/O roots
+/// have been computed, but before the computed batch is sent to the host.
+/// It must therefore be fast and must not block.
+pub trait CommitHook {
+    /// Called with the round and the new state and I/O roots.
+    fn on_commit(&self, round: u64, state_root: Hash, io_root: Hash);
+}
+
+impl<F> CommitHook for F
+where
+    F: Fn(u64, Hash, Hash),
+{
+    fn on_commit(&self, round: u64, state_root: Hash, io_root: Hash) {
+        (*self)(round, state_root, io_root)
+    }
+}
+
+/// Sink receiving all responses sent by the dispatcher.
+///
+/// The sink runs on the dispatch thread just before each response is sent
+/// to the host, so it must be fast and must not block.
+pub trait ResponseSink: Send + Sync {
+    /// Called with the request identifier and the response body.
+    fn on_response(&self, id: u64, body: &Body);
+}
+
+impl<F> ResponseSink for F
+where
+    F: Fn(u64, &Body) + Send + Sync,
+{
+    fn on_response(&self, id: u64, body: &Body) {
+        (*self)(id, body)
+    }
+}
+
 type QueueItem = (Context, u64, Body);
 
 /// A guard that will abort the process if dropped while panicking.
@@ -92,32 +245,508 @@ type QueueItem = (Context, u64, Body);
 /// This is to ensure that the runtime will terminate in case there is
 /// a panic encountered during dispatch and the runtime is built with
 /// a non-abort panic handler.
-struct AbortOnPanic;
+///
+/// If a poison flag is set, the flag is raised instead of aborting.
+struct AbortOnPanic {
+    poisoned: Option<Arc<AtomicBool>>,
+}
 
 impl Drop for AbortOnPanic {
     fn drop(&mut self) {
         if thread::panicking() {
-            process::abort();
+            match self.poisoned {
+                Some(ref poisoned) => poisoned.store(true, Ordering::SeqCst),
+                None => process::abort(),
+            }
+        }
+    }
+}
+
+/// Ensures the panic hook is only installed once per process.
+#[cfg(not(target_env = "sgx"))]
+static PANIC_HOOK: Once = Once::new();
+
+/// Install a process-wide panic hook which logs the location, message and
+/// a backtrace of any panic before chaining to the previous hook.
+///
+/// As the hook runs before unwinding starts, panics during dispatch are
+/// logged before `AbortOnPanic` aborts the process or poisons the
+/// dispatcher.
+#[cfg(not(target_env = "sgx"))]
+fn install_panic_hook(logger: Logger) {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info: &PanicInfo| {
+            log_panic(&logger, info);
+            previous(info);
+        }));
+    });
+}
+
+/// Log the location, message and a backtrace of a panic.
+#[cfg(not(target_env = "sgx"))]
+fn log_panic(logger: &Logger, info: &PanicInfo) {
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+        .unwrap_or_default();
+    let thread = thread::current();
+    error!(logger, "Runtime panicked";
+        "location" => location,
+        "msg" => panic_message(info.payload()),
+        "thread" => thread.name().unwrap_or("<unnamed>"),
+        "backtrace" => %Backtrace::force_capture(),
+    );
+}
+
+/// Extract the message from a panic payload.
+#[cfg(not(target_env = "sgx"))]
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// Dispatcher error.
+#[derive(Error, Debug)]
+pub enum DispatcherError {
+    #[error("request queue is full")]
+    QueueFull,
+    #[error("dispatcher is paused")]
+    Paused,
+    #[error("response too large (size: {size} max: {max})")]
+    ResponseTooLarge { size: usize, max: usize },
+    #[error("dispatcher is read-only")]
+    ReadOnly,
+    #[error("root namespace mismatch (expected: {expected:?} got: {got:?})")]
+    NamespaceMismatch { expected: Namespace, got: Namespace },
+    #[error("attestation unavailable")]
+    AttestationUnavailable,
+    #[error("dispatcher is poisoned")]
+    Poisoned,
+    #[error("inputs inconsistent with scheduler order (expected hash: {expected:?} got: {got:?})")]
+    InputsMismatch { expected: Hash, got: Hash },
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+}
+
+/// Error module used for errors that the host decodes.
+const ERROR_MODULE: &str = "rhp/internal";
+/// Error code reported when compute results cannot be signed as the RAK is
+/// unavailable. The host may retry after re-attestation.
+const ERROR_CODE_ATTESTATION_UNAVAILABLE: u32 = 2;
+
+/// Sign a compute results header, reporting any signing failure as the
+/// attestation being unavailable.
+fn sign_compute_results_header(
+    signer: &dyn Signer,
+    header: &ComputeResultsHeader,
+) -> Result<Signature> {
+    signer
+        .sign(&COMPUTE_RESULTS_HEADER_CONTEXT, &cbor::to_vec(header))
+        .map_err(|error| error.context(DispatcherError::AttestationUnavailable))
+}
+
+/// State of the dispatch circuit breaker, see
+/// `DispatcherBuilder::circuit_breaker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Batches are executed normally.
+    Closed,
+    /// Batch execution requests are rejected until the cooldown has elapsed.
+    Open,
+    /// The cooldown has elapsed and a single batch is let through to probe
+    /// whether dispatching has recovered.
+    HalfOpen,
+}
+
+/// A circuit breaker that rejects batch execution requests after a number of
+/// consecutive dispatch failures.
+struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    inner: Mutex<CircuitBreakerInner>,
+}
+
+#[derive(Default)]
+struct CircuitBreakerInner {
+    consecutive_failures: usize,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            inner: Mutex::new(CircuitBreakerInner::default()),
+        }
+    }
+
+    fn state_at(&self, now: Instant) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(_) if inner.probing => CircuitState::HalfOpen,
+            Some(opened_at) if now.saturating_duration_since(opened_at) >= self.cooldown => {
+                CircuitState::HalfOpen
+            }
+            Some(_) => CircuitState::Open,
+        }
+    }
+
+    /// Check whether a batch may be dispatched. Once the cooldown has
+    /// elapsed, a single probe is let through until its outcome is recorded.
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => true,
+            Some(_) if inner.probing => false,
+            Some(opened_at) if now.saturating_duration_since(opened_at) >= self.cooldown => {
+                inner.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn record_success(&self) {
+        *self.inner.lock().unwrap() = CircuitBreakerInner::default();
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.probing = false;
+        inner.consecutive_failures += 1;
+        // A failed probe opens the circuit again for another cooldown.
+        if inner.opened_at.is_some() || inner.consecutive_failures >= self.failure_threshold {
+            inner.opened_at = Some(now);
+        }
+    }
+}
+
+/// A small LRU cache of recently computed batches, keyed by round and I/O
+/// root, used to avoid re-executing batches that the host resends.
+struct ComputedBatchCache {
+    capacity: usize,
+    entries: VecDeque<((u64, Hash), ComputedBatch)>,
+}
+
+impl ComputedBatchCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn get(&mut self, round: u64, io_root: Hash) -> Option<ComputedBatch> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(key, _)| *key == (round, io_root))?;
+        let entry = self.entries.remove(index).unwrap();
+        let batch = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(batch)
+    }
+
+    fn insert(&mut self, round: u64, io_root: Hash, batch: ComputedBatch) {
+        if !self.is_enabled() {
+            return;
         }
+
+        self.entries.retain(|(key, _)| *key != (round, io_root));
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(((round, io_root), batch));
+    }
+}
+
+#[derive(Default)]
+struct PauseState {
+    /// Number of outstanding pause guards.
+    pauses: usize,
+    /// Number of requests currently being dispatched.
+    in_flight: usize,
+}
+
+/// A guard that keeps the dispatcher paused while it is held.
+///
+/// Dropping the guard resumes normal operation.
+pub struct PauseGuard<'a> {
+    dispatcher: &'a Dispatcher,
+}
+
+impl<'a> Drop for PauseGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.dispatcher.pause_state.lock().unwrap();
+        state.pauses -= 1;
+        self.dispatcher.pause_cond.notify_all();
+    }
+}
+
+/// A guard that accounts for a request being dispatched while it is held.
+///
+/// Dropping the guard, including while unwinding from a panic during
+/// dispatch, marks the request as finished.
+struct InFlightGuard<'a> {
+    dispatcher: &'a Dispatcher,
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.dispatcher.pause_state.lock().unwrap();
+        state.in_flight -= 1;
+        self.dispatcher.pause_cond.notify_all();
     }
 }
 
+/// State owned by whoever processes requests, i.e. the dispatch thread or a
+/// `SyncDispatcher`.
+struct DispatchState {
+    protocol: Arc<Protocol>,
+    rpc_demux: RpcDemux,
+    rpc_dispatcher: RpcDispatcher,
+    local_rpc_codec: Arc<dyn RpcCodec>,
+    txn_dispatcher: Box<dyn TxnDispatcher>,
+    commit_hook: Option<Box<dyn CommitHook>>,
+    cache: Cache,
+    cache_check: Cache,
+}
+
 /// Runtime call dispatcher.
 pub struct Dispatcher {
     logger: Logger,
     queue_tx: channel::Sender<QueueItem>,
     abort_tx: channel::Sender<()>,
     abort_rx: channel::Receiver<()>,
+    abort_waiters: Mutex<HashSet<u64>>,
     protocol: Mutex<Option<Arc<Protocol>>>,
     protocol_cond: Condvar,
-    rak: Arc<RAK>,
+    rak: Mutex<Arc<RAK>>,
+    pending_rak: Mutex<Option<Arc<RAK>>>,
+    pending_state_root: Mutex<Option<Root>>,
     abort_batch: Arc<AtomicBool>,
+    batch_cancel: Mutex<Option<CancelSignal>>,
+    pause_state: Mutex<PauseState>,
+    pause_cond: Condvar,
+    response_sink: Mutex<Option<Box<dyn ResponseSink>>>,
+    km_policy: Mutex<Option<KmPolicyInfo>>,
+    queue_pressure: AtomicUsize,
+    root_watchers: Mutex<RootWatchers>,
+    max_response_size: usize,
+    computed_batches: Mutex<ComputedBatchCache>,
+    ready: AtomicBool,
+    consensus_verifier: Mutex<Option<Arc<dyn ConsensusVerifier>>>,
+    read_only: bool,
+    poisoned: Arc<AtomicBool>,
+    metrics: Mutex<RuntimeMetrics>,
+    log_limiter: LogRateLimiter,
+    tree_depth_warning: Option<usize>,
+    tree_depth_warned: AtomicBool,
+    circuit_breaker: Option<CircuitBreaker>,
 }
 
-impl Dispatcher {
-    /// Create a new runtime call dispatcher.
-    pub fn new(initializer: Box<dyn Initializer>, rak: Arc<RAK>) -> Arc<Self> {
-        let (tx, rx) = channel::bounded(BACKLOG_SIZE);
+/// Runtime call dispatcher builder.
+pub struct DispatcherBuilder {
+    initializer: Box<dyn Initializer>,
+    rak: Arc<RAK>,
+    backlog: usize,
+    commit_hook: Option<Box<dyn CommitHook + Send>>,
+    response_sink: Option<Box<dyn ResponseSink>>,
+    max_response_size: usize,
+    computed_batch_cache_size: usize,
+    read_only: bool,
+    tree_depth_warning: Option<usize>,
+    circuit_breaker: Option<(usize, Duration)>,
+    #[cfg(not(target_env = "sgx"))]
+    poison_on_panic: bool,
+    #[cfg(not(target_env = "sgx"))]
+    log_panics: bool,
+}
+
+impl DispatcherBuilder {
+    /// Create a new runtime call dispatcher builder.
+    pub fn new(initializer: Box<dyn Initializer>, rak: Arc<RAK>) -> Self {
+        Self {
+            initializer,
+            rak,
+            backlog: BACKLOG_SIZE,
+            commit_hook: None,
+            response_sink: None,
+            max_response_size: MAX_MESSAGE_SIZE,
+            computed_batch_cache_size: 0,
+            read_only: false,
+            tree_depth_warning: None,
+            circuit_breaker: None,
+            #[cfg(not(target_env = "sgx"))]
+            poison_on_panic: false,
+            #[cfg(not(target_env = "sgx"))]
+            log_panics: false,
+        }
+    }
+
+    /// Set the maximum amount of requests that can be in the dispatcher queue.
+    pub fn backlog(mut self, backlog: usize) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set the hook to be invoked after each executed batch has been
+    /// committed. This takes precedence over `Initializer::commit_hook`.
+    pub fn on_commit<H>(mut self, hook: H) -> Self
+    where
+        H: CommitHook + Send + 'static,
+    {
+        self.commit_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Set the sink to be invoked with each response. This takes precedence
+    /// over `Initializer::response_sink`.
+    pub fn response_sink<S>(mut self, sink: S) -> Self
+    where
+        S: ResponseSink + 'static,
+    {
+        self.response_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Set the maximum encoded size, in bytes, of a batch execution response.
+    /// Larger responses are replaced by an error so that the batch can be
+    /// split. Defaults to the maximum protocol message size.
+    pub fn max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// Set the number of recently computed batches to keep, so that a batch
+    /// execution request resent by the host for the same round and I/O root
+    /// is answered without re-executing the batch. Defaults to 0, which
+    /// disables the cache.
+    ///
+    /// As any newer batch evicts older ones, this only helps with requests
+    /// resent within a short window.
+    pub fn computed_batch_cache_size(mut self, size: usize) -> Self {
+        self.computed_batch_cache_size = size;
+        self
+    }
+
+    /// Make the dispatcher read-only, e.g., for archive nodes which serve
+    /// queries but never produce results. A read-only dispatcher rejects
+    /// batch execution requests and only serves transaction checks and RPCs.
+    ///
+    /// As read-only dispatchers never sign compute results, they don't need
+    /// a provisioned RAK.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Log a warning, once, if the depth of the committed state tree exceeds
+    /// the given threshold, which suggests that the runtime's keys share long
+    /// prefixes. The depth is sampled every 100 rounds, only considering the
+    /// nodes in the execution cache, see `Tree::cached_max_path_depth`.
+    pub fn tree_depth_warning(mut self, threshold: usize) -> Self {
+        self.tree_depth_warning = Some(threshold);
+        self
+    }
+
+    /// Reject batch execution requests with `DispatcherError::CircuitOpen`
+    /// for `cooldown` after `failure_threshold` consecutive batches failed
+    /// to dispatch, e.g., due to corrupt state. Once the cooldown has
+    /// elapsed, a single batch is dispatched to probe for recovery. If it
+    /// succeeds, batches are executed normally again, otherwise requests are
+    /// rejected for another cooldown. See `Dispatcher::circuit_state`.
+    ///
+    /// Transaction checks are not affected. Disabled by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failure_threshold` is zero.
+    pub fn circuit_breaker(mut self, failure_threshold: usize, cooldown: Duration) -> Self {
+        assert!(failure_threshold > 0, "failure_threshold must be non-zero");
+        self.circuit_breaker = Some((failure_threshold, cooldown));
+        self
+    }
+
+    /// Instead of aborting the process when dispatch panics, mark the
+    /// dispatcher as poisoned and fail all further requests with
+    /// `DispatcherError::Poisoned`. See `Dispatcher::is_poisoned`.
+    ///
+    /// This is meant for tests and other non-enclave embeddings and is not
+    /// available in enclave builds, where a panic always aborts.
+    #[cfg(not(target_env = "sgx"))]
+    pub fn poison_on_panic(mut self) -> Self {
+        self.poison_on_panic = true;
+        self
+    }
+
+    /// Install a panic hook which logs the location, message and a backtrace
+    /// of any panic, so that a panic during dispatch can be diagnosed before
+    /// the process is aborted or the dispatcher is poisoned.
+    ///
+    /// The hook is process-wide, chains to the previously installed hook and
+    /// is only installed once, no matter how many dispatchers enable it. It
+    /// is not available in enclave builds.
+    #[cfg(not(target_env = "sgx"))]
+    pub fn log_panics(mut self) -> Self {
+        self.log_panics = true;
+        self
+    }
+
+    /// Build the dispatcher and spawn its dispatch thread.
+    pub fn build(mut self) -> Arc<Dispatcher> {
+        let (dispatcher, poisoned, rx) = self.build_dispatcher();
+
+        let d = dispatcher.clone();
+        let initializer = self.initializer;
+        let commit_hook = self.commit_hook;
+        thread::spawn(move || {
+            let _guard = AbortOnPanic { poisoned };
+            d.run(initializer, commit_hook, rx)
+        });
+
+        dispatcher
+    }
+
+    /// Build a dispatcher without a dispatch thread, where requests are
+    /// processed synchronously on the caller's thread via
+    /// `SyncDispatcher::process_one`.
+    ///
+    /// This gives tests full control over the order of requests. Requests
+    /// queued via `Dispatcher::queue_request` are never processed, so
+    /// `Dispatcher::abort_and_wait` must not be used.
+    pub fn build_sync(mut self) -> SyncDispatcher {
+        let (dispatcher, poisoned, _) = self.build_dispatcher();
+
+        SyncDispatcher {
+            dispatcher,
+            poisoned,
+            initializer: Some((self.initializer, self.commit_hook)),
+            state: None,
+        }
+    }
+
+    fn build_dispatcher(
+        &mut self,
+    ) -> (
+        Arc<Dispatcher>,
+        Option<Arc<AtomicBool>>,
+        channel::Receiver<QueueItem>,
+    ) {
+        let (tx, rx) = channel::bounded(self.backlog);
         let (abort_tx, abort_rx) = channel::bounded(1);
 
         let dispatcher = Arc::new(Dispatcher {
@@ -125,19 +754,101 @@ impl Dispatcher {
             queue_tx: tx,
             abort_tx: abort_tx,
             abort_rx: abort_rx,
+            abort_waiters: Mutex::new(HashSet::new()),
             protocol: Mutex::new(None),
             protocol_cond: Condvar::new(),
-            rak,
+            rak: Mutex::new(self.rak.clone()),
+            pending_rak: Mutex::new(None),
+            pending_state_root: Mutex::new(None),
             abort_batch: Arc::new(AtomicBool::new(false)),
+            batch_cancel: Mutex::new(None),
+            pause_state: Mutex::new(PauseState::default()),
+            pause_cond: Condvar::new(),
+            response_sink: Mutex::new(self.response_sink.take()),
+            km_policy: Mutex::new(None),
+            queue_pressure: AtomicUsize::new(0),
+            root_watchers: Mutex::new(RootWatchers::new()),
+            max_response_size: self.max_response_size,
+            computed_batches: Mutex::new(ComputedBatchCache::new(self.computed_batch_cache_size)),
+            ready: AtomicBool::new(false),
+            consensus_verifier: Mutex::new(None),
+            read_only: self.read_only,
+            poisoned: Arc::new(AtomicBool::new(false)),
+            metrics: Mutex::new(RuntimeMetrics::default()),
+            log_limiter: LogRateLimiter::new(LOG_RATE_LIMIT),
+            tree_depth_warning: self.tree_depth_warning,
+            tree_depth_warned: AtomicBool::new(false),
+            circuit_breaker: self
+                .circuit_breaker
+                .map(|(threshold, cooldown)| CircuitBreaker::new(threshold, cooldown)),
         });
 
-        let d = dispatcher.clone();
-        thread::spawn(move || {
-            let _guard = AbortOnPanic;
-            d.run(initializer, rx)
-        });
+        #[cfg(not(target_env = "sgx"))]
+        if self.log_panics {
+            install_panic_hook(dispatcher.logger.clone());
+        }
 
-        dispatcher
+        #[cfg(not(target_env = "sgx"))]
+        let poisoned = if self.poison_on_panic {
+            Some(dispatcher.poisoned.clone())
+        } else {
+            None
+        };
+        #[cfg(target_env = "sgx")]
+        let poisoned = None;
+
+        (dispatcher, poisoned, rx)
+    }
+}
+
+/// A runtime call dispatcher processing requests synchronously on the
+/// caller's thread, see `DispatcherBuilder::build_sync`.
+pub struct SyncDispatcher {
+    dispatcher: Arc<Dispatcher>,
+    poisoned: Option<Arc<AtomicBool>>,
+    initializer: Option<(Box<dyn Initializer>, Option<Box<dyn CommitHook + Send>>)>,
+    state: Option<DispatchState>,
+}
+
+impl SyncDispatcher {
+    /// Return the underlying dispatcher, e.g., for creating the protocol
+    /// instance or inspecting metrics.
+    pub fn dispatcher(&self) -> &Arc<Dispatcher> {
+        &self.dispatcher
+    }
+
+    /// Start the dispatcher, initializing it on the caller's thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the dispatcher has already been started.
+    pub fn start(&mut self, protocol: Arc<Protocol>) {
+        let (initializer, commit_hook) =
+            self.initializer.take().expect("dispatcher already started");
+        self.state = Some(self.dispatcher.init(initializer, commit_hook, protocol));
+    }
+
+    /// Process a single request to completion, including sending any
+    /// response to the host.
+    pub fn process_one(&mut self, ctx: Context, id: u64, body: Body) -> Result<()> {
+        self.dispatcher.ensure_not_poisoned()?;
+        let state = self
+            .state
+            .as_mut()
+            .ok_or_else(|| anyhow!("dispatcher not started"))?;
+
+        let _guard = AbortOnPanic {
+            poisoned: self.poisoned.clone(),
+        };
+        let _in_flight = self.dispatcher.begin_dispatch();
+        self.dispatcher.process_one(state, ctx, id, body)
+    }
+}
+
+impl Dispatcher {
+    /// Create a new runtime call dispatcher with default options.
+    pub fn new(initializer: Box<dyn Initializer>, rak: Arc<RAK>) -> Arc<Self> {
+        DispatcherBuilder::new(initializer, rak).build()
     }
 
     /// Start the dispatcher.
@@ -147,26 +858,216 @@ impl Dispatcher {
         self.protocol_cond.notify_one();
     }
 
+    /// Check whether the dispatcher has finished initialization and is ready
+    /// to process requests.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Subscribe to state root changes. The returned receiver receives the
+    /// new state root after each executed batch has been committed, keeping
+    /// at most `ROOT_WATCH_CAPACITY` roots.
+    ///
+    /// Dropping the receiver unsubscribes.
+    pub fn watch_state_root(&self) -> RootReceiver {
+        self.root_watchers.lock().unwrap().subscribe()
+    }
+
+    /// Replace the RAK used by the dispatcher.
+    ///
+    /// The new RAK only takes effect between requests, so a batch that is
+    /// currently being dispatched is still signed with the RAK that was in
+    /// effect when its dispatch started. New enclave RPC sessions are bound
+    /// to the new RAK while existing sessions keep their binding.
+    ///
+    /// Note that compute results signed with the new RAK will only be
+    /// accepted by consensus once the node's registration has been updated
+    /// to include the new RAK.
+    pub fn rotate_rak(&self, new_rak: Arc<RAK>) {
+        *self.pending_rak.lock().unwrap() = Some(new_rak);
+    }
+
+    /// Switch the runtime state to a committed root computed elsewhere, e.g.,
+    /// when migrating the state of the runtime.
+    ///
+    /// The new root is adopted just before the next request is dispatched.
+    /// Adoption fails (and is logged) if the root is not reachable via the
+    /// host storage. As the state is switched to the root of the block
+    /// referenced by each batch, the adopted root is only kept if the
+    /// following blocks are based on it.
+    pub fn adopt_state_root(&self, root: Root) {
+        *self.pending_state_root.lock().unwrap() = Some(root);
+    }
+
+    /// Return the RAK currently in effect.
+    fn rak(&self) -> Arc<RAK> {
+        self.rak.lock().unwrap().clone()
+    }
+
     /// Queue a new request to be dispatched.
+    ///
+    /// Returns `DispatcherError::Paused` if the dispatcher is paused.
     pub fn queue_request(&self, ctx: Context, id: u64, body: Body) -> Result<()> {
+        self.ensure_not_poisoned()?;
+        self.ensure_not_paused()?;
         self.queue_tx.try_send((ctx, id, body))?;
+        self.check_queue_pressure();
         Ok(())
     }
 
+    /// Queue a new request to be dispatched, waiting for up to `timeout` in
+    /// case the queue is full.
+    ///
+    /// Returns `DispatcherError::QueueFull` if the request could not be
+    /// queued before the timeout elapsed and `DispatcherError::Paused` if
+    /// the dispatcher is paused.
+    pub fn queue_request_timeout(
+        &self,
+        ctx: Context,
+        id: u64,
+        body: Body,
+        timeout: Duration,
+    ) -> Result<()> {
+        self.ensure_not_poisoned()?;
+        self.ensure_not_paused()?;
+        match self.queue_tx.send_timeout((ctx, id, body), timeout) {
+            Ok(()) => {
+                self.check_queue_pressure();
+                Ok(())
+            }
+            Err(channel::SendTimeoutError::Timeout(_)) => Err(DispatcherError::QueueFull.into()),
+            Err(error) => Err(anyhow!("{}", error)),
+        }
+    }
+
     /// Signals to dispatcher that it should abort and waits for the abort to
     /// complete.
+    ///
+    /// The dispatcher does not respond to the abort request itself, it is up
+    /// to the caller to respond once this returns.
     pub fn abort_and_wait(&self, ctx: Context, id: u64, req: Body) -> Result<()> {
+        // Nothing would ever acknowledge the abort.
+        self.ensure_not_poisoned()?;
         self.abort_batch.store(true, Ordering::SeqCst);
+        // Cancel the context of the batch currently being dispatched (if any).
+        if let Some(ref signal) = *self.batch_cancel.lock().unwrap() {
+            signal.cancel();
+        }
         // Queue the request to break the dispatch loop in case nothing is
-        // being processed at the moment.
-        self.queue_request(ctx, id, req)?;
+        // being processed at the moment. This bypasses the pause check, but
+        // the abort will only complete once the dispatcher is resumed.
+        self.abort_waiters.lock().unwrap().insert(id);
+        if let Err(error) = self.queue_tx.try_send((ctx, id, req)) {
+            self.abort_waiters.lock().unwrap().remove(&id);
+            return Err(error.into());
+        }
         // Wait for abort.
         self.abort_rx.recv().map_err(|error| anyhow!("{}", error))
     }
 
+    /// Return information about the currently applied key manager policy.
+    ///
+    /// Returns `None` if no policy has been applied yet.
+    pub fn current_km_policy(&self) -> Option<KmPolicyInfo> {
+        self.km_policy.lock().unwrap().clone()
+    }
+
+    /// Stop accepting new requests, wait for all requests currently being
+    /// dispatched (if any) to finish and return a guard which keeps the
+    /// dispatcher paused until it is dropped.
+    ///
+    /// Requests that were already queued remain queued and are dispatched
+    /// after the dispatcher is resumed.
+    pub fn drain_and_pause(&self) -> PauseGuard {
+        let mut state = self.pause_state.lock().unwrap();
+        state.pauses += 1;
+        while state.in_flight > 0 {
+            state = self.pause_cond.wait(state).unwrap();
+        }
+
+        PauseGuard { dispatcher: self }
+    }
+
+    /// Track requests queued while the queue is more than 80% full and log
+    /// a warning if this persists.
+    fn check_queue_pressure(&self) {
+        let capacity = match self.queue_tx.capacity() {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        let len = self.queue_tx.len();
+        if len * 5 <= capacity * 4 {
+            self.queue_pressure.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let count = self.queue_pressure.fetch_add(1, Ordering::Relaxed) + 1;
+        if count % QUEUE_PRESSURE_WARN_INTERVAL == 0 {
+            warn!(self.logger, "Request queue is persistently near capacity";
+                "len" => len,
+                "capacity" => capacity,
+                "count" => count,
+            );
+        }
+    }
+
+    /// Check whether the dispatch thread has panicked. This can only happen
+    /// if the dispatcher was built with `DispatcherBuilder::poison_on_panic`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
+    }
+
+    /// Return a snapshot of the dispatcher's metrics.
+    ///
+    /// Cache statistics are updated after each dispatched request and cover
+    /// all trees used by a cache, including ones replaced after root changes.
+    pub fn metrics_snapshot(&self) -> RuntimeMetrics {
+        let mut metrics = self.metrics.lock().unwrap().clone();
+        metrics.queue_depth = self.queue_tx.len();
+        metrics.in_flight = self.pause_state.lock().unwrap().in_flight;
+        metrics
+    }
+
+    /// Return the state of the dispatch circuit breaker. Without a circuit
+    /// breaker configured, the circuit is always closed.
+    pub fn circuit_state(&self) -> CircuitState {
+        match self.circuit_breaker {
+            Some(ref breaker) => breaker.state_at(Instant::now()),
+            None => CircuitState::Closed,
+        }
+    }
+
+    fn ensure_not_poisoned(&self) -> Result<()> {
+        if self.is_poisoned() {
+            return Err(DispatcherError::Poisoned.into());
+        }
+        Ok(())
+    }
+
+    fn ensure_not_paused(&self) -> Result<()> {
+        if self.pause_state.lock().unwrap().pauses > 0 {
+            return Err(DispatcherError::Paused.into());
+        }
+        Ok(())
+    }
+
+    /// Wait until the dispatcher is not paused and no other request is in
+    /// flight, then account for a new in-flight request until the returned
+    /// guard is dropped.
+    fn begin_dispatch(&self) -> InFlightGuard {
+        let mut state = self.pause_state.lock().unwrap();
+        while state.pauses > 0 || state.in_flight > 0 {
+            state = self.pause_cond.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+
+        InFlightGuard { dispatcher: self }
+    }
+
     fn run(
         &self,
         initializer: Box<dyn Initializer>,
+        commit_hook: Option<Box<dyn CommitHook + Send>>,
         rx: channel::Receiver<QueueItem>,
     ) -> Result<()> {
         // Wait for the protocol instance to be available.
@@ -179,23 +1080,7 @@ impl Dispatcher {
             guard.take().unwrap()
         };
 
-        // Create actual dispatchers for RPCs and transactions.
-        info!(self.logger, "Starting the runtime dispatcher");
-        let mut rpc_demux = RpcDemux::new(self.rak.clone());
-        let mut rpc_dispatcher = RpcDispatcher::new();
-        let mut txn_dispatcher: Box<dyn TxnDispatcher> = if let Some(txn) =
-            initializer.init(&protocol, &self.rak, &mut rpc_demux, &mut rpc_dispatcher)
-        {
-            txn
-        } else {
-            Box::new(TxnNoopDispatcher::new())
-        };
-        txn_dispatcher.set_abort_batch_flag(self.abort_batch.clone());
-
-        // Create common MKVS to use as a cache as long as the root stays the same. Use separate
-        // caches for executing and checking transactions.
-        let mut cache = Cache::new(protocol.clone());
-        let mut cache_check = Cache::new(protocol.clone());
+        let mut state = self.init(initializer, commit_hook, protocol);
 
         'dispatch: loop {
             // Check if abort was requested and if so, signal that the batch
@@ -207,76 +1092,16 @@ impl Dispatcher {
                 self.abort_tx.try_send(())?;
             }
 
-            match rx.recv() {
-                Ok((ctx, id, Body::RuntimeRPCCallRequest { request })) => {
-                    // RPC call.
-                    self.dispatch_rpc(
-                        &mut rpc_demux,
-                        &mut rpc_dispatcher,
-                        &protocol,
-                        ctx,
-                        id,
-                        request,
-                    );
-                }
-                Ok((ctx, id, Body::RuntimeLocalRPCCallRequest { request })) => {
-                    // Local RPC call.
-                    self.dispatch_local_rpc(&mut rpc_dispatcher, &protocol, ctx, id, request);
-                }
-                Ok((
-                    ctx,
-                    id,
-                    Body::RuntimeExecuteTxBatchRequest {
-                        io_root,
-                        inputs,
-                        block,
-                    },
-                )) => {
-                    // Transaction execution.
-                    self.dispatch_txn(
-                        &mut cache,
-                        &mut txn_dispatcher,
-                        &protocol,
-                        ctx,
-                        id,
-                        io_root,
-                        inputs,
-                        block,
-                        false,
-                    );
-                }
-                Ok((ctx, id, Body::RuntimeCheckTxBatchRequest { inputs, block })) => {
-                    // Transaction check.
-                    self.dispatch_txn(
-                        &mut cache_check,
-                        &mut txn_dispatcher,
-                        &protocol,
-                        ctx,
-                        id,
-                        Hash::default(),
-                        inputs,
-                        block,
-                        true,
-                    );
-                }
-                Ok((ctx, id, Body::RuntimeKeyManagerPolicyUpdateRequest { signed_policy_raw })) => {
-                    // KeyManager policy update local RPC call.
-                    self.handle_km_policy_update(
-                        &mut rpc_dispatcher,
-                        &protocol,
-                        ctx,
-                        id,
-                        signed_policy_raw,
-                    );
-                }
-                Ok((_ctx, _id, Body::RuntimeAbortRequest {})) => {
-                    // We handle the RuntimeAbortRequest here so that we break
-                    // the recv loop and re-check abort flag.
-                    info!(self.logger, "Received abort request");
-                }
-                Ok(_) => {
-                    error!(self.logger, "Unsupported request type");
-                    break 'dispatch;
+            let request = rx.recv();
+            // Do not dispatch anything while paused.
+            let _in_flight = self.begin_dispatch();
+
+            match request {
+                Ok((ctx, id, body)) => {
+                    if let Err(error) = self.process_one(&mut state, ctx, id, body) {
+                        error!(self.logger, "Failed to process request"; "err" => %error);
+                        break 'dispatch;
+                    }
                 }
                 Err(error) => {
                     error!(self.logger, "Error while waiting for request"; "err" => %error);
@@ -290,224 +1115,784 @@ impl Dispatcher {
         Ok(())
     }
 
+    /// Create the dispatchers and caches used to process requests and mark
+    /// the dispatcher as ready.
+    fn init(
+        &self,
+        initializer: Box<dyn Initializer>,
+        commit_hook: Option<Box<dyn CommitHook + Send>>,
+        protocol: Arc<Protocol>,
+    ) -> DispatchState {
+        // Create actual dispatchers for RPCs and transactions.
+        info!(self.logger, "Starting the runtime dispatcher");
+        let rak = self.rak();
+        let mut rpc_demux = RpcDemux::new(rak.clone());
+        let mut rpc_dispatcher = RpcDispatcher::new();
+        let mut txn_dispatcher: Box<dyn TxnDispatcher> = if let Some(txn) =
+            initializer.init(&protocol, &rak, &mut rpc_demux, &mut rpc_dispatcher)
+        {
+            txn
+        } else {
+            Box::new(TxnNoopDispatcher::new())
+        };
+        txn_dispatcher.set_abort_batch_flag(self.abort_batch.clone());
+        if let Some(gas_meter) = initializer.gas_meter() {
+            txn_dispatcher.set_gas_meter(gas_meter);
+        }
+        let commit_hook = match commit_hook {
+            Some(hook) => Some(hook as Box<dyn CommitHook>),
+            None => initializer.commit_hook(),
+        };
+        {
+            let mut response_sink = self.response_sink.lock().unwrap();
+            if response_sink.is_none() {
+                *response_sink = initializer.response_sink();
+            }
+        }
+        *self.consensus_verifier.lock().unwrap() = initializer.consensus_verifier();
+        let local_rpc_codec = initializer
+            .local_rpc_codec()
+            .unwrap_or_else(|| Arc::new(CborCodec));
+
+        // Create common MKVS to use as a cache as long as the root stays the same. Use separate
+        // caches for executing and checking transactions.
+        let capacities = initializer.cache_capacities();
+        let cache = Cache::new(protocol.clone(), capacities.execute);
+        let cache_check = Cache::new(protocol.clone(), capacities.check);
+
+        self.ready.store(true, Ordering::SeqCst);
+        initializer.on_ready();
+        info!(self.logger, "Runtime dispatcher is ready");
+
+        DispatchState {
+            protocol,
+            rpc_demux,
+            rpc_dispatcher,
+            local_rpc_codec,
+            txn_dispatcher,
+            commit_hook,
+            cache,
+            cache_check,
+        }
+    }
+
+    /// Process a single request to completion, including any pending RAK
+    /// rotation or state root adoption that must happen before it.
+    ///
+    /// Returns an error for unsupported requests.
+    fn process_one(
+        &self,
+        state: &mut DispatchState,
+        ctx: Context,
+        id: u64,
+        body: Body,
+    ) -> Result<()> {
+        // Apply any pending RAK rotation between requests.
+        if let Some(rak) = self.pending_rak.lock().unwrap().take() {
+            info!(self.logger, "Rotating the RAK");
+            state.rpc_demux.set_rak(rak.clone());
+            *self.rak.lock().unwrap() = rak;
+        }
+
+        // Apply any pending state root adoption before dispatching.
+        if let Some(root) = self.pending_state_root.lock().unwrap().take() {
+            info!(self.logger, "Adopting a new state root";
+                "root" => ?root,
+            );
+            for target in &mut [&mut state.cache, &mut state.cache_check] {
+                if let Err(error) = target.adopt_root(root) {
+                    error!(self.logger, "Failed to adopt state root"; "err" => %error);
+                }
+            }
+        }
+
+        let protocol = state.protocol.clone();
+        match body {
+            Body::RuntimeRPCCallRequest { request } => {
+                // RPC call.
+                self.dispatch_rpc(
+                    &mut state.rpc_demux,
+                    &mut state.rpc_dispatcher,
+                    &protocol,
+                    ctx,
+                    id,
+                    request,
+                );
+            }
+            Body::RuntimeLocalRPCCallRequest { request } => {
+                // Local RPC call.
+                self.dispatch_local_rpc(
+                    &mut state.rpc_dispatcher,
+                    &*state.local_rpc_codec,
+                    &protocol,
+                    ctx,
+                    id,
+                    request,
+                );
+            }
+            Body::RuntimeExecuteTxBatchRequest { .. } if self.read_only => {
+                // Read-only dispatchers never execute batches.
+                warn!(
+                    self.logger,
+                    "Rejecting transaction batch execution in read-only mode"
+                );
+                self.send_response(
+                    &protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", DispatcherError::ReadOnly),
+                    },
+                );
+            }
+            Body::RuntimeExecuteTxBatchRequest {
+                io_root,
+                inputs,
+                block,
+                inputs_hash,
+            } => {
+                // Transaction execution.
+                self.dispatch_txn(
+                    &mut state.cache,
+                    &mut state.txn_dispatcher,
+                    &state.commit_hook,
+                    &protocol,
+                    ctx,
+                    id,
+                    io_root,
+                    inputs,
+                    inputs_hash,
+                    block,
+                    false,
+                );
+            }
+            Body::RuntimeCheckTxBatchRequest { inputs, block } => {
+                // Transaction check.
+                self.dispatch_txn(
+                    &mut state.cache_check,
+                    &mut state.txn_dispatcher,
+                    &state.commit_hook,
+                    &protocol,
+                    ctx,
+                    id,
+                    Hash::default(),
+                    inputs,
+                    None,
+                    block,
+                    true,
+                );
+            }
+            Body::RuntimeKeyManagerPolicyUpdateRequest { signed_policy_raw } => {
+                // KeyManager policy update local RPC call.
+                self.handle_km_policy_update(
+                    &mut state.rpc_dispatcher,
+                    &protocol,
+                    ctx,
+                    id,
+                    signed_policy_raw,
+                );
+            }
+            Body::RuntimeConsensusSyncRequest { height } => {
+                // Consensus state sync.
+                self.dispatch_consensus_sync(&protocol, ctx, id, height);
+            }
+            Body::RuntimeAbortRequest {} => {
+                // We handle the RuntimeAbortRequest here so that we break
+                // the recv loop and re-check abort flag.
+                info!(self.logger, "Received abort request");
+
+                // Requests queued via abort_and_wait are responded to by the
+                // caller. Respond to any other (directly queued) abort request
+                // here. As requests are dispatched in order, all requests
+                // queued before it have completed, i.e. the response means
+                // that the abort has completed. Note that directly queued
+                // requests do not interrupt the batch being dispatched.
+                if !self.abort_waiters.lock().unwrap().remove(&id) {
+                    self.send_response(&protocol, id, Body::RuntimeAbortResponse {});
+                }
+            }
+            _ => {
+                return Err(anyhow!("unsupported request type"));
+            }
+        }
+
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.execute_cache = state.cache.stats();
+        metrics.check_cache = state.cache_check.stats();
+        Ok(())
+    }
+
+    fn send_response(&self, protocol: &Arc<Protocol>, id: u64, body: Body) {
+        if let Some(ref sink) = *self.response_sink.lock().unwrap() {
+            sink.on_response(id, &body);
+        }
+
+        protocol.send_response(id, body).unwrap();
+    }
+
     fn dispatch_txn(
         &self,
         cache: &mut Cache,
         txn_dispatcher: &mut Box<dyn TxnDispatcher>,
+        commit_hook: &Option<Box<dyn CommitHook>>,
         protocol: &Arc<Protocol>,
         ctx: Context,
         id: u64,
         io_root: Hash,
-        mut inputs: TxnBatch,
+        inputs: TxnBatch,
+        inputs_hash: Option<Hash>,
         block: Block,
         check_only: bool,
     ) {
+        // A wrapped round would break version monotonicity of the state.
+        let round = match block.header.round.checked_add(1) {
+            Some(round) => round,
+            None => {
+                error!(self.logger, "Rejecting batch due to round overflow";
+                    "round" => block.header.round,
+                );
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: "round overflow".to_owned(),
+                    },
+                );
+                return;
+            }
+        };
+
+        let _span = if check_only {
+            tracing::batch_span(id, "check_tx_batch", round, &io_root)
+        } else {
+            tracing::batch_span(id, "execute_tx_batch", round, &io_root)
+        };
         debug!(self.logger, "Received transaction batch request";
             "state_root" => ?block.header.state_root,
-            "round" => block.header.round + 1,
+            "round" => round,
             "check_only" => check_only,
         );
 
-        // Create a new context and dispatch the batch.
-        let ctx = ctx.freeze();
-        cache.maybe_replace(Root {
+        // Serve resent requests from the cache if the batch has already been
+        // computed for the same block.
+        if !check_only {
+            let cached = self
+                .computed_batches
+                .lock()
+                .unwrap()
+                .get(round, io_root)
+                .filter(|batch| batch.header.previous_hash == block.header.encoded_hash());
+            if let Some(batch) = cached {
+                debug!(self.logger, "Serving transaction batch from cache");
+                self.send_response(protocol, id, Body::RuntimeExecuteTxBatchResponse { batch });
+                return;
+            }
+        }
+
+        // Regenerating the I/O root only happens after the batch has been
+        // executed, so reject misordered inputs early if possible.
+        if let Some(expected) = inputs_hash {
+            let got = inputs.hash();
+            if got != expected {
+                error!(self.logger, "Rejecting batch with inconsistent inputs";
+                    "expected_hash" => ?expected,
+                    "hash" => ?got,
+                    "num_inputs" => inputs.len(),
+                );
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", DispatcherError::InputsMismatch { expected, got }),
+                    },
+                );
+                return;
+            }
+        }
+
+        // Transaction artifacts are keyed by hash so duplicate transactions
+        // would silently overwrite each other's results.
+        if !check_only {
+            if let Some(tx_hash) = inputs.find_duplicate() {
+                error!(self.logger, "Rejecting batch with duplicate transaction";
+                    "tx_hash" => ?tx_hash,
+                );
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("duplicate transaction in batch: {:?}", tx_hash),
+                    },
+                );
+                return;
+            }
+        }
+
+        let previous_root = Root {
             namespace: block.header.namespace,
             version: block.header.round,
             hash: block.header.state_root,
-        });
+        };
+        let result = cache.maybe_replace(previous_root);
+        if let Err(error) = result {
+            error!(self.logger, "Rejecting batch for a foreign state root"; "err" => %error);
+            self.send_response(
+                protocol,
+                id,
+                Body::Error {
+                    module: "".to_owned(), // XXX: Error codes.
+                    code: 0,               // XXX: Error codes.
+                    message: format!("{}", error),
+                },
+            );
+            return;
+        }
+
+        // Fail fast while the circuit breaker is open.
+        let circuit_breaker = self.circuit_breaker.as_ref().filter(|_| !check_only);
+        if let Some(breaker) = circuit_breaker {
+            if !breaker.try_acquire_at(Instant::now()) {
+                if let Some(suppressed) = self.log_limiter.check("circuit_open") {
+                    warn!(self.logger, "Rejecting batch as the circuit breaker is open";
+                        "suppressed" => suppressed,
+                    );
+                }
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", DispatcherError::CircuitOpen),
+                    },
+                );
+                return;
+            }
+        }
+
+        // Create a new cancellable context and dispatch the batch.
+        let mut ctx = ctx;
+        *self.batch_cancel.lock().unwrap() = Some(cancellation::add_cancel_signal(&mut ctx));
+        let ctx = ctx.freeze();
 
         let untrusted_local = Arc::new(ProtocolUntrustedLocalStorage::new(
             Context::create_child(&ctx),
             protocol.clone(),
         ));
-        let txn_ctx = TxnContext::new(ctx.clone(), &block.header, check_only);
-        match StorageContext::enter(&mut cache.mkvs, untrusted_local.clone(), || {
+        let mut txn_ctx = TxnContext::new(ctx.clone(), &block.header, check_only);
+        txn_ctx.consensus_verifier = self.consensus_verifier.lock().unwrap().clone();
+        let read_syncer_protocol = protocol.clone();
+        txn_ctx.previous_state_source = Some(PreviousStateSource::new(previous_root, move || {
+            Box::new(HostReadSyncer::new(read_syncer_protocol.clone()))
+        }));
+        let result = StorageContext::enter(&mut cache.mkvs, untrusted_local.clone(), || {
             txn_dispatcher.dispatch_batch(&inputs, txn_ctx)
-        }) {
+        });
+        self.batch_cancel.lock().unwrap().take();
+        if let Some(breaker) = circuit_breaker {
+            match result {
+                Ok(_) => breaker.record_success(),
+                Err(_) => breaker.record_failure_at(Instant::now()),
+            }
+        }
+        match result {
             Err(error) => {
-                warn!(self.logger, "Dispatching batch error"; "err" => %error);
-                protocol
-                    .send_response(
-                        id,
-                        Body::Error {
-                            module: "".to_owned(), // XXX: Error codes.
-                            code: 0,               // XXX: Error codes.
-                            message: format!("{}", error),
-                        },
-                    )
-                    .unwrap();
+                if let Some(suppressed) = self.log_limiter.check("batch_error") {
+                    warn!(self.logger, "Dispatching batch error";
+                        "err" => %error,
+                        "suppressed" => suppressed,
+                    );
+                }
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", error),
+                    },
+                );
             }
-            Ok((mut outputs, mut tags, messages)) => {
+            Ok((outputs, tags, messages)) => {
                 if check_only {
                     debug!(self.logger, "Transaction batch check complete");
 
                     // Send the result back.
-                    protocol
-                        .send_response(id, Body::RuntimeCheckTxBatchResponse { results: outputs })
-                        .unwrap();
-                } else {
-                    // Finalize state.
-                    let (state_write_log, new_state_root) = cache
-                        .mkvs
-                        .commit(
-                            Context::create_child(&ctx),
-                            block.header.namespace,
-                            block.header.round + 1,
-                        )
-                        .expect("state commit must succeed");
-                    txn_dispatcher.finalize(new_state_root);
-                    cache.commit(block.header.round + 1, new_state_root);
-
-                    // Generate I/O root. Since we already fetched the inputs we avoid the need
-                    // to fetch them again by generating the previous I/O tree (generated by the
-                    // transaction scheduler) from the inputs.
-                    let mut txn_tree = TxnTree::new(
-                        Box::new(NoopReadSyncer),
-                        Root {
-                            namespace: block.header.namespace,
-                            version: block.header.round + 1,
-                            hash: Hash::empty_hash(),
-                        },
+                    self.send_response(
+                        protocol,
+                        id,
+                        Body::RuntimeCheckTxBatchResponse { results: outputs },
                     );
-                    let mut hashes = Vec::new();
-                    for (batch_order, input) in inputs.drain(..).enumerate() {
-                        hashes.push(Hash::digest_bytes(&input));
-                        txn_tree
-                            .add_input(
-                                Context::create_child(&ctx),
-                                input,
-                                batch_order.try_into().unwrap(),
-                            )
-                            .expect("add transaction must succeed");
-                    }
-
-                    let (_, old_io_root) = txn_tree
-                        .commit(Context::create_child(&ctx))
-                        .expect("io commit must succeed");
-                    if old_io_root != io_root {
-                        panic!(
-                    "dispatcher: I/O root inconsistent with inputs (expected: {:?} got: {:?})",
-                    io_root, old_io_root
-                );
-                    }
-
-                    for (tx_hash, (output, tags)) in
-                        hashes.drain(..).zip(outputs.drain(..).zip(tags.drain(..)))
-                    {
-                        txn_tree
-                            .add_output(Context::create_child(&ctx), tx_hash, output, tags)
-                            .expect("add transaction must succeed");
-                    }
-
-                    let (io_write_log, io_root) = txn_tree
-                        .commit(Context::create_child(&ctx))
-                        .expect("io commit must succeed");
-
-                    let header = ComputeResultsHeader {
-                        round: block.header.round + 1,
-                        previous_hash: block.header.encoded_hash(),
-                        io_root: Some(io_root),
-                        state_root: Some(new_state_root),
+                } else {
+                    match self.commit_batch(
+                        cache,
+                        txn_dispatcher,
+                        commit_hook,
+                        &ctx,
+                        io_root,
+                        inputs,
+                        outputs,
+                        tags,
                         messages,
-                    };
-
-                    debug!(self.logger, "Transaction batch execution complete";
-                        "previous_hash" => ?header.previous_hash,
-                        "io_root" => ?header.io_root,
-                        "state_root" => ?header.state_root
-                    );
-
-                    let rak_sig = if self.rak.public_key().is_some() {
-                        self.rak
-                            .sign(&COMPUTE_RESULTS_HEADER_CONTEXT, &cbor::to_vec(&header))
-                            .unwrap()
-                    } else {
-                        Signature::default()
-                    };
+                        &block,
+                        round,
+                    ) {
+                        Ok(body) => {
+                            // The result is known to fit into a protocol message, so
+                            // resent requests are served without any further checks.
+                            if let Body::RuntimeExecuteTxBatchResponse { ref batch } = body {
+                                let mut computed_batches = self.computed_batches.lock().unwrap();
+                                if computed_batches.is_enabled() {
+                                    computed_batches.insert(round, io_root, batch.clone());
+                                }
+                            }
 
-                    let result = ComputedBatch {
-                        header,
-                        io_write_log,
-                        state_write_log,
-                        rak_sig,
-                    };
+                            // Send the result back.
+                            self.send_response(protocol, id, body);
+                        }
+                        Err(error) => {
+                            error!(self.logger, "Failed to commit batch"; "err" => %error);
 
-                    // Send the result back.
-                    protocol
-                        .send_response(id, Body::RuntimeExecuteTxBatchResponse { batch: result })
-                        .unwrap();
+                            let body = match error.downcast_ref::<DispatcherError>() {
+                                Some(DispatcherError::AttestationUnavailable) => Body::Error {
+                                    module: ERROR_MODULE.to_owned(),
+                                    code: ERROR_CODE_ATTESTATION_UNAVAILABLE,
+                                    message: format!("{}", error),
+                                },
+                                _ => Body::Error {
+                                    module: "".to_owned(), // XXX: Error codes.
+                                    code: 0,               // XXX: Error codes.
+                                    message: format!("{}", error),
+                                },
+                            };
+                            self.send_response(protocol, id, body);
+                        }
+                    }
                 }
             }
         }
     }
 
-    fn dispatch_rpc(
+    fn commit_batch(
         &self,
-        rpc_demux: &mut RpcDemux,
-        rpc_dispatcher: &mut RpcDispatcher,
-        protocol: &Arc<Protocol>,
-        ctx: Context,
-        id: u64,
-        request: Vec<u8>,
-    ) {
-        debug!(self.logger, "Received RPC call request");
+        cache: &mut Cache,
+        txn_dispatcher: &mut Box<dyn TxnDispatcher>,
+        commit_hook: &Option<Box<dyn CommitHook>>,
+        ctx: &Arc<Context>,
+        io_root: Hash,
+        inputs: TxnBatch,
+        outputs: TxnBatch,
+        tags: Vec<Tags>,
+        messages: Vec<RoothashMessage>,
+        block: &Block,
+        round: u64,
+    ) -> Result<Body> {
+        // Only a dispatcher that supports partial batches may have processed a
+        // prefix of the inputs, e.g., if the batch was aborted.
+        let partial = txn_dispatcher.partial_batches() && outputs.len() < inputs.len();
+        if !partial && outputs.len() != inputs.len() {
+            // Roll back any pending modifications.
+            cache.mkvs.clear_pending();
+            return Err(anyhow!(
+                "number of outputs does not match inputs (inputs: {} outputs: {})",
+                inputs.len(),
+                outputs.len()
+            ));
+        }
 
-        // Process frame.
-        let mut buffer = vec![];
-        let result = match rpc_demux.process_frame(request, &mut buffer) {
+        // Finalize state.
+        let (state_write_log, new_state_root) =
+            match cache
+                .mkvs
+                .commit(Context::create_child(&ctx), block.header.namespace, round)
+            {
+                Ok(result) => result,
+                Err(error) => {
+                    // Roll back any pending modifications.
+                    cache.mkvs.clear_pending();
+                    return Err(error.context("state commit failed"));
+                }
+            };
+
+        let result = self.commit_io(ctx, io_root, inputs, outputs, tags, partial, block, round);
+        let (io_write_log, io_root) = match result {
             Ok(result) => result,
             Err(error) => {
-                error!(self.logger, "Error while processing frame"; "err" => %error);
+                // The new state has already been committed to the tree, so go back
+                // to the last root to make sure the batch can be retried.
+                cache.reset();
+                return Err(error);
+            }
+        };
 
-                protocol
-                    .send_response(
-                        id,
-                        Body::Error {
-                            module: "".to_owned(), // XXX: Error codes.
-                            code: 0,               // XXX: Error codes.
-                            message: format!("{}", error),
-                        },
-                    )
-                    .unwrap();
-                return;
+        let previous_hash = block.header.encoded_hash();
+        let header = ComputeResultsHeader {
+            round,
+            previous_hash,
+            io_root: Some(io_root),
+            state_root: Some(new_state_root),
+            messages,
+        };
+
+        // Sign the results before recording the new root, so that the batch
+        // can be retried if the RAK is unavailable (e.g., during re-attestation).
+        let rak = self.rak();
+        let rak_sig = if rak.public_key().is_some() {
+            match sign_compute_results_header(&*rak, &header) {
+                Ok(rak_sig) => rak_sig,
+                Err(error) => {
+                    error!(self.logger, "Failed to sign compute results"; "err" => ?error);
+                    cache.reset();
+                    return Err(error);
+                }
             }
+        } else {
+            Signature::default()
         };
 
-        let protocol_response;
-        if let Some((session_id, session_info, message, untrusted_plaintext)) = result {
-            // Dispatch request.
-            assert!(
-                buffer.is_empty(),
-                "must have no handshake data in transport mode"
+        let body = Body::RuntimeExecuteTxBatchResponse {
+            batch: ComputedBatch {
+                header,
+                io_write_log,
+                state_write_log,
+                rak_sig,
+                partial,
+            },
+        };
+
+        // Make sure the result fits into a protocol message as otherwise sending
+        // it would fail. Go back to the last root so nothing gets recorded for
+        // the rejected batch.
+        let size = cbor::to_vec(&body).len();
+        if size > self.max_response_size {
+            error!(self.logger, "Batch execution response too large";
+                "size" => size,
+                "max_size" => self.max_response_size,
             );
+            cache.reset();
+            return Err(DispatcherError::ResponseTooLarge {
+                size,
+                max: self.max_response_size,
+            }
+            .into());
+        }
 
-            match message {
-                RpcMessage::Request(req) => {
-                    // First make sure that the untrusted_plaintext matches
-                    // the request's method!
-                    if untrusted_plaintext != req.method {
-                        error!(self.logger, "Request methods don't match!";
-                            "untrusted_plaintext" => ?untrusted_plaintext,
-                            "method" => ?req.method
-                        );
-                        let err_reponse = Body::Error {
-                            module: "".to_owned(), // XXX: Error codes.
-                            code: 0,               // XXX: Error codes.
-                            message: "Request's method doesn't match untrusted_plaintext copy."
-                                .to_string(),
-                        };
-                        protocol.send_response(id, err_reponse).unwrap();
-                        return;
-                    }
+        // Everything has been committed, record the new root.
+        txn_dispatcher.finalize(new_state_root);
+        cache.commit(round, new_state_root);
+        self.metrics.lock().unwrap().last_round = Some(round);
+        self.check_tree_depth(cache, round);
+
+        // Notify watchers, dropping any that have unsubscribed.
+        self.root_watchers.lock().unwrap().notify(cache.root);
+
+        if let Some(ref hook) = commit_hook {
+            hook.on_commit(round, new_state_root, io_root);
+        }
+
+        debug!(self.logger, "Transaction batch execution complete";
+            "previous_hash" => ?previous_hash,
+            "io_root" => ?io_root,
+            "state_root" => ?new_state_root
+        );
+
+        Ok(body)
+    }
+
+    /// Warn once if the depth of the committed state tree exceeds the
+    /// configured threshold.
+    fn check_tree_depth(&self, cache: &Cache, round: u64) {
+        let threshold = match self.tree_depth_warning {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if round % TREE_DEPTH_SAMPLE_INTERVAL != 0 || self.tree_depth_warned.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        match cache.mkvs.cached_max_path_depth(Context::background()) {
+            Ok(depth) if depth > threshold => {
+                self.tree_depth_warned.store(true, Ordering::SeqCst);
+                warn!(self.logger, "State tree is deep, keys may share long prefixes";
+                    "depth" => depth,
+                    "threshold" => threshold,
+                    "round" => round,
+                );
+            }
+            Ok(_) => {}
+            Err(error) => {
+                debug!(self.logger, "Failed to compute state tree depth"; "err" => %error);
+            }
+        }
+    }
+
+    fn commit_io(
+        &self,
+        ctx: &Arc<Context>,
+        io_root: Hash,
+        mut inputs: TxnBatch,
+        mut outputs: TxnBatch,
+        mut tags: Vec<Tags>,
+        partial: bool,
+        block: &Block,
+        round: u64,
+    ) -> Result<(WriteLog, Hash)> {
+        // For partial batches only the processed prefix of the inputs is
+        // included in the new I/O tree.
+        let processed_inputs = if partial {
+            Some(inputs[..outputs.len()].to_vec())
+        } else {
+            None
+        };
+
+        // Generate I/O root. Since we already fetched the inputs we avoid the need
+        // to fetch them again by generating the previous I/O tree (generated by the
+        // transaction scheduler) from the inputs.
+        let mut txn_tree = TxnTree::new(
+            Box::new(NoopReadSyncer),
+            Root {
+                namespace: block.header.namespace,
+                version: round,
+                hash: Hash::empty_hash(),
+            },
+        );
+        let mut hashes = Vec::new();
+        for (batch_order, input) in inputs.drain(..).enumerate() {
+            hashes.push(Hash::digest_bytes(&input));
+            txn_tree
+                .add_input(
+                    Context::create_child(&ctx),
+                    input,
+                    batch_order.try_into().unwrap(),
+                )
+                .context("failed to add transaction input")?;
+        }
+
+        let (_, old_io_root) = txn_tree
+            .commit(Context::create_child(&ctx))
+            .context("io commit failed")?;
+        if old_io_root != io_root {
+            panic!(
+                "dispatcher: I/O root inconsistent with inputs (expected: {:?} got: {:?})",
+                io_root, old_io_root
+            );
+        }
+
+        if let Some(processed_inputs) = processed_inputs {
+            txn_tree = TxnTree::new(
+                Box::new(NoopReadSyncer),
+                Root {
+                    namespace: block.header.namespace,
+                    version: round,
+                    hash: Hash::empty_hash(),
+                },
+            );
+            for (batch_order, input) in processed_inputs.into_iter().enumerate() {
+                txn_tree
+                    .add_input(
+                        Context::create_child(&ctx),
+                        input,
+                        batch_order.try_into().unwrap(),
+                    )
+                    .context("failed to add transaction input")?;
+            }
+        }
+
+        for (tx_hash, (output, tags)) in hashes.drain(..).zip(outputs.drain(..).zip(tags.drain(..)))
+        {
+            txn_tree
+                .add_output(Context::create_child(&ctx), tx_hash, output, tags)
+                .context("failed to add transaction output")?;
+        }
+
+        txn_tree
+            .commit(Context::create_child(&ctx))
+            .context("io commit failed")
+    }
+
+    fn dispatch_rpc(
+        &self,
+        rpc_demux: &mut RpcDemux,
+        rpc_dispatcher: &mut RpcDispatcher,
+        protocol: &Arc<Protocol>,
+        ctx: Context,
+        id: u64,
+        request: Vec<u8>,
+    ) {
+        let _span = tracing::dispatch_span(id, "rpc_call");
+        debug!(self.logger, "Received RPC call request");
+
+        // Process frame.
+        let mut buffer = vec![];
+        let result = match rpc_demux.process_frame(request, &mut buffer) {
+            Ok(result) => result,
+            Err(error) => {
+                if let Some(suppressed) = self.log_limiter.check("rpc_frame") {
+                    error!(self.logger, "Error while processing frame";
+                        "err" => %error,
+                        "suppressed" => suppressed,
+                    );
+                }
+
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", error),
+                    },
+                );
+                return;
+            }
+        };
+
+        let protocol_response;
+        if let Some((session_id, session_info, message, untrusted_plaintext)) = result {
+            // Dispatch request.
+            assert!(
+                buffer.is_empty(),
+                "must have no handshake data in transport mode"
+            );
+
+            match message {
+                RpcMessage::Request(req) => {
+                    // First make sure that the untrusted_plaintext matches
+                    // the request's method!
+                    if untrusted_plaintext != req.method {
+                        if let Some(suppressed) = self.log_limiter.check("rpc_method_mismatch") {
+                            error!(self.logger, "Request methods don't match!";
+                                "untrusted_plaintext" => ?untrusted_plaintext,
+                                "method" => ?req.method,
+                                "suppressed" => suppressed,
+                            );
+                        }
+                        let err_reponse = Body::Error {
+                            module: "".to_owned(), // XXX: Error codes.
+                            code: 0,               // XXX: Error codes.
+                            message: "Request's method doesn't match untrusted_plaintext copy."
+                                .to_string(),
+                        };
+                        self.send_response(protocol, id, err_reponse);
+                        return;
+                    }
 
                     // Request, dispatch.
                     let ctx = ctx.freeze();
-                    let mut mkvs = Tree::make().new(Box::new(NoopReadSyncer));
+                    let mut mkvs = Tree::new_in_memory();
                     let untrusted_local = Arc::new(ProtocolUntrustedLocalStorage::new(
                         Context::create_child(&ctx),
                         protocol.clone(),
                     ));
-                    let rpc_ctx = RpcContext::new(ctx.clone(), self.rak.clone(), session_info);
+                    let rpc_ctx = RpcContext::new(ctx.clone(), self.rak(), session_info);
                     let response =
                         StorageContext::enter(&mut mkvs, untrusted_local.clone(), || {
                             rpc_dispatcher.dispatch(req, rpc_ctx)
@@ -553,7 +1938,12 @@ impl Dispatcher {
                     }
                 }
                 msg => {
-                    warn!(self.logger, "Ignoring invalid RPC message type"; "msg" => ?msg);
+                    if let Some(suppressed) = self.log_limiter.check("rpc_message_type") {
+                        warn!(self.logger, "Ignoring invalid RPC message type";
+                            "msg" => ?msg,
+                            "suppressed" => suppressed,
+                        );
+                    }
                     protocol_response = Body::Error {
                         module: "".to_owned(), // XXX: Error codes.
                         code: 0,               // XXX: Error codes.
@@ -566,29 +1956,49 @@ impl Dispatcher {
             protocol_response = Body::RuntimeRPCCallResponse { response: buffer };
         }
 
-        protocol.send_response(id, protocol_response).unwrap();
+        self.send_response(protocol, id, protocol_response);
     }
 
     fn dispatch_local_rpc(
         &self,
         rpc_dispatcher: &mut RpcDispatcher,
+        codec: &dyn RpcCodec,
         protocol: &Arc<Protocol>,
         ctx: Context,
         id: u64,
         request: Vec<u8>,
     ) {
+        let _span = tracing::dispatch_span(id, "local_rpc_call");
         debug!(self.logger, "Received local RPC call request");
 
-        let req: RpcRequest = cbor::from_slice(&request).unwrap();
+        let req = match codec.decode(&request) {
+            Ok(req) => req,
+            Err(error) => {
+                error!(self.logger, "Error while decoding local RPC call request";
+                    "err" => %error,
+                );
+
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", error),
+                    },
+                );
+                return;
+            }
+        };
 
         // Request, dispatch.
         let ctx = ctx.freeze();
-        let mut mkvs = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut mkvs = Tree::new_in_memory();
         let untrusted_local = Arc::new(ProtocolUntrustedLocalStorage::new(
             Context::create_child(&ctx),
             protocol.clone(),
         ));
-        let rpc_ctx = RpcContext::new(ctx.clone(), self.rak.clone(), None);
+        let rpc_ctx = RpcContext::new(ctx.clone(), self.rak(), None);
         let response = StorageContext::enter(&mut mkvs, untrusted_local.clone(), || {
             rpc_dispatcher.dispatch_local(req, rpc_ctx)
         });
@@ -598,10 +2008,66 @@ impl Dispatcher {
 
         debug!(self.logger, "Local RPC call dispatch complete");
 
-        let response = cbor::to_vec(&response);
+        let response = match codec.encode(&response) {
+            Ok(response) => response,
+            Err(error) => {
+                error!(self.logger, "Error while encoding local RPC call response";
+                    "err" => %error,
+                );
+
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", error),
+                    },
+                );
+                return;
+            }
+        };
         let protocol_response = Body::RuntimeLocalRPCCallResponse { response };
 
-        protocol.send_response(id, protocol_response).unwrap();
+        self.send_response(protocol, id, protocol_response);
+    }
+
+    fn dispatch_consensus_sync(
+        &self,
+        protocol: &Arc<Protocol>,
+        _ctx: Context,
+        id: u64,
+        height: u64,
+    ) {
+        let _span = tracing::dispatch_span(id, "consensus_sync");
+        debug!(self.logger, "Received consensus sync request"; "height" => height);
+
+        let verifier = self.consensus_verifier.lock().unwrap().clone();
+        let result = match verifier {
+            Some(verifier) => verifier.sync(height),
+            None => Err(anyhow!("no consensus verifier configured")),
+        };
+        match result {
+            Ok(()) => {
+                debug!(self.logger, "Consensus sync complete"; "height" => height);
+                self.send_response(protocol, id, Body::RuntimeConsensusSyncResponse {});
+            }
+            Err(error) => {
+                warn!(self.logger, "Consensus sync failed";
+                    "height" => height,
+                    "err" => %error,
+                );
+                self.send_response(
+                    protocol,
+                    id,
+                    Body::Error {
+                        module: "".to_owned(), // XXX: Error codes.
+                        code: 0,               // XXX: Error codes.
+                        message: format!("{}", error),
+                    },
+                );
+            }
+        }
     }
 
     fn handle_km_policy_update(
@@ -612,46 +2078,111 @@ impl Dispatcher {
         id: u64,
         signed_policy_raw: Vec<u8>,
     ) {
+        let _span = tracing::dispatch_span(id, "km_policy_update");
         debug!(self.logger, "Received km policy update request");
-        rpc_dispatcher.handle_km_policy_update(signed_policy_raw);
+        let unchanged = rpc_dispatcher.handle_km_policy_update(signed_policy_raw);
+        if unchanged {
+            debug!(self.logger, "KM policy unchanged, skipping update");
+        }
+        *self.km_policy.lock().unwrap() = rpc_dispatcher.current_km_policy();
         debug!(self.logger, "KM policy update request complete");
 
-        protocol
-            .send_response(id, Body::RuntimeKeyManagerPolicyUpdateResponse {})
-            .unwrap();
+        self.send_response(
+            protocol,
+            id,
+            Body::RuntimeKeyManagerPolicyUpdateResponse { unchanged },
+        );
     }
 }
 
 struct Cache {
     protocol: Arc<Protocol>,
+    capacity: CacheCapacity,
     mkvs: Tree,
     root: Root,
+    /// Namespace of the first root seen, all later roots must match it.
+    namespace: Option<Namespace>,
+    /// Cache hits and misses of trees that have been replaced.
+    hits: u64,
+    misses: u64,
 }
 
 impl Cache {
-    fn new(protocol: Arc<Protocol>) -> Self {
+    fn new(protocol: Arc<Protocol>, capacity: CacheCapacity) -> Self {
         Self {
-            mkvs: Self::new_tree(&protocol, Default::default()),
+            mkvs: Self::new_tree(&protocol, capacity, Default::default()),
             root: Default::default(),
+            namespace: None,
             protocol,
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn replace_tree(&mut self, mkvs: Tree) {
+        let stats = self.mkvs.cache_stats();
+        self.hits += stats.hits;
+        self.misses += stats.misses;
+        self.mkvs = mkvs;
+    }
+
+    fn stats(&self) -> CacheStats {
+        let stats = self.mkvs.cache_stats();
+        CacheStats {
+            hits: self.hits + stats.hits,
+            misses: self.misses + stats.misses,
+            ..stats
         }
     }
 
-    fn new_tree(protocol: &Arc<Protocol>, root: Root) -> Tree {
+    fn new_tree(protocol: &Arc<Protocol>, capacity: CacheCapacity, root: Root) -> Tree {
         let read_syncer = HostReadSyncer::new(protocol.clone());
         Tree::make()
-            .with_capacity(100_000, 10_000_000)
+            .with_capacity(capacity.node_capacity, capacity.value_capacity)
             .with_root(root)
+            .with_monotonic_versions()
             .new(Box::new(read_syncer))
     }
 
-    fn maybe_replace(&mut self, root: Root) {
+    fn maybe_replace(&mut self, root: Root) -> Result<()> {
+        // Never switch to the state of a different runtime.
+        match self.namespace {
+            Some(namespace) if namespace != root.namespace => {
+                return Err(DispatcherError::NamespaceMismatch {
+                    expected: namespace,
+                    got: root.namespace,
+                }
+                .into());
+            }
+            Some(_) => {}
+            None => self.namespace = Some(root.namespace),
+        }
+
         if self.root == root {
-            return;
+            return Ok(());
         }
 
-        self.mkvs = Self::new_tree(&self.protocol, root);
+        // Unchanged subtrees share hashes, so keep the clean cached nodes to
+        // avoid re-fetching them from the host.
+        self.mkvs.switch_root(root);
         self.root = root;
+        Ok(())
+    }
+
+    /// Switch to a committed root computed elsewhere. Unlike `maybe_replace`
+    /// this is meant for state migrations, so the namespace may change and
+    /// the root is required to be reachable.
+    fn adopt_root(&mut self, root: Root) -> Result<()> {
+        self.mkvs.adopt_root(Context::background(), root)?;
+        self.namespace = Some(root.namespace);
+        self.root = root;
+        Ok(())
+    }
+
+    /// Discard the tree and go back to the last committed root.
+    fn reset(&mut self) {
+        self.replace_tree(Self::new_tree(&self.protocol, self.capacity, self.root));
     }
 
     fn commit(&mut self, version: u64, root_hash: Hash) {
@@ -659,3 +2190,853 @@ impl Cache {
         self.root.hash = root_hash;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fmt, os::unix::net::UnixStream};
+
+    use slog::KV;
+
+    use super::*;
+    use crate::{
+        common::version::Version,
+        enclave_rpc::{
+            dispatcher::{Method, MethodDescriptor},
+            types::{Body as RpcBody, Request as RpcRequest, Response as RpcResponse},
+        },
+    };
+
+    struct UnavailableSigner;
+
+    impl Signer for UnavailableSigner {
+        fn sign(&self, _context: &[u8], _message: &[u8]) -> Result<Signature> {
+            Err(anyhow!("re-attestation in progress"))
+        }
+    }
+
+    fn computed_batch(round: u64) -> ComputedBatch {
+        ComputedBatch {
+            header: ComputeResultsHeader {
+                round,
+                ..Default::default()
+            },
+            io_write_log: WriteLog::new(),
+            state_write_log: WriteLog::new(),
+            rak_sig: Signature::default(),
+            partial: false,
+        }
+    }
+
+    fn no_txn_dispatcher(
+        _protocol: &Arc<Protocol>,
+        _rak: &Arc<RAK>,
+        _rpc_demux: &mut RpcDemux,
+        _rpc_dispatcher: &mut RpcDispatcher,
+    ) -> Option<Box<dyn TxnDispatcher>> {
+        None
+    }
+
+    #[test]
+    fn test_sync_dispatcher() {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let sink_responses = responses.clone();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(Box::new(no_txn_dispatcher), rak.clone())
+            .read_only()
+            .response_sink(move |id: u64, body: &Body| {
+                let is_error = matches!(body, Body::Error { .. });
+                sink_responses.lock().unwrap().push((id, is_error));
+            })
+            .build_sync();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        assert!(
+            dispatcher
+                .process_one(Context::background(), 1, Body::RuntimeAbortRequest {})
+                .is_err(),
+            "requests should fail before the dispatcher is started"
+        );
+        dispatcher.start(protocol);
+        assert!(dispatcher.dispatcher().is_ready());
+
+        // Requests are processed to completion before process_one returns.
+        dispatcher
+            .process_one(
+                Context::background(),
+                1,
+                Body::RuntimeExecuteTxBatchRequest {
+                    io_root: Hash::default(),
+                    inputs: TxnBatch::default(),
+                    block: Block::default(),
+                    inputs_hash: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(*responses.lock().unwrap(), vec![(1, true)]);
+        assert_eq!(dispatcher.dispatcher().metrics_snapshot().in_flight, 0);
+
+        // Directly dispatched abort requests are acknowledged.
+        dispatcher
+            .process_one(Context::background(), 2, Body::RuntimeAbortRequest {})
+            .unwrap();
+        assert!(dispatcher
+            .process_one(Context::background(), 3, Body::Empty {})
+            .is_err());
+        assert_eq!(*responses.lock().unwrap(), vec![(1, true), (2, false)]);
+    }
+
+    #[test]
+    fn test_abort_response() {
+        let (response_tx, response_rx) = channel::unbounded();
+        let rak = Arc::new(RAK::new());
+        let dispatcher = DispatcherBuilder::new(Box::new(no_txn_dispatcher), rak.clone())
+            .response_sink(move |id: u64, body: &Body| {
+                let is_abort = matches!(body, Body::RuntimeAbortResponse {});
+                response_tx.send((id, is_abort)).unwrap();
+            })
+            .build();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        // Aborts via abort_and_wait are responded to by the caller.
+        dispatcher
+            .abort_and_wait(Context::background(), 1, Body::RuntimeAbortRequest {})
+            .unwrap();
+
+        // Directly queued aborts are responded to by the dispatcher.
+        dispatcher
+            .queue_request(Context::background(), 2, Body::RuntimeAbortRequest {})
+            .unwrap();
+        assert_eq!(
+            response_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (2, true)
+        );
+        assert!(response_rx.try_recv().is_err());
+    }
+
+    struct JsonCodec;
+
+    impl RpcCodec for JsonCodec {
+        fn decode(&self, data: &[u8]) -> Result<RpcRequest> {
+            Ok(serde_json::from_slice(data)?)
+        }
+
+        fn encode(&self, message: &RpcMessage) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(message)?)
+        }
+    }
+
+    struct JsonRpcInitializer;
+
+    impl Initializer for JsonRpcInitializer {
+        fn init(
+            &self,
+            _protocol: &Arc<Protocol>,
+            _rak: &Arc<RAK>,
+            _rpc_demux: &mut RpcDemux,
+            rpc_dispatcher: &mut RpcDispatcher,
+        ) -> Option<Box<dyn TxnDispatcher>> {
+            rpc_dispatcher.add_method(
+                Method::new(
+                    MethodDescriptor {
+                        name: "echo".to_owned(),
+                    },
+                    |request: &String, _ctx: &mut RpcContext| -> Result<String> {
+                        Ok(request.clone())
+                    },
+                ),
+                true,
+            );
+            None
+        }
+
+        fn local_rpc_codec(&self) -> Option<Arc<dyn RpcCodec>> {
+            Some(Arc::new(JsonCodec))
+        }
+    }
+
+    #[test]
+    fn test_local_rpc_codec() {
+        let (response_tx, response_rx) = channel::unbounded();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(Box::new(JsonRpcInitializer), rak.clone())
+            .response_sink(move |_id: u64, body: &Body| match body {
+                Body::RuntimeLocalRPCCallResponse { response } => {
+                    response_tx.send(Ok(response.clone())).unwrap()
+                }
+                _ => response_tx.send(Err(format!("{:?}", body))).unwrap(),
+            })
+            .build_sync();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        let request = serde_json::to_vec(&RpcRequest {
+            method: "echo".to_owned(),
+            args: cbor::to_value("hello"),
+        })
+        .unwrap();
+        dispatcher
+            .process_one(
+                Context::background(),
+                1,
+                Body::RuntimeLocalRPCCallRequest { request },
+            )
+            .unwrap();
+        let response = response_rx.try_recv().unwrap().unwrap();
+        match serde_json::from_slice(&response).unwrap() {
+            RpcMessage::Response(RpcResponse {
+                body: RpcBody::Success(value),
+            }) => assert_eq!(cbor::from_value::<String>(value).unwrap(), "hello"),
+            message => panic!("unexpected response: {:?}", message),
+        }
+
+        // CBOR requests are rejected.
+        let request = cbor::to_vec(&RpcRequest {
+            method: "echo".to_owned(),
+            args: cbor::to_value("hello"),
+        });
+        dispatcher
+            .process_one(
+                Context::background(),
+                2,
+                Body::RuntimeLocalRPCCallRequest { request },
+            )
+            .unwrap();
+        assert!(response_rx.try_recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+
+        // Failures below the threshold keep the circuit closed and a success
+        // resets the count.
+        breaker.record_failure_at(start);
+        breaker.record_success();
+        breaker.record_failure_at(start);
+        assert_eq!(breaker.state_at(start), CircuitState::Closed);
+        assert!(breaker.try_acquire_at(start));
+
+        // Consecutive failures open the circuit.
+        breaker.record_failure_at(start);
+        assert_eq!(breaker.state_at(start), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(start));
+
+        // After the cooldown, a single probe is let through.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(breaker.state_at(later), CircuitState::HalfOpen);
+        assert!(breaker.try_acquire_at(later));
+        assert!(!breaker.try_acquire_at(later));
+
+        // A failed probe opens the circuit for another cooldown.
+        breaker.record_failure_at(later);
+        assert_eq!(breaker.state_at(later), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(later + Duration::from_secs(5)));
+
+        // A successful probe closes the circuit.
+        let much_later = later + Duration::from_secs(10);
+        assert!(breaker.try_acquire_at(much_later));
+        breaker.record_success();
+        assert_eq!(breaker.state_at(much_later), CircuitState::Closed);
+        assert!(breaker.try_acquire_at(much_later));
+        assert!(breaker.try_acquire_at(much_later));
+    }
+
+    #[test]
+    fn test_computed_batch_cache() {
+        let io_root = Hash::digest_bytes(b"io root");
+        let mut cache = ComputedBatchCache::new(2);
+
+        // The first request is not cached, the resent one is.
+        assert!(cache.get(1, io_root).is_none());
+        cache.insert(1, io_root, computed_batch(1));
+        let batch = cache
+            .get(1, io_root)
+            .expect("resent request should be cached");
+        assert_eq!(batch.header.round, 1);
+        assert!(cache.get(1, Hash::default()).is_none());
+
+        // The least recently used batch is evicted.
+        cache.insert(2, io_root, computed_batch(2));
+        assert!(cache.get(1, io_root).is_some());
+        cache.insert(3, io_root, computed_batch(3));
+        assert!(cache.get(2, io_root).is_none());
+        assert!(cache.get(1, io_root).is_some());
+        assert!(cache.get(3, io_root).is_some());
+
+        // A disabled cache never stores anything.
+        let mut cache = ComputedBatchCache::new(0);
+        cache.insert(1, io_root, computed_batch(1));
+        assert!(cache.get(1, io_root).is_none());
+    }
+
+    #[test]
+    fn test_computed_batch_cache_oversized() {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let sink_responses = responses.clone();
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let hook_commits = commits.clone();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(Box::new(no_txn_dispatcher), rak.clone())
+            .computed_batch_cache_size(1)
+            .max_response_size(1)
+            .on_commit(move |round: u64, _state_root: Hash, _io_root: Hash| {
+                hook_commits.lock().unwrap().push(round);
+            })
+            .response_sink(move |id: u64, body: &Body| {
+                let is_error = matches!(body, Body::Error { .. });
+                sink_responses.lock().unwrap().push((id, is_error));
+            })
+            .build_sync();
+        let roots = dispatcher.dispatcher().watch_state_root();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        // A resent request for an oversized result must not be served from
+        // the cache.
+        for id in 1..=2 {
+            dispatcher
+                .process_one(
+                    Context::background(),
+                    id,
+                    Body::RuntimeExecuteTxBatchRequest {
+                        io_root: Hash::empty_hash(),
+                        inputs: TxnBatch::default(),
+                        block: Block::default(),
+                        inputs_hash: None,
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(*responses.lock().unwrap(), vec![(1, true), (2, true)]);
+        assert!(dispatcher
+            .dispatcher()
+            .computed_batches
+            .lock()
+            .unwrap()
+            .get(1, Hash::empty_hash())
+            .is_none());
+
+        // Nothing is recorded for the rejected batches.
+        assert!(roots.try_recv().is_err(), "state root should not advance");
+        assert_eq!(dispatcher.dispatcher().metrics_snapshot().last_round, None);
+        assert!(
+            commits.lock().unwrap().is_empty(),
+            "commit hook should not run"
+        );
+    }
+
+    /// Transaction dispatcher that echoes its inputs, counting the number of
+    /// dispatched batches.
+    struct CountingTxnDispatcher {
+        dispatched: Arc<AtomicUsize>,
+    }
+
+    impl TxnDispatcher for CountingTxnDispatcher {
+        fn dispatch_batch(
+            &self,
+            batch: &TxnBatch,
+            _ctx: TxnContext,
+        ) -> Result<(TxnBatch, Vec<Tags>, Vec<RoothashMessage>)> {
+            self.dispatched.fetch_add(1, Ordering::SeqCst);
+            let tags = vec![Tags::new(); batch.len()];
+            Ok((TxnBatch::new(batch.to_vec()), tags, Vec::new()))
+        }
+
+        fn finalize(&self, _new_storage_root: Hash) {}
+
+        fn set_abort_batch_flag(&mut self, _abort_batch: Arc<AtomicBool>) {}
+    }
+
+    #[test]
+    fn test_computed_batch_cache_resent() {
+        let dispatched = Arc::new(AtomicUsize::new(0));
+        let txn_dispatched = dispatched.clone();
+        let initializer = move |_protocol: &Arc<Protocol>,
+                                _rak: &Arc<RAK>,
+                                _rpc_demux: &mut RpcDemux,
+                                _rpc_dispatcher: &mut RpcDispatcher|
+              -> Option<Box<dyn TxnDispatcher>> {
+            Some(Box::new(CountingTxnDispatcher {
+                dispatched: txn_dispatched.clone(),
+            }))
+        };
+        let (response_tx, response_rx) = channel::unbounded();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(Box::new(initializer), rak.clone())
+            .computed_batch_cache_size(1)
+            .response_sink(move |id: u64, body: &Body| {
+                response_tx.send((id, cbor::to_vec(body))).unwrap();
+            })
+            .build_sync();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        // A resent request is served from the cache without dispatching the
+        // batch again.
+        let inputs = TxnBatch::new(vec![b"tx".to_vec()]);
+        let mut block = Block::default();
+        block.header.state_root = Hash::empty_hash();
+        for id in 1..=2 {
+            dispatcher
+                .process_one(
+                    Context::background(),
+                    id,
+                    Body::RuntimeExecuteTxBatchRequest {
+                        io_root: io_root(&inputs, &[], 1),
+                        inputs: inputs.clone(),
+                        block: block.clone(),
+                        inputs_hash: None,
+                    },
+                )
+                .unwrap();
+        }
+        let (id, first) = response_rx.try_recv().unwrap();
+        assert_eq!(id, 1);
+        let (id, second) = response_rx.try_recv().unwrap();
+        assert_eq!(id, 2);
+        assert!(matches!(
+            cbor::from_slice(&first).unwrap(),
+            Body::RuntimeExecuteTxBatchResponse { .. }
+        ));
+        assert_eq!(first, second, "resent request should get the same response");
+        assert_eq!(dispatched.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_duplicate_inputs() {
+        let dispatched = Arc::new(AtomicUsize::new(0));
+        let txn_dispatched = dispatched.clone();
+        let initializer = move |_protocol: &Arc<Protocol>,
+                                _rak: &Arc<RAK>,
+                                _rpc_demux: &mut RpcDemux,
+                                _rpc_dispatcher: &mut RpcDispatcher|
+              -> Option<Box<dyn TxnDispatcher>> {
+            Some(Box::new(CountingTxnDispatcher {
+                dispatched: txn_dispatched.clone(),
+            }))
+        };
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let hook_commits = commits.clone();
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let sink_responses = responses.clone();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(Box::new(initializer), rak.clone())
+            .on_commit(move |round: u64, _state_root: Hash, _io_root: Hash| {
+                hook_commits.lock().unwrap().push(round);
+            })
+            .response_sink(move |id: u64, body: &Body| {
+                let is_error = matches!(body, Body::Error { .. });
+                sink_responses.lock().unwrap().push((id, is_error));
+            })
+            .build_sync();
+        let roots = dispatcher.dispatcher().watch_state_root();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        // Batches with duplicate transactions are rejected without being
+        // dispatched.
+        let inputs = TxnBatch::new(vec![b"tx".to_vec(), b"tx".to_vec()]);
+        let mut block = Block::default();
+        block.header.state_root = Hash::empty_hash();
+        dispatcher
+            .process_one(
+                Context::background(),
+                1,
+                Body::RuntimeExecuteTxBatchRequest {
+                    io_root: io_root(&inputs, &[], 1),
+                    inputs,
+                    block,
+                    inputs_hash: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(*responses.lock().unwrap(), vec![(1, true)]);
+        assert_eq!(dispatched.load(Ordering::SeqCst), 0);
+
+        // Nothing is recorded for the rejected batch.
+        assert!(roots.try_recv().is_err(), "state root should not advance");
+        assert_eq!(dispatcher.dispatcher().metrics_snapshot().last_round, None);
+        assert!(
+            commits.lock().unwrap().is_empty(),
+            "commit hook should not run"
+        );
+    }
+
+    /// Transaction dispatcher that stops after the first transaction of each
+    /// batch, writing each processed input into the state.
+    struct TruncatingTxnDispatcher {
+        partial_batches: bool,
+    }
+
+    impl TxnDispatcher for TruncatingTxnDispatcher {
+        fn dispatch_batch(
+            &self,
+            batch: &TxnBatch,
+            _ctx: TxnContext,
+        ) -> Result<(TxnBatch, Vec<Tags>, Vec<RoothashMessage>)> {
+            let processed: Vec<Vec<u8>> = batch.iter().take(1).cloned().collect();
+            for input in &processed {
+                StorageContext::with_current(|mkvs, _| {
+                    mkvs.insert(Context::background(), input, input);
+                });
+            }
+            let tags = vec![Tags::new(); processed.len()];
+            Ok((TxnBatch::new(processed), tags, Vec::new()))
+        }
+
+        fn finalize(&self, _new_storage_root: Hash) {}
+
+        fn set_abort_batch_flag(&mut self, _abort_batch: Arc<AtomicBool>) {}
+
+        fn partial_batches(&self) -> bool {
+            self.partial_batches
+        }
+    }
+
+    fn partial_txn_dispatcher(
+        _protocol: &Arc<Protocol>,
+        _rak: &Arc<RAK>,
+        _rpc_demux: &mut RpcDemux,
+        _rpc_dispatcher: &mut RpcDispatcher,
+    ) -> Option<Box<dyn TxnDispatcher>> {
+        Some(Box::new(TruncatingTxnDispatcher {
+            partial_batches: true,
+        }))
+    }
+
+    fn truncating_txn_dispatcher(
+        _protocol: &Arc<Protocol>,
+        _rak: &Arc<RAK>,
+        _rpc_demux: &mut RpcDemux,
+        _rpc_dispatcher: &mut RpcDispatcher,
+    ) -> Option<Box<dyn TxnDispatcher>> {
+        Some(Box::new(TruncatingTxnDispatcher {
+            partial_batches: false,
+        }))
+    }
+
+    /// Execute a batch of two transactions, returning the response.
+    fn execute_truncated_batch(initializer: Box<dyn Initializer>) -> Body {
+        let (response_tx, response_rx) = channel::unbounded();
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher = DispatcherBuilder::new(initializer, rak.clone())
+            .response_sink(move |_id: u64, body: &Body| {
+                response_tx.send(cbor::to_vec(body)).unwrap();
+            })
+            .build_sync();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        let inputs = TxnBatch::new(vec![b"first".to_vec(), b"second".to_vec()]);
+        let mut block = Block::default();
+        block.header.state_root = Hash::empty_hash();
+        dispatcher
+            .process_one(
+                Context::background(),
+                1,
+                Body::RuntimeExecuteTxBatchRequest {
+                    io_root: io_root(&inputs, &[], 1),
+                    inputs,
+                    block,
+                    inputs_hash: None,
+                },
+            )
+            .unwrap();
+        cbor::from_slice(&response_rx.try_recv().unwrap()).unwrap()
+    }
+
+    /// Compute the I/O root for the given inputs and the outputs of the
+    /// processed prefix.
+    fn io_root(inputs: &TxnBatch, outputs: &[Vec<u8>], round: u64) -> Hash {
+        let mut txn_tree = TxnTree::new(
+            Box::new(NoopReadSyncer),
+            Root {
+                version: round,
+                hash: Hash::empty_hash(),
+                ..Default::default()
+            },
+        );
+        for (batch_order, input) in inputs.iter().enumerate() {
+            txn_tree
+                .add_input(Context::background(), input.clone(), batch_order as u32)
+                .unwrap();
+        }
+        for (input, output) in inputs.iter().zip(outputs) {
+            txn_tree
+                .add_output(
+                    Context::background(),
+                    Hash::digest_bytes(input),
+                    output.clone(),
+                    Tags::new(),
+                )
+                .unwrap();
+        }
+        let (_, io_root) = txn_tree.commit(Context::background()).unwrap();
+        io_root
+    }
+
+    #[test]
+    fn test_partial_batch() {
+        let batch = match execute_truncated_batch(Box::new(partial_txn_dispatcher)) {
+            Body::RuntimeExecuteTxBatchResponse { batch } => batch,
+            body => panic!("unexpected response: {:?}", body),
+        };
+        assert!(batch.partial, "batch should be marked as partial");
+
+        // Only the processed prefix is included in the new I/O root.
+        let prefix = TxnBatch::new(vec![b"first".to_vec()]);
+        assert_eq!(
+            batch.header.io_root,
+            Some(io_root(&prefix, &[b"first".to_vec()], 1))
+        );
+
+        // Only the processed prefix has been applied to the state.
+        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        tree.insert(Context::background(), b"first", b"first")
+            .unwrap();
+        let (_, state_root) = tree
+            .commit(Context::background(), Default::default(), 1)
+            .unwrap();
+        assert_eq!(batch.header.state_root, Some(state_root));
+
+        // Without partial batch support, truncated results are rejected.
+        assert!(matches!(
+            execute_truncated_batch(Box::new(truncating_txn_dispatcher)),
+            Body::Error { .. }
+        ));
+    }
+
+    #[test]
+    fn test_abort_on_panic_poison() {
+        let poisoned = Arc::new(AtomicBool::new(false));
+        let flag = poisoned.clone();
+        let result = thread::spawn(move || {
+            let _guard = AbortOnPanic {
+                poisoned: Some(flag),
+            };
+            panic!("dispatch failed");
+        })
+        .join();
+        assert!(result.is_err(), "thread should have panicked");
+        assert!(poisoned.load(Ordering::SeqCst), "panic should poison");
+
+        // Dropping the guard without a panic doesn't poison.
+        let poisoned = Arc::new(AtomicBool::new(false));
+        drop(AbortOnPanic {
+            poisoned: Some(poisoned.clone()),
+        });
+        assert!(!poisoned.load(Ordering::SeqCst));
+    }
+
+    struct PanickingTxnDispatcher;
+
+    impl TxnDispatcher for PanickingTxnDispatcher {
+        fn dispatch_batch(
+            &self,
+            _batch: &TxnBatch,
+            _ctx: TxnContext,
+        ) -> Result<(TxnBatch, Vec<Tags>, Vec<RoothashMessage>)> {
+            panic!("dispatch failed");
+        }
+
+        fn finalize(&self, _new_storage_root: Hash) {}
+
+        fn set_abort_batch_flag(&mut self, _abort_batch: Arc<AtomicBool>) {}
+    }
+
+    fn panicking_txn_dispatcher(
+        _protocol: &Arc<Protocol>,
+        _rak: &Arc<RAK>,
+        _rpc_demux: &mut RpcDemux,
+        _rpc_dispatcher: &mut RpcDispatcher,
+    ) -> Option<Box<dyn TxnDispatcher>> {
+        Some(Box::new(PanickingTxnDispatcher))
+    }
+
+    #[test]
+    fn test_sync_dispatcher_poison() {
+        let rak = Arc::new(RAK::new());
+        let mut dispatcher =
+            DispatcherBuilder::new(Box::new(panicking_txn_dispatcher), rak.clone())
+                .poison_on_panic()
+                .response_sink(|_id: u64, _body: &Body| {})
+                .build_sync();
+
+        let (stream, _host) = UnixStream::pair().unwrap();
+        let protocol = Arc::new(Protocol::new(
+            stream,
+            rak,
+            dispatcher.dispatcher().clone(),
+            Version::new(0, 0, 0),
+        ));
+        dispatcher.start(protocol);
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            dispatcher.process_one(
+                Context::background(),
+                1,
+                Body::RuntimeExecuteTxBatchRequest {
+                    io_root: Hash::default(),
+                    inputs: TxnBatch::default(),
+                    block: Block::default(),
+                    inputs_hash: None,
+                },
+            )
+        }));
+        assert!(result.is_err(), "dispatch should have panicked");
+        assert!(dispatcher.dispatcher().is_poisoned());
+
+        // The panicked request is no longer in flight, so draining doesn't
+        // block.
+        assert_eq!(dispatcher.dispatcher().metrics_snapshot().in_flight, 0);
+        drop(dispatcher.dispatcher().drain_and_pause());
+
+        assert!(dispatcher
+            .process_one(Context::background(), 2, Body::RuntimeAbortRequest {})
+            .is_err());
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload: Box<dyn Any + Send> = Box::new("static message");
+        assert_eq!(panic_message(&*payload), "static message");
+        let payload: Box<dyn Any + Send> = Box::new(format!("formatted {}", 42));
+        assert_eq!(panic_message(&*payload), "formatted 42");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(panic_message(&*payload), "<non-string panic payload>");
+
+        // Panics are logged and still caught by the poisoned path with the
+        // hook. Other tests may panic concurrently, so use a distinct message.
+        let drain = RecordingDrain::default();
+        let records = drain.records.clone();
+        let logger = Logger::root(drain, o!());
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info: &PanicInfo| log_panic(&logger, info)));
+        let poisoned = Arc::new(AtomicBool::new(false));
+        let flag = poisoned.clone();
+        let result = thread::spawn(move || {
+            let _guard = AbortOnPanic {
+                poisoned: Some(flag),
+            };
+            panic!("panic hook test");
+        })
+        .join();
+        panic::set_hook(previous);
+        assert!(result.is_err(), "thread should have panicked");
+        assert!(poisoned.load(Ordering::SeqCst), "panic should poison");
+
+        let records = records.lock().unwrap();
+        let (message, fields) = records
+            .iter()
+            .find(|(_, fields)| fields.get("msg").map(String::as_str) == Some("panic hook test"))
+            .expect("panic should be logged");
+        assert_eq!(message, "Runtime panicked");
+        assert!(
+            fields["location"].starts_with(file!()),
+            "unexpected location: {}",
+            fields["location"]
+        );
+        assert!(
+            !fields["backtrace"].is_empty(),
+            "backtrace should be logged"
+        );
+    }
+
+    /// Drain recording the message and the key-value pairs of each record.
+    #[derive(Default)]
+    struct RecordingDrain {
+        records: Arc<Mutex<Vec<(String, HashMap<String, String>)>>>,
+    }
+
+    impl slog::Drain for RecordingDrain {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            _values: &slog::OwnedKVList,
+        ) -> std::result::Result<(), slog::Never> {
+            let mut fields = RecordingSerializer(HashMap::new());
+            record.kv().serialize(record, &mut fields).unwrap();
+            self.records
+                .lock()
+                .unwrap()
+                .push((record.msg().to_string(), fields.0));
+            Ok(())
+        }
+    }
+
+    struct RecordingSerializer(HashMap<String, String>);
+
+    impl slog::Serializer for RecordingSerializer {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sign_compute_results_header_unavailable() {
+        let header = ComputeResultsHeader {
+            round: 1,
+            ..Default::default()
+        };
+        let error = sign_compute_results_header(&UnavailableSigner, &header)
+            .expect_err("signing should fail");
+        assert!(matches!(
+            error.downcast_ref::<DispatcherError>(),
+            Some(DispatcherError::AttestationUnavailable)
+        ));
+    }
+}
@@ -0,0 +1,33 @@
+//! RPC message codecs.
+use anyhow::Result;
+
+use super::types::{Message, Request};
+use crate::common::cbor;
+
+/// Codec used to decode RPC requests and encode RPC messages.
+///
+/// Remote (encrypted) RPC calls always use CBOR, as the message format is
+/// part of the secure channel protocol. A different codec can only be
+/// installed for local RPC calls, which come from the untrusted host
+/// anyway, e.g., to bridge to a debugging proxy that speaks JSON.
+pub trait Codec: Send + Sync {
+    /// Decode a serialized RPC request.
+    fn decode(&self, data: &[u8]) -> Result<Request>;
+
+    /// Encode an RPC message.
+    fn encode(&self, message: &Message) -> Result<Vec<u8>>;
+}
+
+/// The default CBOR codec.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn decode(&self, data: &[u8]) -> Result<Request> {
+        Ok(cbor::from_slice(data)?)
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        Ok(cbor::to_vec(message))
+    }
+}
@@ -70,6 +70,13 @@ impl Demux {
         self.stale_session_timeout = stale_session_timeout;
     }
 
+    /// Configures the RAK used to bind new sessions.
+    ///
+    /// Existing sessions keep the binding they were established with.
+    pub fn set_rak(&mut self, rak: Arc<RAK>) {
+        self.rak = rak;
+    }
+
     fn purge_stale_sessions(&mut self) {
         let now = insecure_posix_system_time();
         let stale_session_timeout = self.stale_session_timeout;
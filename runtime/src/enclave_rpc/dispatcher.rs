@@ -9,7 +9,7 @@ use super::{
     context::Context,
     types::{Body, Request, Response},
 };
-use crate::common::cbor;
+use crate::common::{cbor, crypto::hash::Hash};
 
 /// Dispatch error.
 #[derive(Error, Debug)]
@@ -128,6 +128,15 @@ impl Method {
 /// Key manager policy update handler callback.
 pub type KeyManagerPolicyHandler = dyn Fn(Vec<u8>) -> ();
 
+/// Information about the currently applied key manager policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KmPolicyInfo {
+    /// Hash of the raw signed policy.
+    pub hash: Hash,
+    /// Number of policy updates applied so far, including this one.
+    pub updates: u64,
+}
+
 /// RPC call dispatcher.
 pub struct Dispatcher {
     /// Registered RPC methods.
@@ -136,6 +145,8 @@ pub struct Dispatcher {
     local_methods: HashMap<String, Method>,
     /// Registered key manager policy handler.
     km_policy_handler: Option<Box<KeyManagerPolicyHandler>>,
+    /// Currently applied key manager policy.
+    km_policy: Option<KmPolicyInfo>,
     /// Registered context initializer.
     ctx_initializer: Option<Box<dyn ContextInitializer>>,
 }
@@ -147,6 +158,7 @@ impl Dispatcher {
             methods: HashMap::new(),
             local_methods: HashMap::new(),
             km_policy_handler: None,
+            km_policy: None,
             ctx_initializer: None,
         }
     }
@@ -159,6 +171,34 @@ impl Dispatcher {
         };
     }
 
+    /// Return the sorted names of all registered RPC methods, both local and
+    /// remotely callable ones.
+    pub fn registered_methods(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .methods
+            .keys()
+            .chain(self.local_methods.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Return the sorted names of registered remotely callable RPC methods.
+    pub fn registered_remote_methods(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.methods.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Return the sorted names of registered local RPC methods.
+    pub fn registered_local_methods(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.local_methods.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
     /// Configure context initializer.
     pub fn set_context_initializer<I>(&mut self, initializer: I)
     where
@@ -220,10 +260,33 @@ impl Dispatcher {
     }
 
     /// Handle key manager policy update.
-    pub fn handle_km_policy_update(&self, signed_policy_raw: Vec<u8>) {
-        self.km_policy_handler
-            .as_ref()
-            .map(|handler| handler(signed_policy_raw));
+    ///
+    /// Returns true iff the policy is identical to the currently applied
+    /// policy, in which case it is not applied again.
+    pub fn handle_km_policy_update(&mut self, signed_policy_raw: Vec<u8>) -> bool {
+        let handler = match self.km_policy_handler {
+            Some(ref handler) => handler,
+            None => return false,
+        };
+
+        let hash = Hash::digest_bytes(&signed_policy_raw);
+        if let Some(ref info) = self.km_policy {
+            if info.hash == hash {
+                return true;
+            }
+        }
+        handler(signed_policy_raw);
+
+        let updates = self.km_policy.as_ref().map_or(0, |info| info.updates) + 1;
+        self.km_policy = Some(KmPolicyInfo { hash, updates });
+        false
+    }
+
+    /// Return information about the currently applied key manager policy.
+    ///
+    /// Returns `None` if no policy has been applied yet.
+    pub fn current_km_policy(&self) -> Option<KmPolicyInfo> {
+        self.km_policy.clone()
     }
 
     /// Update key manager policy update handler.
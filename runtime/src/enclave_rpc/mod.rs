@@ -1,5 +1,6 @@
 //! Secure inter-enclave RPC.
 
+pub mod codec;
 pub mod context;
 pub mod demux;
 pub mod dispatcher;
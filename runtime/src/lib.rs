@@ -13,6 +13,7 @@
 #![feature(test)]
 #![feature(box_into_pin)]
 #![feature(arbitrary_self_types)]
+#![feature(backtrace)]
 
 #[macro_use]
 extern crate slog;
@@ -47,8 +48,10 @@ use lazy_static::lazy_static;
 #[cfg(target_env = "sgx")]
 use sgx_isa::{AttributesFlags, Report};
 
+pub mod cancellation;
 #[macro_use]
 pub mod common;
+pub mod consensus;
 pub mod dispatcher;
 pub mod enclave_rpc;
 pub mod executor;
@@ -31,7 +31,7 @@ pub type Stream = ::std::os::unix::net::UnixStream;
 pub type Stream = ::std::net::TcpStream;
 
 /// Maximum message size.
-const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024; // 16MiB
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024; // 16MiB
 
 #[derive(Error, Debug)]
 pub enum ProtocolError {
@@ -334,6 +334,11 @@ impl Protocol {
                 self.dispatcher.queue_request(ctx, id, req)?;
                 Ok(None)
             }
+            req @ Body::RuntimeConsensusSyncRequest { .. } => {
+                self.can_handle_runtime_requests()?;
+                self.dispatcher.queue_request(ctx, id, req)?;
+                Ok(None)
+            }
             req => {
                 warn!(self.logger, "Received unsupported request"; "req" => format!("{:?}", req));
                 Err(ProtocolError::MethodNotSupported.into())
@@ -2,9 +2,16 @@
 //!
 //! The storage context is a convenient way to share CAS and MKVS
 //! implementations across the current thread.
-use std::{cell::RefCell, sync::Arc};
+use std::{cell::RefCell, mem, sync::Arc};
 
-use super::{KeyValue, MKVS};
+use anyhow::{anyhow, Result};
+use io_context::Context;
+
+use super::{
+    mkvs::{Prefix, WriteLog},
+    KeyValue, MKVS,
+};
+use crate::common::{crypto::hash::Hash, roothash::Namespace};
 
 struct Ctx {
     mkvs: *mut dyn MKVS,
@@ -42,6 +49,91 @@ impl Drop for CtxGuard {
     }
 }
 
+/// An MKVS wrapper recording the previous values of all modified keys so
+/// that the modifications can be undone.
+struct Journal {
+    inner: *mut dyn MKVS,
+    undo: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+// The journal is only used from the thread that entered the storage context,
+// the same as the MKVS it wraps.
+unsafe impl Send for Journal {}
+unsafe impl Sync for Journal {}
+
+impl Journal {
+    #[allow(clippy::mut_from_ref)]
+    fn inner(&self) -> &mut dyn MKVS {
+        unsafe { self.inner.as_mut().expect("pointer is never null") }
+    }
+
+    /// Undo all recorded modifications, most recent first.
+    fn revert(&mut self) {
+        let undo = mem::take(&mut self.undo);
+        let inner = self.inner();
+        for (key, previous) in undo.into_iter().rev() {
+            match previous {
+                Some(value) => inner.insert(Context::background(), &key, &value),
+                None => inner.remove(Context::background(), &key),
+            };
+        }
+    }
+}
+
+impl MKVS for Journal {
+    fn get(&self, ctx: Context, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner().get(ctx, key)
+    }
+
+    fn cache_contains_key(&self, ctx: Context, key: &[u8]) -> bool {
+        self.inner().cache_contains_key(ctx, key)
+    }
+
+    fn insert(&mut self, ctx: Context, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.inner().insert(ctx, key, value);
+        self.undo.push((key.to_vec(), previous.clone()));
+        previous
+    }
+
+    fn remove(&mut self, ctx: Context, key: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.inner().remove(ctx, key);
+        self.undo.push((key.to_vec(), previous.clone()));
+        previous
+    }
+
+    fn prefetch_prefixes(&self, ctx: Context, prefixes: &Vec<Prefix>, limit: u16) {
+        self.inner().prefetch_prefixes(ctx, prefixes, limit)
+    }
+
+    fn commit(
+        &mut self,
+        _ctx: Context,
+        _namespace: Namespace,
+        _version: u64,
+    ) -> Result<(WriteLog, Hash)> {
+        Err(anyhow!(
+            "storage: commit is not allowed inside an atomic section"
+        ))
+    }
+
+    fn rollback(&mut self) {
+        self.undo.clear();
+        self.inner().rollback()
+    }
+}
+
+/// Guard restoring the MKVS replaced by a journal.
+struct JournalGuard(*mut dyn MKVS);
+
+impl Drop for JournalGuard {
+    fn drop(&mut self) {
+        let mkvs = self.0;
+        CTX.with(|ctx| {
+            ctx.borrow_mut().as_mut().expect("context is entered").mkvs = mkvs;
+        });
+    }
+}
+
 /// Thread-local storage context.
 pub struct StorageContext;
 
@@ -73,4 +165,109 @@ impl StorageContext {
             f(mkvs_ref, &ctx_ref.untrusted_local)
         })
     }
+
+    /// Run a closure atomically with respect to the thread-local MKVS: if the
+    /// closure returns an error, all modifications it made are undone.
+    ///
+    /// Committing the MKVS from within the closure is not allowed.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if called outside `StorageContext::enter`.
+    pub fn atomically<F, T, E>(f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce() -> std::result::Result<T, E>,
+    {
+        let mut journal = Box::new(Journal {
+            inner: CTX.with(|ctx| {
+                ctx.borrow()
+                    .as_ref()
+                    .expect("must only be called while entered")
+                    .mkvs
+            }),
+            undo: Vec::new(),
+        });
+
+        let result = {
+            let journal_ptr: *mut dyn MKVS = &mut *journal;
+            let _guard = JournalGuard(journal.inner);
+            CTX.with(|ctx| {
+                ctx.borrow_mut().as_mut().expect("context is entered").mkvs = journal_ptr;
+            });
+            f()
+        };
+
+        if result.is_err() {
+            journal.revert();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::mkvs::Tree;
+
+    struct NoopKeyValue;
+
+    impl KeyValue for NoopKeyValue {
+        fn get(&self, _key: Vec<u8>) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn insert(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_atomically() {
+        let mut tree = Tree::new_in_memory();
+        tree.insert(Context::background(), b"existing", b"old")
+            .unwrap();
+
+        let get = |key: &[u8]| {
+            StorageContext::with_current(|mkvs, _| mkvs.get(Context::background(), key))
+        };
+        let insert = |key: &[u8], value: &[u8]| {
+            StorageContext::with_current(|mkvs, _| {
+                mkvs.insert(Context::background(), key, value);
+            })
+        };
+        let remove = |key: &[u8]| {
+            StorageContext::with_current(|mkvs, _| {
+                mkvs.remove(Context::background(), key);
+            })
+        };
+
+        StorageContext::enter(&mut tree, Arc::new(NoopKeyValue), || {
+            // Failing closures should not modify the MKVS.
+            let result: std::result::Result<(), ()> = StorageContext::atomically(|| {
+                insert(b"existing", b"new");
+                insert(b"added", b"value");
+                remove(b"existing");
+                assert_eq!(get(b"added"), Some(b"value".to_vec()));
+                Err(())
+            });
+            assert!(result.is_err());
+            assert_eq!(get(b"existing"), Some(b"old".to_vec()));
+            assert_eq!(get(b"added"), None);
+
+            // Successful closures should keep their modifications.
+            let result: std::result::Result<(), ()> = StorageContext::atomically(|| {
+                insert(b"existing", b"new");
+                insert(b"added", b"value");
+                Ok(())
+            });
+            assert!(result.is_ok());
+            assert_eq!(get(b"existing"), Some(b"new".to_vec()));
+            assert_eq!(get(b"added"), Some(b"value".to_vec()));
+        });
+
+        let (write_log, _) = tree
+            .commit(Context::background(), Default::default(), 0)
+            .unwrap();
+        assert_eq!(write_log.len(), 2);
+    }
 }
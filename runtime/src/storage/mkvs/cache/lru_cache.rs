@@ -1,11 +1,24 @@
-use std::{any::Any, cell::RefCell, pin::Pin, ptr::NonNull, rc::Rc, sync::Arc};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    mem,
+    pin::Pin,
+    ptr::NonNull,
+    rc::Rc,
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Result};
 use intrusive_collections::{IntrusivePointer, LinkedList, LinkedListLink};
 use io_context::Context;
 use thiserror::Error;
 
-use crate::storage::mkvs::{cache::*, sync::*, tree::*};
+use crate::{
+    cancellation,
+    common::crypto::hash::Hash,
+    storage::mkvs::{cache::*, sync::*, tree::*},
+};
 
 #[derive(Error, Debug)]
 #[error("mkvs: tried to remove locked node")]
@@ -152,6 +165,56 @@ where
     }
 }
 
+/// Detached clean nodes of one kind which may be used instead of fetching
+/// them from the read syncer. Once the capacity is reached, the nodes added
+/// first are dropped.
+struct PrewarmList {
+    nodes: HashMap<Hash, (u64, NodeBox)>,
+    order: BTreeMap<u64, Hash>,
+    next_seq: u64,
+    capacity: usize,
+}
+
+impl PrewarmList {
+    fn new(capacity: usize) -> PrewarmList {
+        PrewarmList {
+            nodes: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            capacity: capacity,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn add(&mut self, hash: Hash, node: NodeBox) {
+        self.remove(&hash);
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.nodes.insert(hash, (seq, node));
+        self.order.insert(seq, hash);
+
+        if self.capacity > 0 {
+            while self.nodes.len() > self.capacity {
+                let oldest = match self.order.values().next() {
+                    Some(hash) => *hash,
+                    None => break,
+                };
+                self.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<NodeBox> {
+        let (seq, node) = self.nodes.remove(hash)?;
+        self.order.remove(&seq);
+        Some(node)
+    }
+}
+
 /// Cache implementation with a simple LRU eviction strategy.
 pub struct LRUCache {
     read_syncer: Box<dyn ReadSync>,
@@ -161,6 +224,14 @@ pub struct LRUCache {
 
     lru_leaf: LRUList<NodePointer>,
     lru_internal: LRUList<NodePointer>,
+
+    prewarm_leaf: PrewarmList,
+    prewarm_internal: PrewarmList,
+
+    hasher: Arc<dyn NodeHasher>,
+
+    hits: u64,
+    misses: u64,
 }
 
 impl LRUCache {
@@ -171,10 +242,12 @@ impl LRUCache {
     /// * `value_capacity` is the total size, in bytes, of values held
     ///   by the cache before eviction.
     /// * `read_syncer` is the read syncer used as backing for the cache.
+    /// * `hasher` is the hasher used to verify node hashes.
     pub fn new(
         node_capacity: usize,
         value_capacity: usize,
         read_syncer: Box<dyn ReadSync>,
+        hasher: Arc<dyn NodeHasher>,
     ) -> Box<LRUCache> {
         Box::new(LRUCache {
             read_syncer: read_syncer,
@@ -187,9 +260,238 @@ impl LRUCache {
 
             lru_leaf: LRUList::new(value_capacity),
             lru_internal: LRUList::new(node_capacity),
+
+            prewarm_leaf: PrewarmList::new(value_capacity),
+            prewarm_internal: PrewarmList::new(node_capacity),
+
+            hasher,
+
+            hits: 0,
+            misses: 0,
         })
     }
 
+    /// Return the hasher used for nodes in the cache.
+    pub fn hasher(&self) -> Arc<dyn NodeHasher> {
+        self.hasher.clone()
+    }
+
+    /// Return statistics about the cache.
+    pub fn stats(&self) -> CacheStats {
+        let value_bytes = self
+            .lru_leaf
+            .list
+            .iter()
+            .map(|item| match item.item.borrow().node {
+                Some(ref node_ref) => match *node_ref.borrow() {
+                    NodeBox::Leaf(ref n) => n.value.len(),
+                    NodeBox::Internal(_) => 0,
+                },
+                None => 0,
+            })
+            .sum();
+
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            node_count: self.lru_internal.list.iter().count() + self.lru_leaf.list.iter().count(),
+            value_bytes,
+        }
+    }
+
+    /// Drop all cached nodes and reset the pending root to the sync root.
+    ///
+    /// Clean nodes are moved to the prewarmed nodes so only nodes that have
+    /// been modified need to be fetched again. As with cached nodes, the
+    /// number of prewarmed nodes is bounded by the cache capacity.
+    pub fn reset(&mut self) {
+        let lru_internal = mem::replace(
+            &mut self.lru_internal,
+            LRUList::new(self.lru_internal.capacity),
+        );
+        let lru_leaf = mem::replace(&mut self.lru_leaf, LRUList::new(self.lru_leaf.capacity));
+        // Internal nodes go first as they keep their embedded leaf nodes.
+        for node in Self::take_clean_nodes(lru_internal) {
+            self.add_prewarm_node(node);
+        }
+        for node in Self::take_clean_nodes(lru_leaf) {
+            self.add_prewarm_node(node);
+        }
+
+        self.pending_root = if self.sync_root.hash == Hash::default() {
+            // No root has ever been set.
+            Rc::new(RefCell::new(NodePointer {
+                node: None,
+                ..Default::default()
+            }))
+        } else {
+            NodePointer::hash_ptr(self.sync_root.hash)
+        };
+    }
+
+    /// Add nodes which may be used instead of fetching them from the read
+    /// syncer. Nodes are verified against their hashes before use.
+    ///
+    /// Prewarmed nodes are bounded by the cache capacity, dropping the nodes
+    /// added first once it is reached.
+    pub fn add_prewarm_nodes(&mut self, nodes: Vec<(Hash, NodeBox)>) {
+        for node in nodes {
+            self.add_prewarm_node(node);
+        }
+    }
+
+    fn add_prewarm_node(&mut self, (hash, node): (Hash, NodeBox)) {
+        match node {
+            NodeBox::Internal(_) => self.prewarm_internal.add(hash, node),
+            NodeBox::Leaf(_) => self.prewarm_leaf.add(hash, node),
+        }
+    }
+
+    /// Return the number of prewarmed nodes that have not been used yet.
+    pub fn prewarm_node_count(&self) -> usize {
+        self.prewarm_internal.len() + self.prewarm_leaf.len()
+    }
+
+    /// Move all clean nodes out of a detached LRU list, least recently used
+    /// first. Unlike `clean_nodes`, leaf values are not copied.
+    fn take_clean_nodes(mut lru: LRUList<NodePointer>) -> Vec<(Hash, NodeBox)> {
+        let mut nodes = Vec::new();
+        while let Some(item_box) = lru.list.pop_back() {
+            let mut ptr = item_box.item.borrow_mut();
+            ptr.set_cache_extra(None);
+            if !ptr.clean {
+                continue;
+            }
+            let node_ref = match ptr.node.take() {
+                Some(node_ref) => node_ref,
+                None => continue,
+            };
+            let node = match Rc::try_unwrap(node_ref) {
+                Ok(node) => node.into_inner(),
+                // Still referenced elsewhere (e.g., embedded in an internal
+                // node taken before), fetch again if needed.
+                Err(_) => continue,
+            };
+            let node = match node {
+                NodeBox::Internal(mut n) => {
+                    if !n.clean || !n.left.borrow().clean || !n.right.borrow().clean {
+                        continue;
+                    }
+                    // Only the embedded leaf node is kept, children are fetched on demand.
+                    let leaf_node = {
+                        let leaf_ptr = n.leaf_node.borrow();
+                        if !leaf_ptr.clean || (!leaf_ptr.is_null() && leaf_ptr.node.is_none()) {
+                            continue;
+                        }
+                        Rc::new(RefCell::new(NodePointer {
+                            clean: true,
+                            hash: leaf_ptr.hash,
+                            node: leaf_ptr.node.clone(),
+                            ..Default::default()
+                        }))
+                    };
+                    let left = n.left.borrow().extract();
+                    let right = n.right.borrow().extract();
+                    n.leaf_node = leaf_node;
+                    n.left = left;
+                    n.right = right;
+                    NodeBox::Internal(n)
+                }
+                NodeBox::Leaf(n) => {
+                    if !n.clean {
+                        continue;
+                    }
+                    NodeBox::Leaf(n)
+                }
+            };
+            nodes.push((ptr.hash, node));
+        }
+        nodes
+    }
+
+    /// Return detached copies of all clean nodes held in the cache.
+    pub fn clean_nodes(&self) -> Vec<(Hash, NodeBox)> {
+        let mut nodes = Vec::new();
+        for list in &[&self.lru_internal, &self.lru_leaf] {
+            for item in list.list.iter() {
+                let ptr = item.item.borrow();
+                if !ptr.clean {
+                    continue;
+                }
+                if let Some(ref node_ref) = ptr.node {
+                    let node = node_ref.borrow();
+                    let clean = match *node {
+                        NodeBox::Internal(ref n) => {
+                            n.clean
+                                && n.leaf_node.borrow().clean
+                                && n.left.borrow().clean
+                                && n.right.borrow().clean
+                        }
+                        NodeBox::Leaf(ref n) => n.clean,
+                    };
+                    if !clean {
+                        continue;
+                    }
+
+                    if let Ok(node) = Rc::try_unwrap(node.extract()) {
+                        nodes.push((ptr.hash, node.into_inner()));
+                    }
+                }
+            }
+        }
+        nodes
+    }
+
+    /// Try to resolve the given pointer using a prewarmed node. Returns true
+    /// iff a node matching the pointer's hash was found, verified and added
+    /// to the cache.
+    fn use_prewarm_node(&mut self, ptr: NodePtrRef) -> bool {
+        let hash = ptr.borrow().hash;
+        let mut node = match self.prewarm_internal.remove(&hash) {
+            Some(node) => node,
+            None => match self.prewarm_leaf.remove(&hash) {
+                Some(node) => node,
+                None => return false,
+            },
+        };
+
+        // Make sure that the node actually matches the expected hash.
+        if let NodeBox::Internal(ref mut n) = node {
+            // Only the embedded leaf node is kept, children are fetched on demand.
+            let left_hash = n.left.borrow().hash;
+            let right_hash = n.right.borrow().hash;
+            n.left = NodePointer::hash_ptr(left_hash);
+            n.right = NodePointer::hash_ptr(right_hash);
+
+            let leaf_ptr = n.leaf_node.borrow();
+            if !leaf_ptr.is_null() {
+                let valid = match leaf_ptr.node {
+                    Some(ref leaf_ref) => {
+                        let mut leaf = leaf_ref.borrow_mut();
+                        leaf.update_hash_with(&*self.hasher);
+                        leaf.get_hash() == leaf_ptr.hash
+                    }
+                    None => false,
+                };
+                if !valid {
+                    return false;
+                }
+            }
+        }
+        node.update_hash_with(&*self.hasher);
+        if node.get_hash() != hash {
+            return false;
+        }
+
+        ptr.borrow_mut().node = Some(Rc::new(RefCell::new(node)));
+        if self.commit_merged_node(ptr.clone(), &ptr).is_err() {
+            // Cache is too small, fetch the node as usual.
+            ptr.borrow_mut().node = None;
+            return false;
+        }
+        true
+    }
+
     fn new_internal_node_ptr(&mut self, node: Option<NodeRef>) -> NodePtrRef {
         Rc::new(RefCell::new(NodePointer {
             node: node,
@@ -438,6 +740,7 @@ impl Cache for LRUCache {
                 drop(ptr);
                 self.remove_node(ptr_ref.clone());
             } else {
+                self.hits += 1;
                 return Ok(Some(node.clone()));
             }
         } else {
@@ -447,8 +750,19 @@ impl Cache for LRUCache {
             drop(ptr);
         }
 
-        // Node not available locally, fetch from read syncer.
+        // Node not available locally, first try the prewarmed nodes and then
+        // fetch from read syncer.
+        if self.prewarm_node_count() > 0 && self.use_prewarm_node(ptr_ref.clone()) {
+            self.hits += 1;
+            return Ok(ptr_ref.borrow().node.clone());
+        }
+        self.misses += 1;
         if let Some(fetcher) = fetcher {
+            // Don't start potentially slow fetches once the context has been
+            // cancelled or its deadline has been exceeded.
+            if cancellation::is_done(ctx) {
+                return Err(TreeError::ContextCancelled.into());
+            }
             self.remote_sync(ctx, ptr_ref.clone(), fetcher)?;
         } else {
             return Err(anyhow!(
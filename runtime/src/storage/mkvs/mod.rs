@@ -1,5 +1,8 @@
 //! Merklized key-value store.
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
 
 use anyhow::Result;
 use base64;
@@ -19,12 +22,15 @@ pub mod sync;
 #[cfg(test)]
 mod tests;
 
-pub use tree::{Depth, Key, NodeBox, Root, Tree};
+pub use tree::{
+    CacheStats, Depth, Key, NodeBox, Root, RootReceiver, RootWatchers, Tree, ROOT_WATCH_CAPACITY,
+};
 
 /// The type of entry in the log.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum LogEntryKind {
     Insert,
+    InsertMarker,
     Delete,
 }
 
@@ -37,6 +43,11 @@ pub struct LogEntry {
     /// The inserted value (empty if the key was deleted).
     #[serde(with = "serde_bytes")]
     pub value: Option<Vec<u8>>,
+    /// Whether the key was inserted as a presence marker without a value.
+    ///
+    /// Only encoded when set, so write logs without markers are unchanged.
+    #[serde(default)]
+    pub marker: bool,
 }
 
 impl LogEntry {
@@ -44,11 +55,21 @@ impl LogEntry {
         Self {
             key: key.to_owned(),
             value: Some(value.to_owned()),
+            marker: false,
+        }
+    }
+
+    pub fn new_marker(key: &[u8]) -> Self {
+        Self {
+            key: key.to_owned(),
+            value: Some(Vec::new()),
+            marker: true,
         }
     }
 
     pub fn kind(&self) -> LogEntryKind {
         match self.value {
+            Some(_) if self.marker => LogEntryKind::InsertMarker,
             Some(_) => LogEntryKind::Insert,
             None => LogEntryKind::Delete,
         }
@@ -61,7 +82,7 @@ impl serde::Serialize for LogEntry {
         S: Serializer,
     {
         let is_human_readable = serializer.is_human_readable();
-        let mut seq = serializer.serialize_seq(Some(2))?;
+        let mut seq = serializer.serialize_seq(Some(if self.marker { 3 } else { 2 }))?;
         if is_human_readable {
             seq.serialize_element(&base64::encode(&self.key))?;
             seq.serialize_element(&self.value.as_ref().map(|v| base64::encode(v)))?;
@@ -69,6 +90,9 @@ impl serde::Serialize for LogEntry {
             seq.serialize_element(&Bytes::new(&self.key))?;
             seq.serialize_element(&self.value.as_ref().map(|v| Bytes::new(v)))?;
         }
+        if self.marker {
+            seq.serialize_element(&true)?;
+        }
         seq.end()
     }
 }
@@ -78,6 +102,20 @@ impl serde::Serialize for LogEntry {
 /// The keys in the write log must be unique.
 pub type WriteLog = Vec<LogEntry>;
 
+/// Compose two write logs into a single write log, sorted by key, which is
+/// equivalent to applying `base` and then `overlay`.
+///
+/// Entries in `overlay` take precedence over entries for the same key in
+/// `base`, so a delete in `overlay` cancels an insert in `base`.
+pub fn compose_write_logs(base: WriteLog, overlay: WriteLog) -> WriteLog {
+    let mut entries: BTreeMap<Vec<u8>, LogEntry> = BTreeMap::new();
+    for entry in base.into_iter().chain(overlay) {
+        entries.insert(entry.key.clone(), entry);
+    }
+
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
 /// A key prefix.
 #[derive(Clone, Debug, Default, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Prefix(#[serde(with = "serde_bytes")] Vec<u8>);
@@ -166,11 +204,75 @@ mod _tests {
         let write_log = vec![LogEntry {
             key: b"foo".to_vec(),
             value: Some(b"bar".to_vec()),
+            marker: false,
         }];
 
         let raw = cbor::to_vec(&write_log);
         let deserialized: WriteLog = cbor::from_slice(&raw).unwrap();
 
         assert_eq!(write_log, deserialized);
+
+        // Markers are encoded as an additional element.
+        let marker_log = vec![LogEntry::new_marker(b"foo")];
+        let marker_raw = cbor::to_vec(&marker_log);
+        let deserialized: WriteLog = cbor::from_slice(&marker_raw).unwrap();
+        assert_eq!(marker_log, deserialized);
+    }
+
+    #[test]
+    fn test_compose_write_logs() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        fn random_write_log(rng: &mut StdRng) -> WriteLog {
+            (0..rng.gen_range(0, 20))
+                .map(|_| {
+                    let key = vec![rng.gen_range(0, 16)];
+                    if rng.gen_bool(0.3) {
+                        LogEntry {
+                            key,
+                            value: None,
+                            marker: false,
+                        }
+                    } else {
+                        LogEntry::new(&key, &[rng.gen::<u8>()])
+                    }
+                })
+                .collect()
+        }
+
+        fn apply(tree: &mut Tree, write_log: &WriteLog) {
+            for entry in write_log {
+                match entry.value {
+                    Some(ref value) => tree.insert(Context::background(), &entry.key, value),
+                    None => tree.remove(Context::background(), &entry.key),
+                }
+                .expect("apply");
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let initial = random_write_log(&mut rng);
+            // Write logs have unique keys.
+            let base = compose_write_logs(random_write_log(&mut rng), Vec::new());
+            let overlay = compose_write_logs(random_write_log(&mut rng), Vec::new());
+
+            let mut sequential = Tree::new_in_memory();
+            apply(&mut sequential, &initial);
+            apply(&mut sequential, &base);
+            apply(&mut sequential, &overlay);
+            let (_, sequential_root) = sequential
+                .commit(Context::background(), Default::default(), 0)
+                .expect("commit");
+
+            let mut composed = Tree::new_in_memory();
+            apply(&mut composed, &initial);
+            apply(&mut composed, &compose_write_logs(base, overlay));
+            let (_, composed_root) = composed
+                .commit(Context::background(), Default::default(), 0)
+                .expect("commit");
+
+            assert_eq!(sequential_root, composed_root);
+        }
     }
 }
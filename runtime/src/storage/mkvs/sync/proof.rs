@@ -1,4 +1,8 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    cmp,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
 
 use anyhow::{anyhow, Result};
 use arbitrary::Arbitrary;
@@ -11,6 +15,9 @@ use crate::{
     storage::mkvs::{marshal::Marshal, tree::*},
 };
 
+/// Version of the proof format produced by this implementation.
+pub const PROOF_VERSION: u16 = 0;
+
 /// Proof entry type for full nodes.
 const PROOF_ENTRY_FULL: u8 = 0x01;
 /// Proof entry type for subtree hashes.
@@ -53,8 +60,22 @@ impl From<Vec<u8>> for RawProofEntry {
 }
 
 /// A Merkle proof for a subtree.
+///
+/// The proof is versioned and each entry is self-describing. For version 0
+/// (the only version currently defined) entries are, in pre-order traversal
+/// of the included part of the tree, either:
+///
+/// * nil for an empty subtree,
+/// * `0x01` followed by the compact encoding of a full node (internal nodes
+///   are followed by the entries for their left and right child),
+/// * `0x02` followed by the hash of a subtree that is not included.
+///
+/// Verifiers reject proofs with a version they do not understand.
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Proof {
+    /// Proof format version. Omitted from the encoding for version 0.
+    #[serde(default, skip_serializing_if = "is_initial_version")]
+    pub v: u16,
     /// The root hash this proof is for. This should only be used as a quick
     /// sanity check and proof verification MUST use an independently obtained
     /// root hash as the prover can provide any root.
@@ -63,6 +84,163 @@ pub struct Proof {
     pub entries: Vec<Option<RawProofEntry>>,
 }
 
+fn is_initial_version(v: &u16) -> bool {
+    *v == 0
+}
+
+/// A Merkle proof for all key/value pairs in a key range.
+///
+/// The proof includes all nodes whose subtrees may contain keys in the range,
+/// including the nodes on the paths to the closest keys just outside of it.
+/// Only subtrees that cannot contain keys in the range are replaced by their
+/// hashes, so a verifier can check that no keys in the range were omitted.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RangeProof {
+    /// The underlying subtree proof.
+    pub proof: Proof,
+}
+
+/// Check whether a subtree containing keys starting with the given bit
+/// prefix may contain any keys in `[start, end)`.
+pub(crate) fn prefix_intersects_range(
+    prefix: &Key,
+    prefix_len: Depth,
+    start: &[u8],
+    end: &[u8],
+) -> bool {
+    // The smallest key with the prefix.
+    let len = prefix_len.to_bytes();
+    let mut lo: Key = vec![0; len];
+    let copy_len = cmp::min(len, prefix.len());
+    lo[..copy_len].clone_from_slice(&prefix[..copy_len]);
+    if prefix_len % 8 != 0 {
+        lo[len - 1] &= 0xff << (8 - prefix_len % 8);
+    }
+
+    // The (exclusive) upper bound of keys with the prefix, obtained by
+    // incrementing the prefix, or none if all bits of the prefix are set.
+    let mut hi = None;
+    if prefix_len > 0 {
+        let mut bound = lo.clone();
+        let mut carry = 0x80 >> ((prefix_len - 1) % 8);
+        for byte in bound.iter_mut().rev() {
+            let (value, overflow) = byte.overflowing_add(carry);
+            *byte = value;
+            if !overflow {
+                hi = Some(bound.clone());
+                break;
+            }
+            carry = 1;
+        }
+    }
+
+    let lower = cmp::max(lo.as_slice(), start);
+    lower < end && hi.map_or(true, |hi| lower < hi.as_slice())
+}
+
+struct ProofNode {
+    serialized: Vec<u8>,
+    children: Vec<Hash>,
+}
+
+/// A Merkle proof builder.
+pub struct ProofBuilder {
+    root: Hash,
+    included: HashMap<Hash, ProofNode>,
+}
+
+impl ProofBuilder {
+    /// Create a new Merkle proof builder for the given root.
+    pub fn new(root: Hash) -> Self {
+        Self {
+            root,
+            included: HashMap::new(),
+        }
+    }
+
+    /// Add a node to the set of included nodes.
+    ///
+    /// # Panics
+    ///
+    /// The node must be clean.
+    pub fn include(&mut self, node: &NodeBox) -> Result<()> {
+        if !node.is_clean() {
+            panic!("proof: attempted to add a dirty node");
+        }
+
+        // If node is already included, skip it.
+        let node_hash = node.get_hash();
+        if self.included.contains_key(&node_hash) {
+            return Ok(());
+        }
+
+        // For internal nodes, also add any children. The leaf node is always
+        // included with the internal node.
+        let children = match node {
+            NodeBox::Internal(ref n) => vec![n.left.borrow().hash, n.right.borrow().hash],
+            NodeBox::Leaf(_) => vec![],
+        };
+
+        self.included.insert(
+            node_hash,
+            ProofNode {
+                serialized: node.compact_marshal_binary()?,
+                children,
+            },
+        );
+        Ok(())
+    }
+
+    /// Return true iff the root node has already been included.
+    pub fn has_root(&self) -> bool {
+        self.included.contains_key(&self.root)
+    }
+
+    /// Return the root hash for this proof.
+    pub fn get_root(&self) -> Hash {
+        self.root
+    }
+
+    /// Build the proof.
+    pub fn build(&self) -> Proof {
+        let mut proof = Proof {
+            v: PROOF_VERSION,
+            untrusted_root: self.root,
+            entries: Vec::new(),
+        };
+        self._build(&mut proof, &self.root);
+        proof
+    }
+
+    fn _build(&self, proof: &mut Proof, hash: &Hash) {
+        if hash.is_empty() {
+            // Append nil for empty nodes.
+            proof.entries.push(None);
+            return;
+        }
+
+        match self.included.get(hash) {
+            None => {
+                // Node is not included in this proof, just add hash of subtree.
+                let mut entry = vec![PROOF_ENTRY_HASH];
+                entry.extend_from_slice(hash.as_ref());
+                proof.entries.push(Some(entry.into()));
+            }
+            Some(node) => {
+                // Pre-order traversal, add visited node.
+                let mut entry = vec![PROOF_ENTRY_FULL];
+                entry.extend_from_slice(&node.serialized);
+                proof.entries.push(Some(entry.into()));
+
+                // And then add any children.
+                for child in &node.children {
+                    self._build(proof, child);
+                }
+            }
+        }
+    }
+}
+
 /// A proof verifier enables verifying proofs returned by the ReadSyncer API.
 pub struct ProofVerifier;
 
@@ -70,6 +248,13 @@ impl ProofVerifier {
     /// Verify a proof and generate an in-memory subtree representing the
     /// nodes which are included in the proof.
     pub fn verify_proof(&self, _ctx: Context, root: Hash, proof: &Proof) -> Result<NodePtrRef> {
+        if proof.v != PROOF_VERSION {
+            return Err(anyhow!(
+                "verifier: unsupported proof version (expected: {} got {})",
+                PROOF_VERSION,
+                proof.v,
+            ));
+        }
         // Sanity check that the proof is for the correct root (as otherwise it
         // makes no sense to verify the proof).
         if proof.untrusted_root != root {
@@ -96,6 +281,87 @@ impl ProofVerifier {
         Ok(root_node)
     }
 
+    /// Verify a range proof and check that the given key/value pairs are
+    /// exactly the pairs in `[start, end)` of the tree with the given root.
+    pub fn verify_range_proof(
+        &self,
+        ctx: Context,
+        root: Hash,
+        start: &[u8],
+        end: &[u8],
+        pairs: &[(Key, Vec<u8>)],
+        proof: &RangeProof,
+    ) -> Result<()> {
+        let root_ptr = self.verify_proof(ctx, root, &proof.proof)?;
+
+        let mut found = Vec::new();
+        Self::_collect_range(root_ptr, 0, Key::new(), 0, start, end, &mut found)?;
+        if found.as_slice() != pairs {
+            return Err(anyhow!(
+                "verifier: key/value pairs do not match the range proof"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Collect all key/value pairs in `[start, end)` from a verified subtree,
+    /// failing if any part of the subtree that may contain such pairs is not
+    /// included.
+    fn _collect_range(
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        path_len: Depth,
+        start: &[u8],
+        end: &[u8],
+        found: &mut Vec<(Key, Vec<u8>)>,
+    ) -> Result<()> {
+        if !prefix_intersects_range(&path, path_len, start, end) {
+            return Ok(());
+        }
+        let node_ref = match ptr.borrow().node {
+            Some(ref node_ref) => node_ref.clone(),
+            None if ptr.borrow().is_null() => return Ok(()),
+            None => return Err(anyhow!("verifier: range proof is incomplete")),
+        };
+
+        let node = node_ref.borrow();
+        match *node {
+            NodeBox::Internal(ref n) => {
+                let bit_length = bit_depth + n.label_bit_length;
+                let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+                Self::_collect_range(
+                    n.leaf_node.clone(),
+                    bit_length,
+                    new_path.clone(),
+                    bit_length,
+                    start,
+                    end,
+                    found,
+                )?;
+                for bit in &[false, true] {
+                    let child = if *bit { &n.right } else { &n.left };
+                    Self::_collect_range(
+                        child.clone(),
+                        bit_length,
+                        new_path.append_bit(bit_length, *bit),
+                        bit_length + 1,
+                        start,
+                        end,
+                        found,
+                    )?;
+                }
+            }
+            NodeBox::Leaf(ref n) => {
+                if n.key.as_slice() >= start && n.key.as_slice() < end {
+                    found.push((n.key.clone(), n.value.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn _verify_proof(&self, proof: &Proof, idx: usize) -> Result<(usize, NodePtrRef)> {
         if idx >= proof.entries.len() {
             return Err(anyhow!("verifier: malformed proof"));
@@ -242,4 +508,44 @@ mod test {
             "verify proof should fail with invalid proof"
         );
     }
+
+    #[test]
+    fn test_proof_version() {
+        let mut tree = Tree::new_in_memory();
+        tree.insert(Context::background(), b"key", b"value")
+            .unwrap();
+        let (_, root_hash) = tree
+            .commit(Context::background(), Default::default(), 0)
+            .unwrap();
+        let root = tree.cache.borrow().get_pending_root();
+        let mut pb = ProofBuilder::new(root_hash);
+        pb.include(&root.borrow().node.as_ref().unwrap().borrow())
+            .unwrap();
+        let proof = pb.build();
+        assert_eq!(proof.v, PROOF_VERSION);
+
+        // Version 0 proofs should encode the same as before versioning.
+        let encoded = cbor::to_vec(&proof);
+        let decoded: Proof = cbor::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, proof);
+        let value: cbor::Value = cbor::from_slice(&encoded).unwrap();
+        match value {
+            cbor::Value::Map(fields) => assert_eq!(fields.len(), 2),
+            _ => panic!("proof should encode as a map"),
+        }
+
+        let pv = ProofVerifier;
+        pv.verify_proof(Context::background(), root_hash, &decoded)
+            .expect("verify proof should not fail with a valid proof");
+
+        // Proofs with a future version should be rejected.
+        let mut future = proof.clone();
+        future.v = PROOF_VERSION + 1;
+        let decoded: Proof = cbor::from_slice(&cbor::to_vec(&future)).unwrap();
+        assert_eq!(decoded.v, PROOF_VERSION + 1);
+        let error = pv
+            .verify_proof(Context::background(), root_hash, &decoded)
+            .expect_err("verify proof should fail with a future version");
+        assert!(format!("{}", error).contains("unsupported proof version"));
+    }
 }
@@ -2,7 +2,6 @@ use io_context::Context;
 
 use crate::storage::mkvs::{
     interop::{Driver, ProtocolServer},
-    sync::*,
     tree::*,
     LogEntry,
 };
@@ -11,7 +10,7 @@ use crate::storage::mkvs::{
 fn test_nil_pointers() {
     let server = ProtocolServer::new();
 
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     // Arbitrary sequence of operations. The point is to produce a tree with
     // an internal node where at least one of the children is a null pointer.
@@ -5,7 +5,7 @@ use io_context::Context;
 
 use crate::{
     common::{crypto::hash::Hash, roothash::Namespace},
-    storage::mkvs::{cache::*, tree::*, LogEntry, WriteLog},
+    storage::mkvs::{cache::*, sync::*, tree::*, LogEntry, WriteLog},
 };
 
 impl Tree {
@@ -17,12 +17,22 @@ impl Tree {
         namespace: Namespace,
         version: u64,
     ) -> Result<(WriteLog, Hash)> {
+        self.check_commit_version(version)?;
+
         let ctx = ctx.freeze();
         let mut update_list: UpdateList<LRUCache> = UpdateList::new();
         let pending_root = self.cache.borrow().get_pending_root();
-        let new_hash = _commit(&ctx, pending_root.clone(), &mut update_list, Some(version))?;
+        let hasher = self.cache.borrow().hasher();
+        let new_hash = _commit(
+            &ctx,
+            pending_root.clone(),
+            &mut update_list,
+            Some(version),
+            &*hasher,
+        )?;
 
         update_list.commit(&mut self.cache.borrow_mut());
+        self.preview_version.set(None);
 
         let mut log: WriteLog = Vec::new();
         for (_, entry) in self.pending_write_log.iter() {
@@ -34,17 +44,121 @@ impl Tree {
             log.push(LogEntry {
                 key: entry.key.clone(),
                 value: entry.value.clone(),
+                marker: entry.marker,
             });
         }
         self.pending_write_log.clear();
-        self.cache.borrow_mut().set_sync_root(Root {
+        let root = Root {
             namespace,
             version,
             hash: new_hash,
-        });
+        };
+        self.cache.borrow_mut().set_sync_root(root);
+
+        // Notify watchers, dropping any that have unsubscribed.
+        self.root_watchers.notify(root);
 
         Ok((log, new_hash))
     }
+
+    /// Compute the merkle root that committing the tree updates at the given
+    /// version would produce, without actually committing them.
+    ///
+    /// The updates remain pending, so the tree can continue to accept writes
+    /// and a subsequent `commit` at the same version yields the same root
+    /// (unless there were further writes). The namespace is taken for parity
+    /// with `commit`, the root hash does not depend on it.
+    pub fn commit_dry_run(
+        &self,
+        ctx: Context,
+        _namespace: Namespace,
+        version: u64,
+    ) -> Result<Hash> {
+        self.check_commit_version(version)?;
+
+        let ctx = ctx.freeze();
+        // Node hashes are computed in place, but as the update list is never
+        // committed, the nodes remain dirty and will be rehashed on commit.
+        let mut update_list: UpdateList<LRUCache> = UpdateList::new();
+        let pending_root = self.cache.borrow().get_pending_root();
+        let hasher = self.cache.borrow().hasher();
+        let hash = _commit(
+            &ctx,
+            pending_root,
+            &mut update_list,
+            Some(version),
+            &*hasher,
+        )?;
+        self.preview_version.set(Some(version));
+        Ok(hash)
+    }
+
+    /// Compute the merkle root that committing the tree updates at the given
+    /// version would produce, like `commit_dry_run`, but reusing the hashes
+    /// computed by the previous preview (or dry run) where possible.
+    ///
+    /// Writes discard the computed hashes of the nodes on the modified paths,
+    /// so repeated previews at the same version only rehash the paths that
+    /// were modified in between. Previewing at a different version rehashes
+    /// all modified nodes, as node hashes depend on the version. This makes
+    /// it cheap to follow the root as writes accumulate, e.g., in tools.
+    pub fn pending_root_preview(&self, ctx: Context, version: u64) -> Result<Hash> {
+        if self.preview_version.get() != Some(version) {
+            // The root hash does not depend on the namespace.
+            return self.commit_dry_run(ctx, Namespace::default(), version);
+        }
+        self.check_commit_version(version)?;
+
+        let mut update_list: UpdateList<LRUCache> = UpdateList::new();
+        let pending_root = self.cache.borrow().get_pending_root();
+        let hasher = self.cache.borrow().hasher();
+        Ok(_preview_subtree(
+            pending_root,
+            &mut update_list,
+            version,
+            &*hasher,
+        ))
+    }
+
+    /// Check whether applying the write log on top of the given base root
+    /// yields the expected root, without committing anything.
+    ///
+    /// As node hashes depend on the version, the write log is applied as if
+    /// committed at the version of the expected root. The write log is
+    /// applied to a throwaway tree backed by the given read syncer, which is
+    /// dropped afterwards. Use this to validate a write log (e.g., received
+    /// during state sync) before accepting it.
+    pub fn verify_write_log(
+        ctx: Context,
+        base_root: Root,
+        write_log: &WriteLog,
+        expected_root: Root,
+        read_syncer: Box<dyn ReadSync>,
+    ) -> Result<bool> {
+        let ctx = ctx.freeze();
+        let mut tree = Tree::make().with_root(base_root).new(read_syncer);
+        tree.apply_pending_entries(Context::create_child(&ctx), write_log.clone())?;
+        let hash = tree.commit_dry_run(
+            Context::create_child(&ctx),
+            expected_root.namespace,
+            expected_root.version,
+        )?;
+        Ok(hash == expected_root.hash)
+    }
+
+    fn check_commit_version(&self, version: u64) -> Result<()> {
+        if self.monotonic_versions {
+            let sync_root = self.cache.borrow().get_sync_root();
+            if sync_root.hash != Hash::default() && version <= sync_root.version {
+                return Err(TreeError::NonMonotonicVersion {
+                    current: sync_root.version,
+                    requested: version,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn _commit<C: Cache>(
@@ -52,11 +166,130 @@ pub fn _commit<C: Cache>(
     ptr: NodePtrRef,
     update_list: &mut UpdateList<C>,
     version: Option<u64>,
+    hasher: &dyn NodeHasher,
+) -> Result<Hash> {
+    // Fast path: when only a single path from the root is dirty (e.g., after
+    // updating a single key), rehash it bottom-up without recursing.
+    if let Some(path) = single_dirty_path(&ptr) {
+        for ptr in path.into_iter().rev() {
+            commit_node(ptr, update_list, version, hasher);
+        }
+        return Ok(ptr.borrow().hash);
+    }
+
+    _commit_subtree(ctx, ptr, update_list, version, hasher)
+}
+
+/// Return the dirty pointers from the given pointer down to the last dirty
+/// node, if at each internal node at most one child is dirty.
+pub(super) fn single_dirty_path(ptr: &NodePtrRef) -> Option<Vec<NodePtrRef>> {
+    if ptr.borrow().clean {
+        return None;
+    }
+
+    let mut path = vec![ptr.clone()];
+    loop {
+        let next = {
+            let current = path.last().expect("path is not empty").borrow();
+            let node_ref = match current.node {
+                Some(ref node_ref) => node_ref.clone(),
+                None => break,
+            };
+            let node = node_ref.borrow();
+            match *node {
+                NodeBox::Internal(ref n) if !n.clean => {
+                    let children = [&n.leaf_node, &n.left, &n.right];
+                    let mut dirty = children
+                        .iter()
+                        .filter(|child| !child.borrow().clean)
+                        .map(|child| (*child).clone());
+                    let next = dirty.next();
+                    if dirty.next().is_some() {
+                        return None;
+                    }
+                    next
+                }
+                _ => None,
+            }
+        };
+        match next {
+            Some(next) => path.push(next),
+            None => break,
+        }
+    }
+    Some(path)
+}
+
+/// Commit the subtree behind the given pointer, recursing into all dirty
+/// subtrees.
+pub(super) fn _commit_subtree<C: Cache>(
+    ctx: &Arc<Context>,
+    ptr: NodePtrRef,
+    update_list: &mut UpdateList<C>,
+    version: Option<u64>,
+    hasher: &dyn NodeHasher,
 ) -> Result<Hash> {
     if ptr.borrow().clean {
         return Ok(ptr.borrow().hash);
     }
 
+    if let NodeKind::Internal = classify_noderef!(? ptr.borrow().node) {
+        let some_node_ref = ptr.borrow().get_node();
+        if !some_node_ref.borrow().is_clean() {
+            let int_leaf_node = noderef_as!(some_node_ref, Internal).leaf_node.clone();
+            let int_left = noderef_as!(some_node_ref, Internal).left.clone();
+            let int_right = noderef_as!(some_node_ref, Internal).right.clone();
+
+            _commit_subtree(ctx, int_leaf_node, update_list, version, hasher)?;
+            _commit_subtree(ctx, int_left, update_list, version, hasher)?;
+            _commit_subtree(ctx, int_right, update_list, version, hasher)?;
+        }
+    }
+
+    commit_node(ptr.clone(), update_list, version, hasher);
+    Ok(ptr.borrow().hash)
+}
+
+/// Compute the hash of the subtree behind the given pointer, only recursing
+/// into dirty subtrees whose hash has been discarded by a write.
+fn _preview_subtree<C: Cache>(
+    ptr: NodePtrRef,
+    update_list: &mut UpdateList<C>,
+    version: u64,
+    hasher: &dyn NodeHasher,
+) -> Hash {
+    {
+        let ptr = ptr.borrow();
+        if ptr.clean || ptr.hash != Hash::default() {
+            return ptr.hash;
+        }
+    }
+
+    if let NodeKind::Internal = classify_noderef!(? ptr.borrow().node) {
+        let some_node_ref = ptr.borrow().get_node();
+        if !some_node_ref.borrow().is_clean() {
+            let int_leaf_node = noderef_as!(some_node_ref, Internal).leaf_node.clone();
+            let int_left = noderef_as!(some_node_ref, Internal).left.clone();
+            let int_right = noderef_as!(some_node_ref, Internal).right.clone();
+
+            _preview_subtree(int_leaf_node, update_list, version, hasher);
+            _preview_subtree(int_left, update_list, version, hasher);
+            _preview_subtree(int_right, update_list, version, hasher);
+        }
+    }
+
+    commit_node(ptr.clone(), update_list, Some(version), hasher);
+    ptr.borrow().hash
+}
+
+/// Compute the hash of the node behind a dirty pointer, assuming that all
+/// of its children have already been committed.
+fn commit_node<C: Cache>(
+    ptr: NodePtrRef,
+    update_list: &mut UpdateList<C>,
+    version: Option<u64>,
+    hasher: &dyn NodeHasher,
+) {
     match classify_noderef!(? ptr.borrow().node) {
         NodeKind::None => {
             ptr.borrow_mut().hash = Hash::empty_hash();
@@ -66,18 +299,10 @@ pub fn _commit<C: Cache>(
             if some_node_ref.borrow().is_clean() {
                 ptr.borrow_mut().hash = some_node_ref.borrow().get_hash();
             } else {
-                let int_leaf_node = noderef_as!(some_node_ref, Internal).leaf_node.clone();
-                let int_left = noderef_as!(some_node_ref, Internal).left.clone();
-                let int_right = noderef_as!(some_node_ref, Internal).right.clone();
-
-                _commit(ctx, int_leaf_node.clone(), update_list, version)?;
-                _commit(ctx, int_left.clone(), update_list, version)?;
-                _commit(ctx, int_right.clone(), update_list, version)?;
-
                 if let Some(version) = version {
                     noderef_as_mut!(some_node_ref, Internal).version = version;
                 }
-                some_node_ref.borrow_mut().update_hash();
+                some_node_ref.borrow_mut().update_hash_with(hasher);
                 ptr.borrow_mut().hash = some_node_ref.borrow().get_hash();
 
                 let closure_node_ref = some_node_ref.clone();
@@ -94,7 +319,7 @@ pub fn _commit<C: Cache>(
                 if let Some(version) = version {
                     noderef_as_mut!(node_ref, Leaf).version = version;
                 }
-                node_ref.borrow_mut().update_hash();
+                node_ref.borrow_mut().update_hash_with(hasher);
                 ptr.borrow_mut().hash = node_ref.borrow().get_hash();
 
                 let closure_node_ref = node_ref.clone();
@@ -111,6 +336,4 @@ pub fn _commit<C: Cache>(
         // Make node eligible for eviction.
         cache.commit_node(closure_ptr.clone());
     }));
-
-    Ok(ptr.borrow().hash)
 }
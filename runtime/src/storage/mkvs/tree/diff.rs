@@ -0,0 +1,295 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use anyhow::Result;
+use io_context::Context;
+
+use crate::storage::mkvs::{cache::*, tree::*, LogEntry, WriteLog};
+
+use super::iterator::FetcherSyncIterate;
+
+enum DiffItem {
+    Node {
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+    },
+    Internal {
+        ptr: NodePtrRef,
+        node_ref: NodeRef,
+        bit_depth: Depth,
+        path: Key,
+    },
+    Leaf {
+        key: Key,
+        value: Value,
+        marker: bool,
+    },
+}
+
+/// One side of a diff, walking a tree in key order.
+struct DiffSide<'tree> {
+    tree: &'tree Tree,
+    stack: Vec<DiffItem>,
+}
+
+impl<'tree> DiffSide<'tree> {
+    fn new(tree: &'tree Tree) -> Self {
+        let pending_root = tree.cache.borrow().get_pending_root();
+        Self {
+            tree,
+            stack: vec![DiffItem::Node {
+                ptr: pending_root,
+                bit_depth: 0,
+                path: Key::new(),
+            }],
+        }
+    }
+
+    /// Resolve the top of the stack into either an internal node or a leaf.
+    fn resolve(&mut self, ctx: &Arc<Context>) -> Result<()> {
+        loop {
+            let (ptr, bit_depth, path) = match self.stack.pop() {
+                Some(DiffItem::Node {
+                    ptr,
+                    bit_depth,
+                    path,
+                }) => (ptr, bit_depth, path),
+                Some(item) => {
+                    self.stack.push(item);
+                    return Ok(());
+                }
+                None => return Ok(()),
+            };
+
+            let node_ref = self.tree.cache.borrow_mut().deref_node_ptr(
+                ctx,
+                ptr.clone(),
+                Some(FetcherSyncIterate::new(&path, 0)),
+            )?;
+
+            match classify_noderef!(?node_ref) {
+                NodeKind::None => continue,
+                NodeKind::Internal => {
+                    self.stack.push(DiffItem::Internal {
+                        ptr,
+                        node_ref: node_ref.unwrap(),
+                        bit_depth,
+                        path,
+                    });
+                    return Ok(());
+                }
+                NodeKind::Leaf => {
+                    let node_ref = node_ref.unwrap();
+                    if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                        self.stack.push(DiffItem::Leaf {
+                            key: n.key.clone(),
+                            value: n.value.clone(),
+                            marker: n.marker,
+                        });
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Replace the internal node on top of the stack with its children.
+    fn expand(&mut self) {
+        let (node_ref, bit_depth, path) = match self.stack.pop() {
+            Some(DiffItem::Internal {
+                node_ref,
+                bit_depth,
+                path,
+                ..
+            }) => (node_ref, bit_depth, path),
+            _ => unreachable!("top of the stack is an internal node"),
+        };
+
+        let node = node_ref.borrow();
+        if let NodeBox::Internal(ref n) = *node {
+            let bit_length = bit_depth + n.label_bit_length;
+            let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+            // Push in reverse key order so that the leaf node is visited first.
+            self.stack.push(DiffItem::Node {
+                ptr: n.right.clone(),
+                bit_depth: bit_length,
+                path: new_path.append_bit(bit_length, true),
+            });
+            self.stack.push(DiffItem::Node {
+                ptr: n.left.clone(),
+                bit_depth: bit_length,
+                path: new_path.append_bit(bit_length, false),
+            });
+            self.stack.push(DiffItem::Node {
+                ptr: n.leaf_node.clone(),
+                bit_depth: bit_length,
+                path,
+            });
+        }
+    }
+}
+
+/// Iterator over the differences between two trees.
+///
+/// See `Tree::diff_iter` for details.
+pub struct DiffIterator<'tree> {
+    ctx: Arc<Context>,
+    old: DiffSide<'tree>,
+    new: DiffSide<'tree>,
+    failed: bool,
+}
+
+impl<'tree> DiffIterator<'tree> {
+    fn next_entry(&mut self) -> Result<Option<LogEntry>> {
+        loop {
+            self.old.resolve(&self.ctx)?;
+            self.new.resolve(&self.ctx)?;
+
+            match (self.old.stack.last(), self.new.stack.last()) {
+                (None, None) => return Ok(None),
+                (
+                    Some(DiffItem::Internal {
+                        ptr: old_ptr,
+                        bit_depth: old_depth,
+                        path: old_path,
+                        ..
+                    }),
+                    Some(DiffItem::Internal {
+                        ptr: new_ptr,
+                        bit_depth: new_depth,
+                        path: new_path,
+                        ..
+                    }),
+                ) => {
+                    // Identical subtrees at the same position contain the same
+                    // keys, so they can be skipped entirely.
+                    let (old_ptr, new_ptr) = (old_ptr.borrow(), new_ptr.borrow());
+                    let same = old_ptr.clean
+                        && new_ptr.clean
+                        && old_ptr.hash == new_ptr.hash
+                        && old_depth == new_depth
+                        && old_path == new_path;
+                    drop(old_ptr);
+                    drop(new_ptr);
+
+                    if same {
+                        self.old.stack.pop();
+                        self.new.stack.pop();
+                    } else {
+                        self.old.expand();
+                        self.new.expand();
+                    }
+                }
+                (Some(DiffItem::Internal { .. }), _) => self.old.expand(),
+                (_, Some(DiffItem::Internal { .. })) => self.new.expand(),
+                (old, new) => {
+                    let order = match (old, new) {
+                        (
+                            Some(DiffItem::Leaf { key: old_key, .. }),
+                            Some(DiffItem::Leaf { key: new_key, .. }),
+                        ) => old_key.cmp(new_key),
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        _ => unreachable!("both sides are resolved"),
+                    };
+
+                    match order {
+                        Ordering::Less => {
+                            // Key has been removed.
+                            if let Some(DiffItem::Leaf { key, .. }) = self.old.stack.pop() {
+                                return Ok(Some(LogEntry {
+                                    key,
+                                    value: None,
+                                    marker: false,
+                                }));
+                            }
+                        }
+                        Ordering::Greater => {
+                            // Key has been inserted.
+                            if let Some(DiffItem::Leaf { key, value, marker }) =
+                                self.new.stack.pop()
+                            {
+                                return Ok(Some(LogEntry {
+                                    key,
+                                    value: Some(value),
+                                    marker,
+                                }));
+                            }
+                        }
+                        Ordering::Equal => {
+                            let old = self.old.stack.pop();
+                            let new = self.new.stack.pop();
+                            if let (
+                                Some(DiffItem::Leaf {
+                                    value: old_value,
+                                    marker: old_marker,
+                                    ..
+                                }),
+                                Some(DiffItem::Leaf { key, value, marker }),
+                            ) = (old, new)
+                            {
+                                if old_value != value || old_marker != marker {
+                                    // Key has been updated.
+                                    return Ok(Some(LogEntry {
+                                        key,
+                                        value: Some(value),
+                                        marker,
+                                    }));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'tree> Iterator for DiffIterator<'tree> {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(error) => {
+                self.failed = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Tree {
+    /// Return the write log that transforms the `old` tree into the `new`
+    /// tree, in key order.
+    ///
+    /// See `Tree::diff_iter` for details.
+    pub fn diff(ctx: Context, old: &Tree, new: &Tree) -> Result<WriteLog> {
+        Tree::diff_iter(ctx, old, new).collect()
+    }
+
+    /// Return an iterator over the write log that transforms the `old` tree
+    /// into the `new` tree, yielding entries lazily in key order.
+    ///
+    /// Subtrees at the same position whose hashes match in both trees are
+    /// skipped without being traversed. Only committed subtrees have known
+    /// hashes, so uncommitted modifications are always traversed. Missing
+    /// nodes are fetched via the read syncer of the corresponding tree.
+    pub fn diff_iter<'tree>(
+        ctx: Context,
+        old: &'tree Tree,
+        new: &'tree Tree,
+    ) -> DiffIterator<'tree> {
+        DiffIterator {
+            ctx: ctx.freeze(),
+            old: DiffSide::new(old),
+            new: DiffSide::new(new),
+            failed: false,
+        }
+    }
+}
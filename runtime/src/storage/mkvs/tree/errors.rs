@@ -6,4 +6,10 @@ pub enum TreeError {
     MalformedNode,
     #[error("mkvs: malformed key")]
     MalformedKey,
+    #[error("mkvs: value too large (size: {size} max: {max})")]
+    ValueTooLarge { size: usize, max: usize },
+    #[error("mkvs: non-monotonic version (current: {current} requested: {requested})")]
+    NonMonotonicVersion { current: u64, requested: u64 },
+    #[error("mkvs: context cancelled")]
+    ContextCancelled,
 }
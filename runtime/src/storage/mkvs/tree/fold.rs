@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use io_context::Context;
+
+use crate::storage::mkvs::{cache::*, tree::*};
+
+use super::iterator::FetcherSyncIterate;
+
+/// Whether a fold should continue with the next key or stop early.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlFlow<B> {
+    /// Continue with the next key.
+    Continue(B),
+    /// Stop folding and return the given value.
+    Break(B),
+}
+
+impl Tree {
+    /// Fold over all key/value pairs in the tree in key order.
+    ///
+    /// Missing nodes are fetched via the read syncer.
+    pub fn fold<B, F>(&self, ctx: Context, init: B, mut f: F) -> Result<B>
+    where
+        F: FnMut(B, &[u8], &[u8]) -> B,
+    {
+        self.try_fold(ctx, init, |acc, key, value| {
+            ControlFlow::Continue(f(acc, key, value))
+        })
+    }
+
+    /// Fold over all key/value pairs in the tree in key order, stopping
+    /// as soon as `f` returns `ControlFlow::Break`.
+    ///
+    /// Missing nodes are fetched via the read syncer.
+    pub fn try_fold<B, F>(&self, ctx: Context, init: B, mut f: F) -> Result<B>
+    where
+        F: FnMut(B, &[u8], &[u8]) -> ControlFlow<B>,
+    {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        match self._try_fold(&ctx, pending_root, 0, Key::new(), init, &mut f)? {
+            ControlFlow::Continue(acc) | ControlFlow::Break(acc) => Ok(acc),
+        }
+    }
+
+    fn _try_fold<B, F>(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        acc: B,
+        f: &mut F,
+    ) -> Result<ControlFlow<B>>
+    where
+        F: FnMut(B, &[u8], &[u8]) -> ControlFlow<B>,
+    {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(ControlFlow::Continue(acc)),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (leaf_node, left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.leaf_node.clone(),
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                // The leaf node's key is a prefix of all keys in the left and
+                // right subtrees, so it comes first in key order.
+                let acc = match self._try_fold(ctx, leaf_node, bit_length, path, acc, f)? {
+                    ControlFlow::Continue(acc) => acc,
+                    done => return Ok(done),
+                };
+                let acc = match self._try_fold(
+                    ctx,
+                    left,
+                    bit_length,
+                    new_path.append_bit(bit_length, false),
+                    acc,
+                    f,
+                )? {
+                    ControlFlow::Continue(acc) => acc,
+                    done => return Ok(done),
+                };
+                self._try_fold(
+                    ctx,
+                    right,
+                    bit_length,
+                    new_path.append_bit(bit_length, true),
+                    acc,
+                    f,
+                )
+            }
+            NodeKind::Leaf => {
+                let node_ref = node_ref.unwrap();
+                if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                    Ok(f(acc, &n.key, &n.value))
+                } else {
+                    unreachable!("node kind is leaf node");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use io_context::Context;
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{cache::*, marshal::*, sync::*, tree::*},
+};
+
+use super::iterator::FetcherSyncIterate;
+
+/// An immutable snapshot of a committed tree.
+///
+/// Unlike `Tree`, a frozen tree can be shared between threads and is cheap
+/// to clone. It holds all nodes of the tree in serialized form, so it never
+/// needs to fetch anything via a read syncer.
+#[derive(Clone)]
+pub struct FrozenTree {
+    inner: Arc<FrozenTreeInner>,
+}
+
+struct FrozenTreeInner {
+    root: Root,
+    nodes: HashMap<Hash, Vec<u8>>,
+}
+
+impl FrozenTree {
+    /// Return the root of the frozen tree.
+    pub fn root(&self) -> Root {
+        self.inner.root
+    }
+
+    /// Get an existing key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.lookup(&key, None)
+    }
+
+    /// Get a proof of the value of an existing key (or of its absence if the
+    /// key does not exist).
+    pub fn get_proof(&self, key: &[u8]) -> Result<Proof> {
+        let key = key.to_vec();
+        let mut builder = ProofBuilder::new(self.inner.root.hash);
+        self.lookup(&key, Some(&mut builder))?;
+        Ok(builder.build())
+    }
+
+    /// Return an iterator over all key/value pairs in key order.
+    pub fn iter(&self) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>> {
+        let mut items = Vec::new();
+        self.collect(self.inner.root.hash, &mut items)?;
+        Ok(items.into_iter())
+    }
+
+    fn load(&self, hash: &Hash) -> Result<Option<NodeBox>> {
+        if hash.is_empty() {
+            return Ok(None);
+        }
+
+        let data = self
+            .inner
+            .nodes
+            .get(hash)
+            .ok_or_else(|| anyhow!("mkvs: frozen tree is missing node {:?}", hash))?;
+        let mut node = NodeBox::default();
+        node.unmarshal_binary(data)?;
+        // Unmarshalling always uses the default hasher, but the tree may have
+        // been hashed differently.
+        match node {
+            NodeBox::Internal(ref mut n) => n.hash = *hash,
+            NodeBox::Leaf(ref mut n) => n.hash = *hash,
+        }
+        Ok(Some(node))
+    }
+
+    fn lookup(&self, key: &Key, mut proof: Option<&mut ProofBuilder>) -> Result<Option<Value>> {
+        let mut hash = self.inner.root.hash;
+        let mut bit_depth: Depth = 0;
+        loop {
+            let node = match self.load(&hash)? {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            if let Some(proof) = proof.as_mut() {
+                proof.include(&node)?;
+            }
+
+            match node {
+                NodeBox::Internal(n) => {
+                    let bit_length = bit_depth + n.label_bit_length;
+
+                    // Does lookup key end here? Look into LeafNode.
+                    if key.bit_length() == bit_length {
+                        let leaf_node = n.leaf_node.borrow();
+                        if leaf_node.is_null() {
+                            return Ok(None);
+                        }
+                        let node_ref = leaf_node.get_node();
+                        let node = node_ref.borrow();
+                        if let NodeBox::Leaf(ref leaf) = *node {
+                            if leaf.key == *key {
+                                return Ok(Some(leaf.value.clone()));
+                            }
+                        }
+                        return Ok(None);
+                    }
+
+                    // Lookup key is too short for the current label. It's not stored.
+                    if key.bit_length() < bit_length {
+                        return Ok(None);
+                    }
+
+                    // Continue based on a bit value.
+                    hash = if key.get_bit(bit_length) {
+                        n.right.borrow().hash
+                    } else {
+                        n.left.borrow().hash
+                    };
+                    bit_depth = bit_length;
+                }
+                NodeBox::Leaf(n) => {
+                    if n.key == *key {
+                        return Ok(Some(n.value));
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn collect(&self, hash: Hash, items: &mut Vec<(Key, Value)>) -> Result<()> {
+        match self.load(&hash)? {
+            None => Ok(()),
+            Some(NodeBox::Internal(n)) => {
+                // The leaf node's key is a prefix of all keys in the left and
+                // right subtrees, so it comes first in key order.
+                if !n.leaf_node.borrow().is_null() {
+                    let node_ref = n.leaf_node.borrow().get_node();
+                    let node = node_ref.borrow();
+                    if let NodeBox::Leaf(ref leaf) = *node {
+                        items.push((leaf.key.clone(), leaf.value.clone()));
+                    }
+                }
+                self.collect(n.left.borrow().hash, items)?;
+                self.collect(n.right.borrow().hash, items)
+            }
+            Some(NodeBox::Leaf(n)) => {
+                items.push((n.key, n.value));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Tree {
+    /// Produce an immutable snapshot of the current root of the tree that can
+    /// be shared between threads.
+    ///
+    /// All nodes of the tree are copied into the snapshot, so any missing
+    /// nodes are fetched via the read syncer first. The tree must not have
+    /// any uncommitted modifications.
+    pub fn freeze(&self, ctx: Context) -> Result<FrozenTree> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !self.pending_write_log.is_empty() || !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: freeze requested for a tree with uncommitted modifications"
+            ));
+        }
+
+        let root = Root {
+            hash: pending_root.borrow().hash,
+            ..self.cache.borrow().get_sync_root()
+        };
+        let mut nodes = HashMap::new();
+        self._freeze(&ctx, pending_root, 0, Key::new(), &mut nodes)?;
+
+        Ok(FrozenTree {
+            inner: Arc::new(FrozenTreeInner { root, nodes }),
+        })
+    }
+
+    fn _freeze(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        nodes: &mut HashMap<Hash, Vec<u8>>,
+    ) -> Result<()> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(()),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (leaf_node, left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.leaf_node.clone(),
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                // The leaf node is serialized together with the internal node,
+                // so make sure that it is available.
+                self.cache.borrow_mut().deref_node_ptr(
+                    ctx,
+                    leaf_node,
+                    Some(FetcherSyncIterate::new(&path, 0)),
+                )?;
+                let node = node_ref.borrow();
+                nodes.insert(node.get_hash(), node.marshal_binary()?);
+                drop(node);
+
+                self._freeze(
+                    ctx,
+                    left,
+                    bit_length,
+                    new_path.append_bit(bit_length, false),
+                    nodes,
+                )?;
+                self._freeze(
+                    ctx,
+                    right,
+                    bit_length,
+                    new_path.append_bit(bit_length, true),
+                    nodes,
+                )
+            }
+            NodeKind::Leaf => {
+                let node_ref = node_ref.unwrap();
+                let node = node_ref.borrow();
+                nodes.insert(node.get_hash(), node.marshal_binary()?);
+                Ok(())
+            }
+        }
+    }
+}
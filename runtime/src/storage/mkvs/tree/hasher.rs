@@ -0,0 +1,27 @@
+use crate::common::crypto::hash::Hash;
+
+/// Hash function used to compute the hashes of tree nodes.
+///
+/// Trees using different hashers produce different roots for the same
+/// contents, so they are not compatible with each other.
+pub trait NodeHasher: Send + Sync {
+    /// Name identifying the hash function.
+    fn name(&self) -> &'static str;
+
+    /// Compute the hash of the concatenation of the given byte slices.
+    fn digest_bytes_list(&self, data: &[&[u8]]) -> Hash;
+}
+
+/// The default node hasher, using SHA-512/256.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultNodeHasher;
+
+impl NodeHasher for DefaultNodeHasher {
+    fn name(&self) -> &'static str {
+        "sha512_256"
+    }
+
+    fn digest_bytes_list(&self, data: &[&[u8]]) -> Hash {
+        Hash::digest_bytes_list(data)
+    }
+}
@@ -7,38 +7,448 @@ use crate::storage::mkvs::{cache::*, tree::*};
 
 use super::lookup::FetcherSyncGet;
 
+/// Conditions under which an insert writes the value.
+#[derive(Clone, Copy, PartialEq)]
+enum InsertMode {
+    /// Always write the value.
+    Always,
+    /// Only write the value if the key does not exist.
+    IfAbsent,
+    /// Only write the value if the key already exists.
+    IfPresent,
+}
+
+/// Value to be written by an insert, computed when first needed.
+struct InsertValue<'a> {
+    value: Option<Value>,
+    default: Option<Box<dyn FnOnce() -> Value + 'a>>,
+    max_size: Option<usize>,
+}
+
+impl<'a> InsertValue<'a> {
+    fn new(value: Value) -> Self {
+        Self {
+            value: Some(value),
+            default: None,
+            max_size: None,
+        }
+    }
+
+    fn with_default<F>(default: F, max_size: Option<usize>) -> Self
+    where
+        F: FnOnce() -> Value + 'a,
+    {
+        Self {
+            value: None,
+            default: Some(Box::new(default)),
+            max_size,
+        }
+    }
+
+    /// Return a copy of the value, computing it on first use.
+    fn get(&mut self) -> Result<Value> {
+        self.compute()?;
+        Ok(self.value.clone().unwrap_or_default())
+    }
+
+    /// Return the value, computing it if it has not been used yet.
+    fn into_value(mut self) -> Result<Value> {
+        self.compute()?;
+        Ok(self.value.take().unwrap_or_default())
+    }
+
+    fn compute(&mut self) -> Result<()> {
+        if let Some(default) = self.default.take() {
+            let value = default();
+            check_value_size(&value, self.max_size)?;
+            self.value = Some(value);
+        }
+        Ok(())
+    }
+}
+
+/// Split sorted keys that all continue through an internal node into the
+/// keys ending at the node, followed by the keys continuing left and then
+/// right.
+///
+/// Returns the end offsets of the first two groups, or `None` if any key
+/// diverges from the node's label or the keys are out of order.
+fn partition_batch(
+    label: &Key,
+    label_bit_length: Depth,
+    bit_depth: Depth,
+    keys: &[Key],
+) -> Option<(usize, usize)> {
+    let label_end = bit_depth + label_bit_length;
+    let (mut here, mut left) = (0, 0);
+    let mut last_group = 0;
+    for (i, key) in keys.iter().enumerate() {
+        let key_bit_length = key.bit_length();
+        if key_bit_length < label_end {
+            return None;
+        }
+        let (_, key_remainder) = key.split(bit_depth, key_bit_length);
+        let cp_len =
+            label.common_prefix_len(label_bit_length, &key_remainder, key_bit_length - bit_depth);
+        if cp_len != label_bit_length {
+            return None;
+        }
+
+        let group = if key_bit_length == label_end {
+            0
+        } else if key.get_bit(label_end) {
+            2
+        } else {
+            1
+        };
+        if group < last_group {
+            return None;
+        }
+        last_group = group;
+        if group == 0 {
+            here = i + 1;
+        }
+        if group <= 1 {
+            left = i + 1;
+        }
+    }
+    Some((here, left))
+}
+
+fn check_value_size(value: &[u8], max_size: Option<usize>) -> Result<()> {
+    if let Some(max) = max_size {
+        if value.len() > max {
+            return Err(TreeError::ValueTooLarge {
+                size: value.len(),
+                max,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
 impl Tree {
     /// Insert a key/value pair into the tree.
     pub fn insert(&mut self, ctx: Context, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        check_value_size(value, self.max_value_size)?;
+        self.insert_with_mode(
+            ctx,
+            key,
+            InsertValue::new(value.to_vec()),
+            InsertMode::Always,
+            false,
+        )
+        .map(|(old_val, _)| old_val)
+    }
+
+    /// Insert a presence marker for a key, i.e. the key without any value,
+    /// replacing any existing value.
+    ///
+    /// Markers are meant for using the tree as a set. Unlike a key with an
+    /// empty value, a marker is reported by `contains_key`, but `get` returns
+    /// `None` for it. Iteration yields markers with an empty value.
+    pub fn insert_marker(&mut self, ctx: Context, key: &[u8]) -> Result<()> {
+        self.insert_with_mode(
+            ctx,
+            key,
+            InsertValue::new(Value::new()),
+            InsertMode::Always,
+            true,
+        )
+        .map(|_| ())
+    }
+
+    /// Insert key/value pairs whose keys are in strictly ascending order.
+    ///
+    /// The order is trusted and only validated in debug builds, where
+    /// out-of-order or duplicate keys cause a panic. In release builds such
+    /// input is still inserted correctly, with later pairs overwriting earlier
+    /// ones, but may lose the speedup.
+    ///
+    /// Instead of descending from the root for every key, the pairs are
+    /// inserted in a single descent which visits each node on their shared
+    /// prefixes once, e.g., when importing a checkpoint.
+    pub fn insert_batch_sorted<K, V>(&mut self, ctx: Context, pairs: &[(K, V)]) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        debug_assert!(
+            pairs.windows(2).all(|w| w[0].0.as_ref() < w[1].0.as_ref()),
+            "mkvs: keys must be in strictly ascending order"
+        );
+
+        for (_, value) in pairs {
+            check_value_size(value.as_ref(), self.max_value_size)?;
+        }
+
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        let keys: Vec<Key> = pairs.iter().map(|(key, _)| key.as_ref().to_vec()).collect();
+        let values: Vec<Value> = pairs
+            .iter()
+            .map(|(_, value)| value.as_ref().to_vec())
+            .collect();
+        let mut existed = vec![false; pairs.len()];
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let new_root =
+            self._insert_batch(&ctx, pending_root, 0, &keys, &values, &mut existed, 0)?;
+        for ((key, value), existed) in keys.into_iter().zip(values).zip(existed) {
+            self.record_insert(key, value, existed, false);
+        }
+        self.cache.borrow_mut().set_pending_root(new_root);
+
+        Ok(())
+    }
+
+    /// Insert a key/value pair into the tree unless the key already exists.
+    ///
+    /// Returns true iff the value has been inserted.
+    pub fn insert_if_absent(&mut self, ctx: Context, key: &[u8], value: &[u8]) -> Result<bool> {
+        check_value_size(value, self.max_value_size)?;
+        self.insert_with_mode(
+            ctx,
+            key,
+            InsertValue::new(value.to_vec()),
+            InsertMode::IfAbsent,
+            false,
+        )
+        .map(|(_, written)| written.is_some())
+    }
+
+    /// Replace the value of an existing key in the tree. Nothing is written
+    /// if the key does not exist.
+    ///
+    /// Returns true iff the value has been replaced.
+    pub fn replace_existing(&mut self, ctx: Context, key: &[u8], value: &[u8]) -> Result<bool> {
+        check_value_size(value, self.max_value_size)?;
+        self.insert_with_mode(
+            ctx,
+            key,
+            InsertValue::new(value.to_vec()),
+            InsertMode::IfPresent,
+            false,
+        )
+        .map(|(_, written)| written.is_some())
+    }
+
+    /// Return the value of an existing key, or insert the value computed by
+    /// `default` if the key does not exist and return it.
+    ///
+    /// Keys removed since the last commit are treated as absent, while a
+    /// presence marker counts as an existing key with an empty value. The
+    /// lookup and the insert share a single descent: the default is only
+    /// computed, and the insert only recorded in the write log, once the
+    /// descent finds that the key does not exist.
+    pub fn get_or_insert_with<F>(&mut self, ctx: Context, key: &[u8], default: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let value = InsertValue::with_default(default, self.max_value_size);
+        let (old_val, written) =
+            self.insert_with_mode(ctx, key, value, InsertMode::IfAbsent, false)?;
+        // The key either existed or the default has been inserted.
+        Ok(written.or(old_val).unwrap_or_default())
+    }
+
+    /// Copy all keys under `prefix` from the `source` tree into this tree.
+    ///
+    /// Returns the number of copied keys.
+    ///
+    /// Node hashes commit to the version in which the node was created, so
+    /// the source's subtree hashes cannot be reused: the copied keys are new
+    /// in this tree and must hash identically to what storage computes when
+    /// applying the resulting write log.
+    pub fn copy_subtree(&mut self, ctx: Context, source: &Tree, prefix: &[u8]) -> Result<usize> {
+        let ctx = ctx.freeze();
+        let mut it = source.iter(Context::create_child(&ctx));
+        it.seek(prefix);
+
+        let mut copied = 0;
+        for (key, value) in it.by_ref().take_while(|(key, _)| key.starts_with(prefix)) {
+            self.insert(Context::create_child(&ctx), &key, &value)?;
+            copied += 1;
+        }
+        if let Some(error) = it.error() {
+            return Err(anyhow!("{}", error));
+        }
+
+        Ok(copied)
+    }
+
+    /// Returns the old value, if the key existed, and the written value, if
+    /// the insert has been performed.
+    fn insert_with_mode(
+        &mut self,
+        ctx: Context,
+        key: &[u8],
+        mut value: InsertValue,
+        mode: InsertMode,
+        marker: bool,
+    ) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
         let ctx = ctx.freeze();
         let pending_root = self.cache.borrow().get_pending_root();
         let boxed_key = key.to_vec();
-        let boxed_val = value.to_vec();
 
         // Remember where the path from root to target node ends (will end).
         self.cache.borrow_mut().mark_position();
 
-        let (new_root, old_val) =
-            self._insert(&ctx, pending_root, 0, &boxed_key, boxed_val.clone(), 0)?;
+        let (new_root, old_val) = self._insert(
+            &ctx,
+            pending_root,
+            0,
+            &boxed_key,
+            &mut value,
+            0,
+            mode,
+            marker,
+        )?;
         let existed = old_val != None;
-        match self.pending_write_log.get_mut(&boxed_key) {
+        let written = match mode {
+            InsertMode::Always => true,
+            InsertMode::IfAbsent => !existed,
+            InsertMode::IfPresent => existed,
+        };
+        if !written {
+            return Ok((old_val, None));
+        }
+        let boxed_val = value.into_value()?;
+
+        self.record_insert(boxed_key, boxed_val.clone(), existed, marker);
+        self.cache.borrow_mut().set_pending_root(new_root.clone());
+
+        Ok((old_val, Some(boxed_val)))
+    }
+
+    /// Record an insert in the pending write log.
+    fn record_insert(&mut self, key: Key, value: Value, existed: bool, marker: bool) {
+        match self.pending_write_log.get_mut(&key) {
             None => {
                 self.pending_write_log.insert(
-                    boxed_key,
+                    key.clone(),
                     PendingLogEntry {
-                        key: key.to_vec(),
-                        value: Some(boxed_val.clone()),
+                        key,
+                        value: Some(value),
+                        marker,
                         existed: existed,
                     },
                 );
             }
             Some(ref mut entry) => {
-                entry.value = Some(boxed_val.clone());
+                entry.value = Some(value);
+                entry.marker = marker;
             }
         };
-        self.cache.borrow_mut().set_pending_root(new_root.clone());
+    }
+
+    /// Insert sorted key/value pairs into the subtree at `ptr`, visiting
+    /// each node on their shared prefixes once.
+    ///
+    /// For every pair, `existed` is set to whether the key existed before.
+    fn _insert_batch(
+        &mut self,
+        ctx: &Arc<Context>,
+        mut ptr: NodePtrRef,
+        bit_depth: Depth,
+        keys: &[Key],
+        values: &[Value],
+        existed: &mut [bool],
+        depth: Depth,
+    ) -> Result<NodePtrRef> {
+        let mut offset = 0;
+        while offset < keys.len() {
+            let node_ref = self.cache.borrow_mut().deref_node_ptr(
+                ctx,
+                ptr.clone(),
+                Some(FetcherSyncGet::new(&keys[offset], false)),
+            )?;
+
+            if let Some(ref node_ref) = node_ref {
+                if let NodeBox::Internal(ref mut n) = *node_ref.borrow_mut() {
+                    let keys = &keys[offset..];
+                    let values = &values[offset..];
+                    let existed = &mut existed[offset..];
+
+                    if let Some((here, left)) =
+                        partition_batch(&n.label, n.label_bit_length, bit_depth, keys)
+                    {
+                        // All remaining keys continue through this node, so
+                        // insert each group into the corresponding child.
+                        let label_end = bit_depth + n.label_bit_length;
+                        if here > 0 {
+                            n.leaf_node = self._insert_batch(
+                                ctx,
+                                n.leaf_node.clone(),
+                                label_end,
+                                &keys[..here],
+                                &values[..here],
+                                &mut existed[..here],
+                                depth,
+                            )?;
+                        }
+                        if left > here {
+                            n.left = self._insert_batch(
+                                ctx,
+                                n.left.clone(),
+                                label_end,
+                                &keys[here..left],
+                                &values[here..left],
+                                &mut existed[here..left],
+                                depth + 1,
+                            )?;
+                        }
+                        if keys.len() > left {
+                            n.right = self._insert_batch(
+                                ctx,
+                                n.right.clone(),
+                                label_end,
+                                &keys[left..],
+                                &values[left..],
+                                &mut existed[left..],
+                                depth + 1,
+                            )?;
+                        }
+
+                        if !n.leaf_node.borrow().clean
+                            || !n.left.borrow().clean
+                            || !n.right.borrow().clean
+                        {
+                            n.clean = false;
+                            ptr.borrow_mut().mark_dirty();
+                            // No longer eligible for eviction as it is dirty.
+                            self.cache
+                                .borrow_mut()
+                                .rollback_node(ptr.clone(), NodeKind::Internal);
+                        }
 
-        Ok(old_val)
+                        return Ok(ptr);
+                    }
+                }
+            }
+
+            // Not all remaining keys continue through this node. Insert the
+            // next key on its own, which creates or splits the node as needed,
+            // and retry with the rest.
+            let (new_ptr, old_val) = self._insert(
+                ctx,
+                ptr,
+                bit_depth,
+                &keys[offset],
+                &mut InsertValue::new(values[offset].clone()),
+                depth,
+                InsertMode::Always,
+                false,
+            )?;
+            existed[offset] = old_val != None;
+            ptr = new_ptr;
+            offset += 1;
+        }
+
+        Ok(ptr)
     }
 
     fn _insert(
@@ -47,8 +457,10 @@ impl Tree {
         ptr: NodePtrRef,
         bit_depth: Depth,
         key: &Key,
-        val: Value,
+        val: &mut InsertValue,
         depth: Depth,
+        mode: InsertMode,
+        marker: bool,
     ) -> Result<(NodePtrRef, Option<Value>)> {
         let node_ref = self.cache.borrow_mut().deref_node_ptr(
             ctx,
@@ -60,7 +472,10 @@ impl Tree {
 
         match classify_noderef!(?node_ref) {
             NodeKind::None => {
-                return Ok((self.cache.borrow_mut().new_leaf_node(key, val), None));
+                if mode == InsertMode::IfPresent {
+                    return Ok((ptr, None));
+                }
+                return Ok((self.new_leaf(key, val.get()?, marker), None));
             }
             NodeKind::Internal => {
                 let node_ref = node_ref.unwrap();
@@ -87,6 +502,8 @@ impl Tree {
                                 key,
                                 val,
                                 depth,
+                                mode,
+                                marker,
                             )?;
                             n.leaf_node = r.0;
                         } else if key.get_bit(bit_depth + n.label_bit_length) {
@@ -98,6 +515,8 @@ impl Tree {
                                 key,
                                 val,
                                 depth + 1,
+                                mode,
+                                marker,
                             )?;
                             n.right = r.0;
                         } else {
@@ -108,6 +527,8 @@ impl Tree {
                                 key,
                                 val,
                                 depth + 1,
+                                mode,
+                                marker,
                             )?;
                             n.left = r.0;
                         }
@@ -117,7 +538,7 @@ impl Tree {
                             || !n.right.borrow().clean
                         {
                             n.clean = false;
-                            ptr.borrow_mut().clean = false;
+                            ptr.borrow_mut().mark_dirty();
                             // No longer eligible for eviction as it is dirty.
                             self.cache
                                 .borrow_mut()
@@ -127,20 +548,26 @@ impl Tree {
                         return Ok((ptr, r.1));
                     }
 
-                    // Key mismatches the label at position cp_len. Split the edge and
-                    // insert new leaf.
+                    // Key mismatches the label at position cp_len, so the key does
+                    // not exist.
+                    if mode == InsertMode::IfPresent {
+                        return Ok((ptr, None));
+                    }
+                    let val = val.get()?;
+
+                    // Split the edge and insert new leaf.
                     let label_split = n.label.split(cp_len, n.label_bit_length);
                     label_prefix = label_split.0;
                     n.label = label_split.1;
                     n.label_bit_length = n.label_bit_length - cp_len;
                     n.clean = false;
-                    ptr.borrow_mut().clean = false;
+                    ptr.borrow_mut().mark_dirty();
                     // No longer eligible for eviction as it is dirty.
                     self.cache
                         .borrow_mut()
                         .rollback_node(ptr.clone(), NodeKind::Internal);
 
-                    let new_leaf = self.cache.borrow_mut().new_leaf_node(key, val);
+                    let new_leaf = self.new_leaf(key, val, marker);
                     if key.bit_length() - bit_depth == cp_len {
                         // The key is a prefix of existing path.
                         leaf_node = new_leaf;
@@ -188,13 +615,19 @@ impl Tree {
                 if let NodeBox::Leaf(ref mut n) = *node_ref.borrow_mut() {
                     // Should always succeed.
                     if n.key == *key {
+                        if mode == InsertMode::IfAbsent {
+                            return Ok((ptr.clone(), Some(n.value.clone())));
+                        }
+
                         // If the key matches, we can just update the value.
-                        if n.value == val {
+                        let val = val.get()?;
+                        if n.value == val && n.marker == marker {
                             return Ok((ptr.clone(), Some(val)));
                         }
+                        n.marker = marker;
                         let old_val = mem::replace(&mut n.value, val);
                         n.clean = false;
-                        ptr.borrow_mut().clean = false;
+                        ptr.borrow_mut().mark_dirty();
                         // No longer eligible for eviction as it is dirty.
                         self.cache
                             .borrow_mut()
@@ -202,6 +635,12 @@ impl Tree {
                         return Ok((ptr.clone(), Some(old_val)));
                     }
 
+                    // The key does not exist.
+                    if mode == InsertMode::IfPresent {
+                        return Ok((ptr.clone(), None));
+                    }
+                    let val = val.get()?;
+
                     let (_, leaf_key_remainder) = n.key.split(bit_depth, n.key.bit_length());
                     cp_len = leaf_key_remainder.common_prefix_len(
                         n.key.bit_length() - bit_depth,
@@ -213,7 +652,7 @@ impl Tree {
                     label_prefix = leaf_key_remainder
                         .split(cp_len, leaf_key_remainder.bit_length())
                         .0;
-                    let new_leaf = self.cache.borrow_mut().new_leaf_node(key, val);
+                    let new_leaf = self.new_leaf(key, val, marker);
 
                     if key.bit_length() - bit_depth == cp_len {
                         // Inserted key is a prefix of the label.
@@ -260,4 +699,12 @@ impl Tree {
             }
         }
     }
+
+    fn new_leaf(&mut self, key: &Key, val: Value, marker: bool) -> NodePtrRef {
+        let ptr = self.cache.borrow_mut().new_leaf_node(key, val);
+        if marker {
+            noderef_as_mut!(ptr.borrow().get_node(), Leaf).marker = true;
+        }
+        ptr
+    }
 }
@@ -1,10 +1,13 @@
 //! Tree iterator.
 use std::{collections::VecDeque, fmt, iter::Iterator, mem::replace, sync::Arc};
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use io_context::Context;
 
-use crate::storage::mkvs::{cache::*, sync::*, tree::*};
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{cache::*, sync::*, tree::*},
+};
 
 pub(super) struct FetcherSyncIterate<'a> {
     key: &'a Key,
@@ -341,11 +344,212 @@ impl<'tree> Iterator for TreeIterator<'tree> {
     }
 }
 
+/// A tree iterator going over the tree in descending key order.
+pub struct ReverseTreeIterator<'tree> {
+    ctx: Arc<Context>,
+    tree: &'tree Tree,
+    stack: Vec<(NodePtrRef, Depth, Key)>,
+    start: Option<Key>,
+    end: Option<Key>,
+    error: Option<Error>,
+}
+
+impl<'tree> ReverseTreeIterator<'tree> {
+    /// Create a new reverse tree iterator over keys in `[start, end)`.
+    fn new(ctx: Context, tree: &'tree Tree, start: Option<Key>, end: Option<Key>) -> Self {
+        let pending_root = tree.cache.borrow().get_pending_root();
+        Self {
+            ctx: ctx.freeze(),
+            tree,
+            stack: vec![(pending_root, 0, Key::new())],
+            start,
+            end,
+            error: None,
+        }
+    }
+
+    /// Return the error that occurred during iteration if any.
+    pub fn error(&self) -> &Option<Error> {
+        &self.error
+    }
+
+    /// Return true iff the subtree at the given path only contains keys at
+    /// or after the end of the iterated range.
+    fn past_end(&self, path: &Key) -> bool {
+        // All keys in the subtree start with the path, so the path itself
+        // (with the remaining bits of its last byte cleared) is a lower bound.
+        match self.end {
+            Some(ref end) => path >= end,
+            None => false,
+        }
+    }
+
+    fn _next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((ptr, bit_depth, path)) = self.stack.pop() {
+            let node_ref = self.tree.cache.borrow_mut().deref_node_ptr(
+                &self.ctx,
+                ptr,
+                Some(FetcherSyncIterate::new(&path, 0)),
+            )?;
+
+            match classify_noderef!(?node_ref) {
+                NodeKind::None => {}
+                NodeKind::Internal => {
+                    let node_ref = node_ref.unwrap();
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        let bit_length = bit_depth + n.label_bit_length;
+                        let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+                        // The leaf node key is a prefix of all other keys in the subtree so
+                        // it comes last. Before it come the left and the right subtree, in
+                        // reverse order.
+                        self.stack.push((n.leaf_node.clone(), bit_length, path));
+                        let left_path = new_path.append_bit(bit_length, false);
+                        if !self.past_end(&left_path) {
+                            self.stack.push((n.left.clone(), bit_length, left_path));
+                        }
+                        let right_path = new_path.append_bit(bit_length, true);
+                        if !self.past_end(&right_path) {
+                            self.stack.push((n.right.clone(), bit_length, right_path));
+                        }
+                    } else {
+                        unreachable!("node kind is internal node");
+                    }
+                }
+                NodeKind::Leaf => {
+                    let node_ref = node_ref.unwrap();
+                    if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                        if let Some(ref end) = self.end {
+                            if &n.key >= end {
+                                continue;
+                            }
+                        }
+                        if let Some(ref start) = self.start {
+                            if &n.key < start {
+                                // All remaining keys are smaller.
+                                self.stack.clear();
+                                return Ok(None);
+                            }
+                        }
+                        return Ok(Some((n.key.clone(), n.value.clone())));
+                    } else {
+                        unreachable!("node kind is leaf node");
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'tree> Iterator for ReverseTreeIterator<'tree> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        match self._next() {
+            Ok(item) => item,
+            Err(error) => {
+                self.error = Some(error);
+                self.stack.clear();
+                None
+            }
+        }
+    }
+}
+
 impl Tree {
     /// Returns an iterator over the tree.
     pub fn iter(&self, ctx: Context) -> TreeIterator {
         TreeIterator::new(ctx, self)
     }
+
+    /// Returns an iterator over the tree in descending key order.
+    ///
+    /// As with `iter`, uncommitted modifications are taken into account.
+    pub fn iter_rev(&self, ctx: Context) -> ReverseTreeIterator {
+        ReverseTreeIterator::new(ctx, self, None, None)
+    }
+
+    /// Returns an iterator over keys in `[start, end)` in descending key order.
+    pub fn iter_range_rev(&self, ctx: Context, start: &[u8], end: &[u8]) -> ReverseTreeIterator {
+        ReverseTreeIterator::new(ctx, self, Some(start.to_vec()), Some(end.to_vec()))
+    }
+
+    /// Return all nodes of the tree in canonical order.
+    ///
+    /// The canonical order is a pre-order traversal where each internal node
+    /// is followed by its leaf node (if any), then by its left and finally by
+    /// its right subtree. It only depends on the contents of the tree and is
+    /// independent of the cache state and of the order in which keys were
+    /// inserted, so it should be used as the basis for checkpoint export.
+    ///
+    /// Missing nodes are fetched via the read syncer. The tree must not have
+    /// any uncommitted modifications.
+    pub fn canonical_node_order(
+        &self,
+        ctx: Context,
+    ) -> Result<impl Iterator<Item = (Hash, NodeRef)>> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !self.pending_write_log.is_empty() || !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: canonical node order requested for a tree with uncommitted modifications"
+            ));
+        }
+
+        let mut nodes = Vec::new();
+        self._canonical_node_order(&ctx, pending_root, 0, Key::new(), &mut nodes)?;
+        Ok(nodes.into_iter())
+    }
+
+    fn _canonical_node_order(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        nodes: &mut Vec<(Hash, NodeRef)>,
+    ) -> Result<()> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr.clone(),
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+        let node_ref = match node_ref {
+            Some(node_ref) => node_ref,
+            None => return Ok(()),
+        };
+        nodes.push((ptr.borrow().hash, node_ref.clone()));
+
+        let node = node_ref.borrow();
+        if let NodeBox::Internal(ref n) = *node {
+            let bit_length = bit_depth + n.label_bit_length;
+            let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+            self._canonical_node_order(ctx, n.leaf_node.clone(), bit_length, path, nodes)?;
+            self._canonical_node_order(
+                ctx,
+                n.left.clone(),
+                bit_length,
+                new_path.append_bit(bit_length, false),
+                nodes,
+            )?;
+            self._canonical_node_order(
+                ctx,
+                n.right.clone(),
+                bit_length,
+                new_path.append_bit(bit_length, true),
+                nodes,
+            )?;
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -360,7 +564,7 @@ mod test {
     fn test_iterator() {
         let server = ProtocolServer::new();
 
-        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut tree = Tree::new_in_memory();
 
         // Test with an empty tree.
         let mut it = tree.iter(Context::background());
@@ -477,7 +681,7 @@ mod test {
 
     #[test]
     fn test_iterator_case1() {
-        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut tree = Tree::new_in_memory();
 
         let items = vec![
             (b"key 5".to_vec(), b"fivey".to_vec()),
@@ -495,7 +699,7 @@ mod test {
 
     #[test]
     fn test_iterator_case2() {
-        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut tree = Tree::new_in_memory();
 
         let items: Vec<(Vec<u8>, Vec<u8>)> = vec![
             (
@@ -576,6 +780,73 @@ mod test {
         assert_eq!(2, stats.sync_iterate_count, "sync_iterate_count");
     }
 
+    #[test]
+    fn test_iterator_rev() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        fn check(tree: &Tree, rng: &mut StdRng) {
+            let mut it = tree.iter(Context::background());
+            it.rewind();
+            let mut expected: Vec<_> = it.collect();
+            expected.reverse();
+
+            let mut it = tree.iter_rev(Context::background());
+            let items: Vec<_> = it.by_ref().collect();
+            assert!(it.error().is_none(), "iterator should not error");
+            assert_eq!(items, expected, "reverse iteration should match");
+
+            let start = vec![rng.gen_range(0, 4), rng.gen_range(0, 4)];
+            let end = vec![rng.gen_range(0, 4), rng.gen_range(0, 4)];
+            let range: Vec<_> = tree
+                .iter_range_rev(Context::background(), &start, &end)
+                .collect();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .filter(|(key, _)| key >= &start && key < &end)
+                .collect();
+            assert_eq!(range, expected, "reverse range iteration should match");
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let server = ProtocolServer::new();
+            let mut tree = Tree::new_in_memory();
+            for _ in 0..rng.gen_range(0, 100) {
+                let key: Vec<u8> = (0..rng.gen_range(1, 4))
+                    .map(|_| rng.gen_range(0, 4))
+                    .collect();
+                tree.insert(Context::background(), &key, &[rng.gen::<u8>()])
+                    .unwrap();
+            }
+            check(&tree, &mut rng);
+
+            let (write_log, hash) =
+                Tree::commit(&mut tree, Context::background(), Default::default(), 0)
+                    .expect("commit");
+            server.apply(&write_log, hash, Default::default(), 0);
+
+            let mut remote_tree = Tree::make()
+                .with_capacity(0, 0)
+                .with_root(Root {
+                    hash,
+                    ..Default::default()
+                })
+                .new(server.read_sync());
+            check(&remote_tree, &mut rng);
+
+            // Uncommitted modifications must be taken into account.
+            for entry in write_log.iter().take(5) {
+                remote_tree
+                    .remove(Context::background(), &entry.key)
+                    .unwrap();
+            }
+            remote_tree
+                .insert(Context::background(), &[1, 2, 3, 4], b"pending")
+                .unwrap();
+            check(&remote_tree, &mut rng);
+        }
+    }
+
     fn test_iterator_with(
         items: &Vec<(Vec<u8>, Vec<u8>)>,
         mut it: TreeIterator,
@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{cmp, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use io_context::Context;
 
-use crate::storage::mkvs::{cache::*, sync::*, tree::*};
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{cache::*, sync::*, tree::*, Prefix},
+};
+
+use super::iterator::FetcherSyncIterate;
 
 pub(super) struct FetcherSyncGet<'a> {
     key: &'a Key,
@@ -42,10 +47,161 @@ impl<'a> ReadSyncFetcher for FetcherSyncGet<'a> {
     }
 }
 
+/// Fetches the nodes required to look up multiple keys in a single request.
+struct FetcherSyncGetMany<'a> {
+    lookups: &'a [(usize, &'a Key)],
+}
+
+impl<'a> FetcherSyncGetMany<'a> {
+    fn new(lookups: &'a [(usize, &'a Key)]) -> Self {
+        Self { lookups }
+    }
+}
+
+impl<'a> ReadSyncFetcher for FetcherSyncGetMany<'a> {
+    fn fetch(
+        &self,
+        ctx: Context,
+        root: Root,
+        ptr: NodePtrRef,
+        rs: &mut Box<dyn ReadSync>,
+    ) -> Result<Proof> {
+        let mut prefixes: Vec<Prefix> = self
+            .lookups
+            .iter()
+            .map(|(_, key)| Prefix::from((*key).clone()))
+            .collect();
+        prefixes.dedup();
+        let limit = cmp::min(prefixes.len(), u16::max_value() as usize) as u16;
+
+        let rsp = rs.sync_get_prefixes(
+            ctx,
+            GetPrefixesRequest {
+                tree: TreeID {
+                    root,
+                    position: ptr.borrow().hash,
+                },
+                prefixes,
+                limit,
+            },
+        )?;
+        Ok(rsp.proof)
+    }
+}
+
 impl Tree {
     /// Get an existing key.
     pub fn get(&self, ctx: Context, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self._get_top(ctx, key, false)
+        Ok(entry_value(self._get_top(ctx, key, false)?))
+    }
+
+    /// Check if the key exists, either with a value or as a presence marker.
+    pub fn contains_key(&self, ctx: Context, key: &[u8]) -> Result<bool> {
+        Ok(self._get_top(ctx, key, false)?.is_some())
+    }
+
+    /// Get an existing key together with a proof of its value (or of its
+    /// absence if the key does not exist).
+    ///
+    /// Both are produced in a single traversal against the same root. The
+    /// tree must not have any uncommitted modifications.
+    pub fn get_with_proof(&self, ctx: Context, key: &[u8]) -> Result<(Option<Vec<u8>>, Proof)> {
+        let ctx = ctx.freeze();
+        let boxed_key = key.to_vec();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !self.pending_write_log.is_empty() || !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: proof requested for a tree with uncommitted modifications"
+            ));
+        }
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let mut builder = ProofBuilder::new(pending_root.borrow().hash);
+        let value = self._get(
+            &ctx,
+            pending_root,
+            0,
+            &boxed_key,
+            0,
+            false,
+            Some(&mut builder),
+        )?;
+
+        Ok((entry_value(value), builder.build()))
+    }
+
+    /// Get all key/value pairs in `[start, end)` together with a single proof
+    /// that they are exactly the pairs in that range.
+    ///
+    /// The proof can be checked via `ProofVerifier::verify_range_proof`. It
+    /// includes the paths to the closest keys just outside of the range so
+    /// that omitting any pair in the range is detected. The tree must not
+    /// have any uncommitted modifications.
+    pub fn get_range_proof(
+        &self,
+        ctx: Context,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<(Vec<(Key, Vec<u8>)>, RangeProof)> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !self.pending_write_log.is_empty() || !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: range proof requested for a tree with uncommitted modifications"
+            ));
+        }
+
+        let mut builder = ProofBuilder::new(pending_root.borrow().hash);
+        let mut pairs = Vec::new();
+        self._get_range_proof(
+            &ctx,
+            pending_root,
+            0,
+            Key::new(),
+            0,
+            start,
+            end,
+            &mut builder,
+            &mut pairs,
+        )?;
+
+        Ok((
+            pairs,
+            RangeProof {
+                proof: builder.build(),
+            },
+        ))
+    }
+
+    /// Get multiple existing keys.
+    ///
+    /// The keys are looked up in sorted order so that the common parts of
+    /// their paths are only traversed once, and any nodes missing from the
+    /// cache are fetched together for all keys below that node. The returned
+    /// values are in the same order as the given keys.
+    pub fn get_many(&self, ctx: Context, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let ctx = ctx.freeze();
+        let mut results = vec![None; keys.len()];
+
+        // Keys that have been modified locally don't need any lookups.
+        let mut lookups: Vec<(usize, &Key)> = Vec::with_capacity(keys.len());
+        for (index, key) in keys.iter().enumerate() {
+            match self.pending_write_log.get(key) {
+                Some(PendingLogEntry { marker: true, .. }) => {}
+                Some(PendingLogEntry { ref value, .. }) => results[index] = value.clone(),
+                None => lookups.push((index, key)),
+            }
+        }
+        lookups.sort_by(|a, b| a.1.cmp(b.1));
+
+        // Remember where the path from root to target node ends (will end).
+        self.cache.borrow_mut().mark_position();
+
+        let pending_root = self.cache.borrow().get_pending_root();
+        self._get_many(&ctx, pending_root, 0, &lookups, &mut results)?;
+        Ok(results)
     }
 
     /// Check if the key exists in the local cache.
@@ -57,23 +213,388 @@ impl Tree {
         }
     }
 
-    fn _get_top(&self, ctx: Context, key: &[u8], check_only: bool) -> Result<Option<Vec<u8>>> {
+    /// Return the number of keys in the tree.
+    ///
+    /// This walks the whole tree, fetching any missing nodes via the read
+    /// syncer, so it is O(n) in the number of keys. For large trees consider
+    /// caching the result.
+    pub fn len(&self, ctx: Context) -> Result<usize> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        self._len(&ctx, pending_root, 0, Key::new())
+    }
+
+    /// Check if the tree contains no keys.
+    pub fn is_empty(&self, ctx: Context) -> Result<bool> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if pending_root.borrow().is_null() {
+            return Ok(true);
+        }
+
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            &ctx,
+            pending_root,
+            Some(FetcherSyncIterate::new(&Key::new(), 0)),
+        )?;
+        Ok(node_ref.is_none())
+    }
+
+    /// Return the hash of the smallest subtree that contains all keys
+    /// starting with the given prefix, or `None` if there are no such keys.
+    ///
+    /// In case the keys with the given prefix are spread across multiple
+    /// subtrees, the hash of their lowest common ancestor is returned. As
+    /// this ancestor contains no other keys, comparing the returned hashes
+    /// for two roots tells whether anything under the prefix has changed.
+    /// The tree must not have any uncommitted modifications.
+    pub fn get_subtree_hash(&self, ctx: Context, prefix: &[u8]) -> Result<Option<Hash>> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        if !self.pending_write_log.is_empty() || !pending_root.borrow().clean {
+            return Err(anyhow!(
+                "mkvs: subtree hash requested for a tree with uncommitted modifications"
+            ));
+        }
+
+        let prefix = prefix.to_vec();
+        let subtree = self._get_prefix_subtree(&ctx, pending_root, 0, Key::new(), &prefix)?;
+        Ok(subtree.map(|(ptr, _, _)| ptr.borrow().hash))
+    }
+
+    /// Check whether any key starting with the given prefix exists, taking
+    /// uncommitted modifications into account.
+    ///
+    /// This only descends to the smallest subtree containing all keys with
+    /// the prefix, without enumerating them.
+    pub fn any_with_prefix(&self, ctx: Context, prefix: &[u8]) -> Result<bool> {
+        let prefix = prefix.to_vec();
+
+        // A pending insert under the prefix answers the question without any
+        // traversal. Pending removals are already reflected in the tree.
+        let pending_insert = self
+            .pending_write_log
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .any(|(_, entry)| entry.value.is_some());
+        if pending_insert {
+            return Ok(true);
+        }
+
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+        let subtree = self._get_prefix_subtree(&ctx, pending_root, 0, Key::new(), &prefix)?;
+        Ok(subtree.is_some())
+    }
+
+    /// Find the smallest subtree that contains all keys starting with the
+    /// given prefix, returning its pointer together with its bit depth and
+    /// path.
+    pub(super) fn _get_prefix_subtree(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        prefix: &Key,
+    ) -> Result<Option<(NodePtrRef, Depth, Key)>> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr.clone(),
+            Some(FetcherSyncGet::new(prefix, false)),
+        )?;
+        let prefix_length = prefix.bit_length();
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(None),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                let common_length = new_path.common_prefix_len(bit_length, prefix, prefix_length);
+                if prefix_length <= bit_length {
+                    // All keys in this subtree start with the node's path, so
+                    // either all of them or none of them have the prefix.
+                    if common_length < prefix_length {
+                        return Ok(None);
+                    }
+                    return Ok(Some((ptr, bit_depth, path)));
+                }
+                if common_length < bit_length {
+                    return Ok(None);
+                }
+
+                // The leaf node's key is shorter than the prefix, so continue
+                // based on the next bit of the prefix.
+                if prefix.get_bit(bit_length) {
+                    self._get_prefix_subtree(
+                        ctx,
+                        right,
+                        bit_length,
+                        new_path.append_bit(bit_length, true),
+                        prefix,
+                    )
+                } else {
+                    self._get_prefix_subtree(
+                        ctx,
+                        left,
+                        bit_length,
+                        new_path.append_bit(bit_length, false),
+                        prefix,
+                    )
+                }
+            }
+            NodeKind::Leaf => {
+                let node_ref = node_ref.unwrap();
+                let node = node_ref.borrow();
+                if let NodeBox::Leaf(ref n) = *node {
+                    let common_length =
+                        n.key
+                            .common_prefix_len(n.key.bit_length(), prefix, prefix_length);
+                    if common_length < prefix_length {
+                        return Ok(None);
+                    }
+                    return Ok(Some((ptr, bit_depth, path)));
+                }
+
+                unreachable!("node kind is leaf node");
+            }
+        }
+    }
+
+    fn _len(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+    ) -> Result<usize> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(0),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (leaf_node, left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.leaf_node.clone(),
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                let mut count = self._len(ctx, leaf_node, bit_length, path)?;
+                count += self._len(
+                    ctx,
+                    left,
+                    bit_length,
+                    new_path.append_bit(bit_length, false),
+                )?;
+                count += self._len(
+                    ctx,
+                    right,
+                    bit_length,
+                    new_path.append_bit(bit_length, true),
+                )?;
+                Ok(count)
+            }
+            NodeKind::Leaf => Ok(1),
+        }
+    }
+
+    fn _get_many(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        lookups: &[(usize, &Key)],
+        results: &mut Vec<Option<Value>>,
+    ) -> Result<()> {
+        if lookups.is_empty() {
+            return Ok(());
+        }
+
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncGetMany::new(lookups)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => {
+                // Reached a nil node, there is nothing here.
+                Ok(())
+            }
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                    let bit_length = bit_depth + n.label_bit_length;
+
+                    // Split the lookups based on where they continue. Lookup
+                    // keys that are too short for the label are not stored.
+                    let mut here = Vec::new();
+                    let mut left = Vec::new();
+                    let mut right = Vec::new();
+                    for &(index, key) in lookups {
+                        if key.bit_length() == bit_length {
+                            here.push((index, key));
+                        } else if key.bit_length() > bit_length {
+                            if key.get_bit(bit_length) {
+                                right.push((index, key));
+                            } else {
+                                left.push((index, key));
+                            }
+                        }
+                    }
+
+                    self._get_many(ctx, n.leaf_node.clone(), bit_length, &here, results)?;
+                    self._get_many(ctx, n.left.clone(), bit_length, &left, results)?;
+                    self._get_many(ctx, n.right.clone(), bit_length, &right, results)?;
+                    return Ok(());
+                }
+
+                unreachable!("node kind is internal node");
+            }
+            NodeKind::Leaf => {
+                // Reached a leaf node, check which keys match.
+                let node_ref = node_ref.unwrap();
+                if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                    for &(index, key) in lookups {
+                        if n.key == *key && !n.marker {
+                            results[index] = Some(n.value.clone());
+                        }
+                    }
+                    return Ok(());
+                }
+
+                unreachable!("node kind is leaf node");
+            }
+        }
+    }
+
+    /// Include all nodes of the subtree behind the given pointer that may
+    /// contain keys in `[start, end)` in the proof, collecting such keys.
+    fn _get_range_proof(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        path_len: Depth,
+        start: &[u8],
+        end: &[u8],
+        builder: &mut ProofBuilder,
+        pairs: &mut Vec<(Key, Vec<u8>)>,
+    ) -> Result<()> {
+        if !prefix_intersects_range(&path, path_len, start, end) {
+            return Ok(());
+        }
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+        let node_ref = match node_ref {
+            Some(node_ref) => node_ref,
+            None => return Ok(()),
+        };
+        builder.include(&node_ref.borrow())?;
+
+        let node = node_ref.borrow();
+        match *node {
+            NodeBox::Internal(ref n) => {
+                let bit_length = bit_depth + n.label_bit_length;
+                let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+                self._get_range_proof(
+                    ctx,
+                    n.leaf_node.clone(),
+                    bit_length,
+                    new_path.clone(),
+                    bit_length,
+                    start,
+                    end,
+                    builder,
+                    pairs,
+                )?;
+                self._get_range_proof(
+                    ctx,
+                    n.left.clone(),
+                    bit_length,
+                    new_path.append_bit(bit_length, false),
+                    bit_length + 1,
+                    start,
+                    end,
+                    builder,
+                    pairs,
+                )?;
+                self._get_range_proof(
+                    ctx,
+                    n.right.clone(),
+                    bit_length,
+                    new_path.append_bit(bit_length, true),
+                    bit_length + 1,
+                    start,
+                    end,
+                    builder,
+                    pairs,
+                )?;
+            }
+            NodeBox::Leaf(ref n) => {
+                if n.key.as_slice() >= start && n.key.as_slice() < end {
+                    pairs.push((n.key.clone(), n.value.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up a key, returning its value and whether it is a marker.
+    fn _get_top(
+        &self,
+        ctx: Context,
+        key: &[u8],
+        check_only: bool,
+    ) -> Result<Option<(Vec<u8>, bool)>> {
         let ctx = ctx.freeze();
         let boxed_key = key.to_vec();
         let pending_root = self.cache.borrow().get_pending_root();
 
         // If the key has been modified locally, no need to perform any lookups.
-        if let Some(PendingLogEntry { ref value, .. }) = self.pending_write_log.get(&boxed_key) {
-            return Ok(value.clone());
+        if let Some(PendingLogEntry {
+            ref value, marker, ..
+        }) = self.pending_write_log.get(&boxed_key)
+        {
+            return Ok(value.clone().map(|value| (value, *marker)));
         }
 
         // Remember where the path from root to target node ends (will end).
         self.cache.borrow_mut().mark_position();
 
-        Ok(self._get(&ctx, pending_root, 0, &boxed_key, 0, check_only)?)
+        Ok(self._get(&ctx, pending_root, 0, &boxed_key, 0, check_only, None)?)
     }
 
-    fn _get(
+    pub(super) fn _get(
         &self,
         ctx: &Arc<Context>,
         ptr: NodePtrRef,
@@ -81,7 +602,8 @@ impl Tree {
         key: &Key,
         depth: Depth,
         check_only: bool,
-    ) -> Result<Option<Value>> {
+        mut proof: Option<&mut ProofBuilder>,
+    ) -> Result<Option<(Value, bool)>> {
         let node_ref = self.cache.borrow_mut().deref_node_ptr(
             ctx,
             ptr,
@@ -91,6 +613,11 @@ impl Tree {
                 Some(FetcherSyncGet::new(key, false))
             },
         )?;
+        if let Some(proof) = proof.as_mut() {
+            if let Some(ref node_ref) = node_ref {
+                proof.include(&*node_ref.borrow())?;
+            }
+        }
 
         match classify_noderef!(?node_ref) {
             NodeKind::None => {
@@ -110,6 +637,7 @@ impl Tree {
                             key,
                             depth,
                             check_only,
+                            proof,
                         );
                     }
 
@@ -127,6 +655,7 @@ impl Tree {
                             key,
                             depth + 1,
                             check_only,
+                            proof,
                         );
                     } else {
                         return self._get(
@@ -136,6 +665,7 @@ impl Tree {
                             key,
                             depth + 1,
                             check_only,
+                            proof,
                         );
                     }
                 }
@@ -145,12 +675,21 @@ impl Tree {
             NodeKind::Leaf => {
                 // Reached a leaf node, check if key matches.
                 let node_ref = node_ref.unwrap();
-                if noderef_as!(node_ref, Leaf).key == *key {
-                    return Ok(Some(noderef_as!(node_ref, Leaf).value.clone()));
-                } else {
-                    return Ok(None);
+                if let NodeBox::Leaf(ref n) = *node_ref.borrow() {
+                    if n.key == *key {
+                        return Ok(Some((n.value.clone(), n.marker)));
+                    }
                 }
+                return Ok(None);
             }
         };
     }
 }
+
+/// Return the value of a looked up entry, treating markers as having none.
+fn entry_value(entry: Option<(Value, bool)>) -> Option<Value> {
+    match entry {
+        Some((value, false)) => Some(value),
+        _ => None,
+    }
+}
@@ -62,7 +62,7 @@ impl Marshal for NodeKind {
                 *self = NodeKind::None;
             } else if data[0] == NodeKind::Internal as u8 {
                 *self = NodeKind::Internal;
-            } else if data[0] == NodeKind::Leaf as u8 {
+            } else if data[0] & !LEAF_MARKER_FLAG == NodeKind::Leaf as u8 {
                 *self = NodeKind::Leaf;
             } else {
                 return Err(TreeError::MalformedNode.into());
@@ -72,8 +72,21 @@ impl Marshal for NodeKind {
     }
 }
 
-impl Marshal for InternalNode {
-    fn marshal_binary(&self) -> Result<Vec<u8>> {
+impl NodeBox {
+    /// Serialize the node in compact form, omitting the hashes of the
+    /// children of internal nodes.
+    pub fn compact_marshal_binary(&self) -> Result<Vec<u8>> {
+        match self {
+            NodeBox::Internal(ref n) => n.compact_marshal_binary(),
+            NodeBox::Leaf(ref n) => n.marshal_binary(),
+        }
+    }
+}
+
+impl InternalNode {
+    /// Serialize the node in compact form, omitting the hashes of the
+    /// left and right children. The leaf node is always included.
+    pub fn compact_marshal_binary(&self) -> Result<Vec<u8>> {
         let leaf_node_binary: Vec<u8>;
         if self.leaf_node.borrow().is_null() {
             leaf_node_binary = vec![NodeKind::None as u8];
@@ -89,6 +102,14 @@ impl Marshal for InternalNode {
         result.append(&mut self.label_bit_length.marshal_binary()?);
         result.extend_from_slice(&self.label);
         result.extend_from_slice(leaf_node_binary.as_ref());
+
+        Ok(result)
+    }
+}
+
+impl Marshal for InternalNode {
+    fn marshal_binary(&self) -> Result<Vec<u8>> {
+        let mut result = self.compact_marshal_binary()?;
         result.extend_from_slice(self.left.borrow().hash.as_ref());
         result.extend_from_slice(self.right.borrow().hash.as_ref());
 
@@ -176,23 +197,30 @@ impl Marshal for InternalNode {
 impl Marshal for LeafNode {
     fn marshal_binary(&self) -> Result<Vec<u8>> {
         let mut result: Vec<u8> = Vec::with_capacity(1 + VERSION_SIZE + VALUE_LENGTH_SIZE);
-        result.push(NodeKind::Leaf as u8);
+        result.push(self.kind_byte());
         result.append(&mut self.version.marshal_binary()?);
         result.append(&mut self.key.marshal_binary()?);
-        result.append(&mut (self.value.len() as u32).marshal_binary()?);
-        result.extend_from_slice(&self.value);
+        // Presence markers have no value, not even an empty one.
+        if !self.marker {
+            result.append(&mut (self.value.len() as u32).marshal_binary()?);
+            result.extend_from_slice(&self.value);
+        }
 
         Ok(result)
     }
 
     fn unmarshal_binary(&mut self, data: &[u8]) -> Result<usize> {
-        if data.len() < 1 + VERSION_SIZE + size_of::<Depth>() + VALUE_LENGTH_SIZE
-            || data[0] != NodeKind::Leaf as u8
-        {
+        if data.len() < 1 || data[0] & !LEAF_MARKER_FLAG != NodeKind::Leaf as u8 {
+            return Err(TreeError::MalformedNode.into());
+        }
+        let marker = data[0] & LEAF_MARKER_FLAG != 0;
+        let value_length_size = if marker { 0 } else { VALUE_LENGTH_SIZE };
+        if data.len() < 1 + VERSION_SIZE + size_of::<Depth>() + value_length_size {
             return Err(TreeError::MalformedNode.into());
         }
 
         self.clean = true;
+        self.marker = marker;
 
         let mut pos = 1;
         self.version
@@ -202,11 +230,16 @@ impl Marshal for LeafNode {
         self.key = Key::new();
         let key_len = self.key.unmarshal_binary(&data[pos..])?;
         pos += key_len;
-        if pos + VALUE_LENGTH_SIZE > data.len() {
+        if pos + value_length_size > data.len() {
             return Err(TreeError::MalformedNode.into());
         }
 
         self.value = Value::new();
+        if marker {
+            self.update_hash();
+            return Ok(pos);
+        }
+
         let mut value_len = 0u32;
         value_len.unmarshal_binary(&data[pos..(pos + VALUE_LENGTH_SIZE)])?;
         pos += VALUE_LENGTH_SIZE;
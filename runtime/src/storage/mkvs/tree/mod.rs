@@ -2,7 +2,11 @@
 mod macros;
 
 mod commit;
+mod diff;
 mod errors;
+mod fold;
+mod frozen;
+mod hasher;
 mod insert;
 mod iterator;
 mod lookup;
@@ -11,15 +15,23 @@ mod mkvs;
 mod node;
 mod prefetch;
 mod remove;
+mod stats;
 mod tree;
+mod watch;
 
 pub use commit::*;
+pub use diff::*;
 pub use errors::*;
+pub use fold::*;
+pub use frozen::*;
+pub use hasher::*;
 pub use insert::*;
 pub use iterator::*;
 pub use node::*;
 pub use remove::*;
+pub use stats::*;
 pub use tree::*;
+pub use watch::*;
 
 #[cfg(test)]
 mod node_test;
@@ -4,7 +4,11 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     common::{crypto::hash::Hash, roothash::Namespace},
-    storage::mkvs::{cache::*, marshal::*},
+    storage::mkvs::{
+        cache::*,
+        marshal::*,
+        tree::{DefaultNodeHasher, NodeHasher},
+    },
 };
 
 /// Common interface for node-like objects in the tree.
@@ -13,14 +17,18 @@ pub trait Node {
     fn is_clean(&self) -> bool;
     /// Get the node's hash.
     fn get_hash(&self) -> Hash;
-    /// Recompute the node's hash.
-    fn update_hash(&mut self);
+    /// Recompute the node's hash using the default hasher.
+    fn update_hash(&mut self) {
+        self.update_hash_with(&DefaultNodeHasher)
+    }
+    /// Recompute the node's hash using the given hasher.
+    fn update_hash_with(&mut self, hasher: &dyn NodeHasher);
     /// Duplicate the node but include only hash references.
     fn extract(&self) -> NodeRef;
 }
 
 /// Storage root.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Root {
     /// Namespace under which the root is stored.
     #[serde(rename = "ns")]
@@ -59,10 +67,10 @@ impl Node for NodeBox {
         }
     }
 
-    fn update_hash(&mut self) {
+    fn update_hash_with(&mut self, hasher: &dyn NodeHasher) {
         match self {
-            NodeBox::Internal(ref mut n) => n.update_hash(),
-            NodeBox::Leaf(ref mut n) => n.update_hash(),
+            NodeBox::Internal(ref mut n) => n.update_hash_with(hasher),
+            NodeBox::Leaf(ref mut n) => n.update_hash_with(hasher),
         }
     }
 
@@ -86,6 +94,11 @@ pub enum NodeKind {
     Leaf = 0x00,
 }
 
+/// Flag set in the kind byte of the encoding (and of the hashed data) of
+/// presence marker leaf nodes, distinguishing them from leaf nodes with an
+/// empty value.
+pub const LEAF_MARKER_FLAG: u8 = 0x80;
+
 /// `NodeRef` is a reference-counted pointer to a node box.
 pub type NodeRef = Rc<RefCell<NodeBox>>;
 
@@ -133,6 +146,13 @@ impl NodePointer {
         }))
     }
 
+    /// Mark the pointer as dirty after the node behind it (or any node below
+    /// it) has been modified, discarding any previously computed hash.
+    pub fn mark_dirty(&mut self) {
+        self.clean = false;
+        self.hash = Hash::default();
+    }
+
     /// Check if the pointer is a null pointer.
     pub fn is_null(&self) -> bool {
         self.hash.is_empty()
@@ -236,12 +256,12 @@ impl Node for InternalNode {
         self.hash
     }
 
-    fn update_hash(&mut self) {
+    fn update_hash_with(&mut self, hasher: &dyn NodeHasher) {
         let leaf_node_hash = self.leaf_node.borrow().hash;
         let left_hash = self.left.borrow().hash;
         let right_hash = self.right.borrow().hash;
 
-        self.hash = Hash::digest_bytes_list(&[
+        self.hash = hasher.digest_bytes_list(&[
             &[NodeKind::Internal as u8],
             &self.version.marshal_binary().unwrap(),
             &self.label_bit_length.marshal_binary().unwrap(),
@@ -292,6 +312,9 @@ pub struct LeafNode {
     pub hash: Hash,
     pub key: Key,
     pub value: Value,
+    /// Whether the leaf is a presence marker without a value, in which case
+    /// the value is always empty.
+    pub marker: bool,
 }
 
 impl LeafNode {
@@ -302,10 +325,20 @@ impl LeafNode {
             hash: self.hash.clone(),
             key: self.key.to_owned(),
             value: self.value.clone(),
+            marker: self.marker,
         };
 
         return node;
     }
+
+    /// Return the kind byte used in the encoding and hash of the node.
+    pub(super) fn kind_byte(&self) -> u8 {
+        if self.marker {
+            NodeKind::Leaf as u8 | LEAF_MARKER_FLAG
+        } else {
+            NodeKind::Leaf as u8
+        }
+    }
 }
 
 impl Node for LeafNode {
@@ -317,9 +350,9 @@ impl Node for LeafNode {
         self.hash
     }
 
-    fn update_hash(&mut self) {
-        self.hash = Hash::digest_bytes_list(&[
-            &[NodeKind::Leaf as u8],
+    fn update_hash_with(&mut self, hasher: &dyn NodeHasher) {
+        self.hash = hasher.digest_bytes_list(&[
+            &[self.kind_byte()],
             &self.version.marshal_binary().unwrap(),
             self.key.as_ref(),
             self.value.as_ref(),
@@ -336,6 +369,7 @@ impl Node for LeafNode {
             hash: self.hash,
             key: self.key.clone(),
             value: self.value.clone(),
+            marker: self.marker,
         })))
     }
 }
@@ -345,7 +379,10 @@ impl PartialEq for LeafNode {
         if self.clean && other.clean {
             self.hash == other.hash
         } else {
-            self.version == other.version && self.key == other.key && self.value == other.value
+            self.version == other.version
+                && self.key == other.key
+                && self.value == other.value
+                && self.marker == other.marker
         }
     }
 }
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, rc::Rc, str::FromStr};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, str::FromStr};
 
 use crate::{
     common::crypto::hash::Hash,
@@ -27,6 +27,42 @@ fn test_serialization_leaf() {
     assert_eq!(leaf_node.value, decoded_leaf_node.value);
 }
 
+#[test]
+fn test_serialization_marker_leaf() {
+    let mut leaf_node = LeafNode {
+        key: b"a golden key".to_vec(),
+        marker: true,
+        ..Default::default()
+    };
+    leaf_node.update_hash();
+
+    let marshaled = leaf_node.marshal_binary().expect("marshal");
+
+    let mut decoded = NodeBox::default();
+    decoded
+        .unmarshal_binary(marshaled.as_slice())
+        .expect("unmarshal");
+    let decoded_leaf_node = match decoded {
+        NodeBox::Leaf(n) => n,
+        _ => panic!("expected a leaf node"),
+    };
+    assert_eq!(true, decoded_leaf_node.marker);
+    assert_eq!(leaf_node.key, decoded_leaf_node.key);
+    assert_eq!(leaf_node.hash, decoded_leaf_node.hash);
+
+    // A leaf with an empty value must be distinct from a marker.
+    let mut empty_leaf_node = LeafNode {
+        key: b"a golden key".to_vec(),
+        ..Default::default()
+    };
+    empty_leaf_node.update_hash();
+    assert_ne!(leaf_node.hash, empty_leaf_node.hash);
+    assert_ne!(
+        empty_leaf_node.marshal_binary().expect("marshal"),
+        marshaled
+    );
+}
+
 #[test]
 fn test_serialization_internal() {
     let mut leaf_node = LeafNode {
@@ -111,6 +147,22 @@ fn test_hash_leaf() {
     );
 }
 
+#[test]
+fn test_hash_marker_leaf() {
+    let mut leaf_node = LeafNode {
+        version: 0xDEADBEEF,
+        key: b"a golden key".to_vec(),
+        marker: true,
+        ..Default::default()
+    };
+
+    leaf_node.update_hash();
+    assert_eq!(
+        leaf_node.hash,
+        Hash::from_str("5c42cd1602f08107c2438f5579ff7d308ed391161b33d0ffc122d776286b7374").unwrap()
+    );
+}
+
 #[test]
 fn test_hash_internal() {
     let leaf_node_hash = Hash::digest_bytes(b"everyone stop here");
@@ -260,3 +312,22 @@ fn test_key_common_prefix_len() {
     assert_eq!(12, key.common_prefix_len(13, &vec![0xab, 0xcd], 12));
     assert_eq!(12, key.common_prefix_len(12, &vec![0xab, 0xcd], 13));
 }
+
+#[test]
+fn test_root_as_map_key() {
+    let root = Root {
+        version: 1,
+        hash: Hash::empty_hash(),
+        ..Default::default()
+    };
+    let other_version = Root { version: 2, ..root };
+
+    let mut roots = HashMap::new();
+    roots.insert(root, "first");
+    roots.insert(other_version, "second");
+    roots.insert(root, "third");
+
+    assert_eq!(roots.len(), 2);
+    assert_eq!(roots.get(&root), Some(&"third"));
+    assert_eq!(roots.get(&other_version), Some(&"second"));
+}
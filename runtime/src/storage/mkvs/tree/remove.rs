@@ -30,12 +30,14 @@ impl Tree {
                     PendingLogEntry {
                         key: boxed_key,
                         value: None,
+                        marker: false,
                         existed: changed,
                     },
                 );
             }
             Some(ref mut entry) => {
                 entry.value = None;
+                entry.marker = false;
             }
         };
         self.cache.borrow_mut().set_pending_root(new_root);
@@ -177,7 +179,7 @@ impl Tree {
                                             inode.label_bit_length +=
                                                 noderef_as!(node_ref, Internal).label_bit_length;
                                             inode.clean = false;
-                                            node_ptr.borrow_mut().clean = false;
+                                            node_ptr.borrow_mut().mark_dirty();
                                         }
                                     }
                                     _ => (),
@@ -194,7 +196,7 @@ impl Tree {
                 // Two or more children including leaf_node remain, just mark dirty bit.
                 if changed {
                     noderef_as_mut!(node_ref, Internal).clean = false;
-                    ptr.borrow_mut().clean = false;
+                    ptr.borrow_mut().mark_dirty();
                     // No longer eligible for eviction as it is dirty.
                     self.cache
                         .borrow_mut()
@@ -0,0 +1,307 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use io_context::Context;
+
+use crate::storage::mkvs::{cache::*, tree::*};
+
+use super::iterator::FetcherSyncIterate;
+
+/// Statistics about the structure of a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// Total number of nodes, including leaf nodes attached to internal nodes.
+    pub node_count: usize,
+    /// Number of leaf nodes.
+    pub leaf_count: usize,
+    /// Maximum depth of a leaf node, counted in internal nodes on the path
+    /// from the root.
+    pub max_depth: usize,
+    /// Average depth of a leaf node.
+    pub avg_depth: f64,
+    /// Number of internal nodes by their number of non-nil children (out of
+    /// the leaf node and the left and right subtrees).
+    pub fanout: BTreeMap<usize, usize>,
+}
+
+/// Storage size of the subtree containing all keys with a given prefix.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SubtreeSize {
+    /// Total size, in bytes, of the values in the subtree.
+    pub value_bytes: usize,
+    /// Number of nodes in the subtree, including leaf nodes attached to
+    /// internal nodes.
+    pub node_count: usize,
+}
+
+/// Statistics about the in-memory node cache of a tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Number of node dereferences served from memory.
+    pub hits: u64,
+    /// Number of node dereferences that required a fetch via the read syncer.
+    pub misses: u64,
+    /// Number of nodes currently held in the cache.
+    pub node_count: usize,
+    /// Total size, in bytes, of the values of the leaf nodes currently held
+    /// in the cache.
+    pub value_bytes: usize,
+}
+
+impl CacheStats {
+    /// Return the fraction of node dereferences served from memory, or
+    /// `None` if there were no dereferences yet.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return None;
+        }
+        Some(self.hits as f64 / total as f64)
+    }
+}
+
+impl Tree {
+    /// Return statistics about the in-memory node cache.
+    ///
+    /// Hit and miss counts are kept for the lifetime of the tree.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    /// Collect statistics about the structure of the tree.
+    ///
+    /// This walks the whole tree, fetching any missing nodes via the read
+    /// syncer, so it is O(n) in the number of nodes and meant for offline
+    /// analysis rather than for use on hot paths.
+    pub fn structural_stats(&self, ctx: Context) -> Result<TreeStats> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        let mut stats = TreeStats::default();
+        let mut total_depth = 0;
+        self._structural_stats(
+            &ctx,
+            pending_root,
+            0,
+            Key::new(),
+            0,
+            &mut stats,
+            &mut total_depth,
+        )?;
+        if stats.leaf_count > 0 {
+            stats.avg_depth = total_depth as f64 / stats.leaf_count as f64;
+        }
+        Ok(stats)
+    }
+
+    /// Return the total size of the values and the number of nodes in the
+    /// smallest subtree containing all keys starting with the given prefix,
+    /// e.g., for per-namespace storage accounting. If there are no such keys,
+    /// the size is zero.
+    ///
+    /// Uncommitted modifications are taken into account. This walks the
+    /// whole subtree, fetching any missing nodes via the read syncer, so it
+    /// is O(n) in the size of the subtree.
+    pub fn subtree_size(&self, ctx: Context, prefix: &[u8]) -> Result<SubtreeSize> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        let mut size = SubtreeSize::default();
+        let prefix = prefix.to_vec();
+        if let Some((ptr, bit_depth, path)) =
+            self._get_prefix_subtree(&ctx, pending_root, 0, Key::new(), &prefix)?
+        {
+            self._subtree_size(&ctx, ptr, bit_depth, path, &mut size)?;
+        }
+        Ok(size)
+    }
+
+    fn _subtree_size(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        size: &mut SubtreeSize,
+    ) -> Result<()> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+        let node_ref = match node_ref {
+            Some(node_ref) => node_ref,
+            None => return Ok(()),
+        };
+
+        size.node_count += 1;
+        let node = node_ref.borrow();
+        match *node {
+            NodeBox::Internal(ref n) => {
+                let bit_length = bit_depth + n.label_bit_length;
+                let new_path = path.merge(bit_depth, &n.label, n.label_bit_length);
+
+                self._subtree_size(ctx, n.leaf_node.clone(), bit_length, path, size)?;
+                self._subtree_size(
+                    ctx,
+                    n.left.clone(),
+                    bit_length,
+                    new_path.append_bit(bit_length, false),
+                    size,
+                )?;
+                self._subtree_size(
+                    ctx,
+                    n.right.clone(),
+                    bit_length,
+                    new_path.append_bit(bit_length, true),
+                    size,
+                )?;
+            }
+            NodeBox::Leaf(ref n) => size.value_bytes += n.value.len(),
+        }
+        Ok(())
+    }
+
+    /// Return the maximum depth of a leaf node, counted in internal nodes on
+    /// the path from the root as in `TreeStats::max_depth`.
+    ///
+    /// This walks the whole tree, fetching any missing nodes via the read
+    /// syncer, so it is O(n) in the number of nodes.
+    pub fn max_path_depth(&self, ctx: Context) -> Result<usize> {
+        self.max_path_depth_with(ctx, false)
+    }
+
+    /// Return the maximum depth of a leaf node, like `max_path_depth`, but
+    /// only considering nodes held in the local cache and never fetching any.
+    ///
+    /// The result is a lower bound, which is cheap to compute right after a
+    /// commit when the paths modified in the last version are cached.
+    pub fn cached_max_path_depth(&self, ctx: Context) -> Result<usize> {
+        self.max_path_depth_with(ctx, true)
+    }
+
+    fn max_path_depth_with(&self, ctx: Context, cached_only: bool) -> Result<usize> {
+        let ctx = ctx.freeze();
+        let pending_root = self.cache.borrow().get_pending_root();
+
+        let depth = self._max_path_depth(&ctx, pending_root, 0, Key::new(), cached_only)?;
+        Ok(depth.unwrap_or(0))
+    }
+
+    /// Return the maximum depth of a leaf node in the subtree behind the
+    /// given pointer, relative to it, or `None` if there are no leaf nodes.
+    fn _max_path_depth(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        cached_only: bool,
+    ) -> Result<Option<usize>> {
+        if cached_only && ptr.borrow().node.is_none() {
+            return Ok(None);
+        }
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(None),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (leaf_node, left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.leaf_node.clone(),
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                let mut max_depth = None;
+                for (ptr, path) in vec![
+                    (leaf_node, path),
+                    (left, new_path.append_bit(bit_length, false)),
+                    (right, new_path.append_bit(bit_length, true)),
+                ] {
+                    let depth = self._max_path_depth(ctx, ptr, bit_length, path, cached_only)?;
+                    max_depth = max_depth.max(depth.map(|depth| depth + 1));
+                }
+                Ok(max_depth)
+            }
+            NodeKind::Leaf => Ok(Some(0)),
+        }
+    }
+
+    /// Visit the node behind the given pointer and return whether it exists.
+    fn _structural_stats(
+        &self,
+        ctx: &Arc<Context>,
+        ptr: NodePtrRef,
+        bit_depth: Depth,
+        path: Key,
+        depth: usize,
+        stats: &mut TreeStats,
+        total_depth: &mut usize,
+    ) -> Result<bool> {
+        let node_ref = self.cache.borrow_mut().deref_node_ptr(
+            ctx,
+            ptr,
+            Some(FetcherSyncIterate::new(&path, 0)),
+        )?;
+
+        match classify_noderef!(?node_ref) {
+            NodeKind::None => Ok(false),
+            NodeKind::Internal => {
+                let node_ref = node_ref.unwrap();
+                let (leaf_node, left, right, bit_length, new_path) =
+                    if let NodeBox::Internal(ref n) = *node_ref.borrow() {
+                        (
+                            n.leaf_node.clone(),
+                            n.left.clone(),
+                            n.right.clone(),
+                            bit_depth + n.label_bit_length,
+                            path.merge(bit_depth, &n.label, n.label_bit_length),
+                        )
+                    } else {
+                        unreachable!("node kind is internal node");
+                    };
+
+                stats.node_count += 1;
+                let mut children = 0;
+                for (ptr, path) in vec![
+                    (leaf_node, path),
+                    (left, new_path.append_bit(bit_length, false)),
+                    (right, new_path.append_bit(bit_length, true)),
+                ] {
+                    if self._structural_stats(
+                        ctx,
+                        ptr,
+                        bit_length,
+                        path,
+                        depth + 1,
+                        stats,
+                        total_depth,
+                    )? {
+                        children += 1;
+                    }
+                }
+                *stats.fanout.entry(children).or_insert(0) += 1;
+                Ok(true)
+            }
+            NodeKind::Leaf => {
+                stats.node_count += 1;
+                stats.leaf_count += 1;
+                stats.max_depth = stats.max_depth.max(depth);
+                *total_depth += depth;
+                Ok(true)
+            }
+        }
+    }
+}
@@ -1,16 +1,25 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::BTreeMap,
-    fmt,
+    fmt, mem,
     rc::Rc,
     sync::{Arc, Mutex},
 };
 
-use crate::storage::mkvs::{cache::*, sync::*, tree::*};
+use anyhow::Result;
+use io_context::Context;
+
+use crate::{
+    common::crypto::hash::Hash,
+    storage::mkvs::{cache::*, sync::*, tree::*, LogEntry, WriteLog},
+};
+
+use super::iterator::FetcherSyncIterate;
 
 pub struct PendingLogEntry {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
+    pub marker: bool,
     pub existed: bool,
 }
 
@@ -19,6 +28,10 @@ pub struct Options {
     node_capacity: usize,
     value_capacity: usize,
     root: Option<Root>,
+    prewarm: Vec<(Hash, NodeBox)>,
+    max_value_size: Option<usize>,
+    monotonic_versions: bool,
+    hasher: Arc<dyn NodeHasher>,
 }
 
 impl Options {
@@ -43,9 +56,60 @@ impl Options {
         self
     }
 
+    /// Set a collection of clean nodes (e.g., obtained from a tree for a
+    /// previous root via `Tree::clean_cached_nodes`) that the new tree may
+    /// use instead of fetching them via the read syncer.
+    ///
+    /// Each node is only used after it has been verified to match its hash.
+    pub fn with_prewarm(mut self, nodes: Vec<(Hash, NodeBox)>) -> Self {
+        self.prewarm = nodes;
+        self
+    }
+
+    /// Set the maximum size, in bytes, of values that can be inserted into
+    /// the tree. Inserting a larger value fails with `TreeError::ValueTooLarge`.
+    ///
+    /// If left unspecified, value sizes are not limited.
+    pub fn with_max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// Require each commit to use a version strictly greater than the version
+    /// of the current root. Committing with a lower or equal version fails
+    /// with `TreeError::NonMonotonicVersion`.
+    ///
+    /// The check only applies once the tree has a root, either set via
+    /// `with_root` or by a previous commit.
+    pub fn with_monotonic_versions(mut self) -> Self {
+        self.monotonic_versions = true;
+        self
+    }
+
+    /// Set the hasher used to compute node hashes. If left unspecified, the
+    /// default SHA-512/256 based hasher is used.
+    ///
+    /// Trees using different hashers produce different roots for the same
+    /// contents. As the host storage (and with it the read sync protocol)
+    /// only supports the default hasher, any other hasher is only usable
+    /// for trees that are not backed by host storage, i.e. trees using a
+    /// `NoopReadSyncer`.
+    pub fn with_hasher(mut self, hasher: Box<dyn NodeHasher>) -> Self {
+        self.hasher = Arc::from(hasher);
+        self
+    }
+
     /// Commit the options set so far into a newly constructed tree instance.
-    pub fn new(self, read_syncer: Box<dyn ReadSync>) -> Tree {
-        Tree::new(read_syncer, &self)
+    ///
+    /// # Panics
+    ///
+    /// Panics if a non-default hasher is used with any read syncer other
+    /// than `NoopReadSyncer`.
+    pub fn new(mut self, read_syncer: Box<dyn ReadSync>) -> Tree {
+        let prewarm = mem::take(&mut self.prewarm);
+        let tree = Tree::new(read_syncer, &self);
+        tree.cache.borrow_mut().add_prewarm_nodes(prewarm);
+        tree
     }
 }
 
@@ -54,19 +118,40 @@ pub struct Tree {
     pub(crate) cache: RefCell<Box<LRUCache>>,
     pub(crate) pending_write_log: BTreeMap<Key, PendingLogEntry>,
     pub(crate) lock: Arc<Mutex<isize>>,
+    pub(crate) max_value_size: Option<usize>,
+    pub(crate) monotonic_versions: bool,
+    pub(crate) root_watchers: RootWatchers,
+    pub(crate) preview_version: Cell<Option<u64>>,
 }
 
 impl Tree {
     /// Construct a new tree instance using the given read syncer and options struct.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a non-default hasher is used with any read syncer other
+    /// than `NoopReadSyncer`.
     pub fn new(read_syncer: Box<dyn ReadSync>, opts: &Options) -> Tree {
+        // Proofs fetched via the read syncer are always verified using the
+        // default hasher.
+        let hasher_name = opts.hasher.name();
+        if hasher_name != DefaultNodeHasher.name() && !read_syncer.as_any().is::<NoopReadSyncer>() {
+            panic!("mkvs: hasher {:?} requires a NoopReadSyncer", hasher_name);
+        }
+
         let tree = Tree {
             cache: RefCell::new(LRUCache::new(
                 opts.node_capacity,
                 opts.value_capacity,
                 read_syncer,
+                opts.hasher.clone(),
             )),
             pending_write_log: BTreeMap::new(),
             lock: Arc::new(Mutex::new(0)),
+            max_value_size: opts.max_value_size,
+            monotonic_versions: opts.monotonic_versions,
+            root_watchers: RootWatchers::new(),
+            preview_version: Cell::new(None),
         };
 
         if let Some(root) = opts.root {
@@ -89,6 +174,205 @@ impl Tree {
             node_capacity: 50_000,
             value_capacity: 16 * 1024 * 1024,
             root: None,
+            prewarm: Vec::new(),
+            max_value_size: None,
+            monotonic_versions: false,
+            hasher: Arc::new(DefaultNodeHasher),
+        }
+    }
+
+    /// Construct a new empty tree that is purely local, i.e. not backed by
+    /// any host storage. Use `Tree::make` for more advanced configurations.
+    pub fn new_in_memory() -> Tree {
+        Tree::make().new(Box::new(NoopReadSyncer))
+    }
+
+    /// Subscribe to root changes. The returned receiver receives the new
+    /// root after each commit, keeping at most `ROOT_WATCH_CAPACITY` roots.
+    ///
+    /// Dropping the receiver unsubscribes.
+    pub fn watch_root(&mut self) -> RootReceiver {
+        self.root_watchers.subscribe()
+    }
+
+    /// Discard all uncommitted modifications, going back to the last
+    /// committed root (or the root the tree was created with).
+    ///
+    /// As modifications update nodes in place, any nodes on modified paths
+    /// will be fetched again via the read syncer.
+    pub fn clear_pending(&mut self) {
+        self.pending_write_log.clear();
+        self.cache.borrow_mut().reset();
+    }
+
+    /// Switch the tree to another committed root, discarding any uncommitted
+    /// modifications.
+    ///
+    /// Clean cached nodes are kept, within the capacity of the cache, and
+    /// used for the new root where they match, so subtrees shared with the
+    /// previous root are not fetched again. Nodes of the new root are only
+    /// fetched once they are needed.
+    pub fn switch_root(&mut self, root: Root) {
+        self.pending_write_log.clear();
+        let mut cache = self.cache.borrow_mut();
+        cache.set_sync_root(root);
+        cache.reset();
+    }
+
+    /// Switch the tree to a committed root computed elsewhere (e.g., during a
+    /// state migration), discarding any uncommitted modifications.
+    ///
+    /// The root node of the new root is fetched via the read syncer to make
+    /// sure that the root is reachable. If it is not, the tree goes back to
+    /// its previous committed root. Clean cached nodes are kept and only used
+    /// for the new root if they match.
+    pub fn adopt_root(&mut self, ctx: Context, root: Root) -> Result<()> {
+        let ctx = ctx.freeze();
+        self.pending_write_log.clear();
+
+        let mut cache = self.cache.borrow_mut();
+        let previous_root = cache.get_sync_root();
+        cache.set_sync_root(root);
+        cache.reset();
+
+        let pending_root = cache.get_pending_root();
+        let result = cache.deref_node_ptr(
+            &ctx,
+            pending_root,
+            Some(FetcherSyncIterate::new(&Key::new(), 0)),
+        );
+        if let Err(error) = result {
+            cache.set_sync_root(previous_root);
+            cache.reset();
+            return Err(error.context("mkvs: new root is not reachable"));
+        }
+        Ok(())
+    }
+
+    /// Take all uncommitted modifications out of the tree, returning them as
+    /// write log entries in key order. The tree goes back to the last
+    /// committed root, as with `clear_pending`.
+    ///
+    /// The returned entries can be replayed onto another tree with the same
+    /// committed root via `apply_pending_entries`, e.g., to move writes made
+    /// on a speculative snapshot onto the canonical tree.
+    pub fn take_pending(&mut self) -> WriteLog {
+        let entries = mem::take(&mut self.pending_write_log)
+            .into_iter()
+            .map(|(_, entry)| LogEntry {
+                key: entry.key,
+                value: entry.value,
+                marker: entry.marker,
+            })
+            .collect();
+        self.clear_pending();
+        entries
+    }
+
+    /// Apply write log entries (e.g., as returned by `take_pending`) to the
+    /// tree as uncommitted modifications.
+    pub fn apply_pending_entries(&mut self, ctx: Context, entries: WriteLog) -> Result<()> {
+        let ctx = ctx.freeze();
+        for entry in entries {
+            match entry.value {
+                Some(_) if entry.marker => {
+                    self.insert_marker(Context::create_child(&ctx), &entry.key)?;
+                }
+                Some(value) => {
+                    self.insert(Context::create_child(&ctx), &entry.key, &value)?;
+                }
+                None => {
+                    self.remove(Context::create_child(&ctx), &entry.key)?;
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Check that the uncommitted modifications recorded in the pending write
+    /// log match the dirty nodes of the tree, panicking otherwise.
+    ///
+    /// Every pending write must be reflected in the tree (possibly by a clean
+    /// leaf, in case the same value was written again) and every reachable
+    /// dirty leaf node must have a matching pending write. This walks all
+    /// dirty paths and looks up each pending key, so it is only available in
+    /// debug builds and meant for use in tests after sequences of writes.
+    #[cfg(debug_assertions)]
+    pub fn assert_pending_consistent(&self) {
+        let ctx = Context::background().freeze();
+
+        for (key, entry) in self.pending_write_log.iter() {
+            assert_eq!(*key, entry.key, "mkvs: pending write log key mismatch");
+            // Look the key up in the tree itself, bypassing the write log.
+            let pending_root = self.cache.borrow().get_pending_root();
+            self.cache.borrow_mut().mark_position();
+            let found = self
+                ._get(&ctx, pending_root, 0, key, 0, false, None)
+                .expect("mkvs: lookup of pending key failed");
+            let expected = entry.value.clone().map(|value| (value, entry.marker));
+            assert_eq!(
+                found, expected,
+                "mkvs: pending write of {:?} does not match the tree",
+                key
+            );
+        }
+
+        let pending_root = self.cache.borrow().get_pending_root();
+        let mut dirty_leaves = Vec::new();
+        collect_dirty_leaves(&pending_root, &mut dirty_leaves);
+        for (key, value, marker) in dirty_leaves {
+            match self.pending_write_log.get(&key) {
+                Some(PendingLogEntry {
+                    value: Some(ref expected),
+                    marker: expected_marker,
+                    ..
+                }) => assert!(
+                    *expected == value && *expected_marker == marker,
+                    "mkvs: dirty leaf {:?} does not match its pending write",
+                    key
+                ),
+                _ => panic!("mkvs: dirty leaf {:?} has no pending write", key),
+            }
+        }
+    }
+
+    /// Return the name of the hasher used to compute node hashes.
+    pub fn hasher_name(&self) -> &'static str {
+        self.cache.borrow().hasher().name()
+    }
+
+    /// Return detached copies of all clean nodes currently held in the
+    /// cache, suitable for passing to `Options::with_prewarm`.
+    pub fn clean_cached_nodes(&self) -> Vec<(Hash, NodeBox)> {
+        self.cache.borrow().clean_nodes()
+    }
+}
+
+/// Collect the keys, values and marker flags of all dirty leaf nodes held in
+/// memory in the subtree behind the given pointer.
+#[cfg(debug_assertions)]
+fn collect_dirty_leaves(ptr: &NodePtrRef, leaves: &mut Vec<(Key, Value, bool)>) {
+    // Dirty nodes are never evicted, so a clean pointer or a pointer without
+    // a node cannot lead to any dirty nodes.
+    let ptr = ptr.borrow();
+    if ptr.clean {
+        return;
+    }
+    let node_ref = match ptr.node {
+        Some(ref node_ref) => node_ref.clone(),
+        None => return,
+    };
+    let node = node_ref.borrow();
+    match *node {
+        NodeBox::Internal(ref n) => {
+            for child in &[&n.leaf_node, &n.left, &n.right] {
+                collect_dirty_leaves(child, leaves);
+            }
+        }
+        NodeBox::Leaf(ref n) => {
+            if !n.clean {
+                leaves.push((n.key.clone(), n.value.clone(), n.marker));
+            }
         }
     }
 }
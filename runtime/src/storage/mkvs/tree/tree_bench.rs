@@ -2,7 +2,7 @@ extern crate test;
 
 use io_context::Context;
 
-use crate::storage::mkvs::{sync::*, tree::*};
+use crate::storage::mkvs::{cache::*, tree::*};
 
 use self::test::Bencher;
 
@@ -19,7 +19,7 @@ fn gen_pairs() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
 }
 
 fn gen_tree() -> (Tree, Vec<Vec<u8>>) {
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     let (keys, vals) = gen_pairs();
     for i in 0..keys.len() {
@@ -53,7 +53,7 @@ fn bench_existing_scan(b: &mut Bencher) {
 #[bench]
 fn bench_single_inserts(b: &mut Bencher) {
     let (keys, vals) = gen_pairs();
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     let mut i = 0;
     b.iter(|| {
@@ -72,7 +72,7 @@ fn bench_insert(b: &mut Bencher) {
     let (keys, vals) = gen_pairs();
 
     b.iter(|| {
-        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut tree = Tree::new_in_memory();
 
         for i in 0..keys.len() {
             tree.insert(Context::background(), keys[i].as_ref(), vals[i].as_ref())
@@ -83,9 +83,45 @@ fn bench_insert(b: &mut Bencher) {
     });
 }
 
+fn bench_commit_single_key(b: &mut Bencher, general: bool) {
+    let (mut tree, keys) = gen_tree();
+    tree.commit(Context::background(), Default::default(), 0)
+        .expect("commit");
+
+    let mut i = 0;
+    b.iter(|| {
+        tree.insert(Context::background(), keys[i % keys.len()].as_ref(), b"x")
+            .expect("insert");
+        i += 1;
+        if general {
+            let ctx = Context::background().freeze();
+            let mut update_list: UpdateList<LRUCache> = UpdateList::new();
+            let pending_root = tree.cache.borrow().get_pending_root();
+            let hasher = tree.cache.borrow().hasher();
+            super::commit::_commit_subtree(&ctx, pending_root, &mut update_list, Some(0), &*hasher)
+                .expect("commit");
+            update_list.commit(&mut tree.cache.borrow_mut());
+            tree.pending_write_log.clear();
+        } else {
+            tree.commit(Context::background(), Default::default(), 0)
+                .expect("commit");
+        }
+    });
+}
+
+#[bench]
+fn bench_commit_single_key_fast_path(b: &mut Bencher) {
+    bench_commit_single_key(b, false)
+}
+
+#[bench]
+fn bench_commit_single_key_general(b: &mut Bencher) {
+    bench_commit_single_key(b, true)
+}
+
 fn bench_insert_batch(b: &mut Bencher, num_values: usize, commit: bool) {
     b.iter(|| {
-        let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+        let mut tree = Tree::new_in_memory();
         for i in 0..num_values {
             let key = format!("key {}", i);
             let value = format!("value {}", i);
@@ -1,12 +1,18 @@
+use anyhow::Result;
 use io_context::Context;
 use serde_json;
-use std::{collections::HashSet, fs::File, io::BufReader, iter::FromIterator, path::Path};
+use std::{
+    any::Any, collections::HashSet, fs::File, io::BufReader, iter::FromIterator, path::Path,
+    time::Instant,
+};
 
 use crate::{
+    cancellation,
     common::crypto::hash::Hash,
     storage::mkvs::{
         cache::*,
         interop::{Driver, ProtocolServer},
+        marshal::Marshal,
         sync::*,
         tests,
         tree::*,
@@ -51,7 +57,7 @@ fn generate_long_key_value_pairs() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
 
 #[test]
 fn test_basic() {
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     let key_zero = b"foo";
     let value_zero = b"bar";
@@ -112,6 +118,7 @@ fn test_basic() {
         [LogEntry {
             key: key_zero.to_vec(),
             value: Some(value_zero.to_vec()),
+            marker: false,
         }]
         .to_vec()
     );
@@ -243,10 +250,12 @@ fn test_basic() {
                 LogEntry {
                     key: key_one.to_vec(),
                     value: Some(value_one.to_vec()),
+                    marker: false,
                 },
                 LogEntry {
                     key: key_zero.to_vec(),
                     value: Some(value_zero.to_vec()),
+                    marker: false,
                 }
             ]
             .to_vec()
@@ -275,6 +284,7 @@ fn test_basic() {
         [LogEntry {
             key: key_one.to_vec(),
             value: None,
+            marker: false,
         }]
         .to_vec()
     );
@@ -293,7 +303,7 @@ fn test_basic() {
 
 #[test]
 fn test_long_keys() {
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     // First insert keys 0..n and remove them in order n..0.
     let mut roots: Vec<Hash> = Vec::new();
@@ -354,7 +364,7 @@ fn test_long_keys() {
 
 #[test]
 fn test_empty_keys() {
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     fn test_empty_key(tree: &mut Tree) {
         let empty_key = b"";
@@ -480,7 +490,7 @@ fn test_empty_keys() {
 
 #[test]
 fn test_insert_commit_batch() {
-    let mut tree = Tree::make().new(Box::new(NoopReadSyncer));
+    let mut tree = Tree::new_in_memory();
 
     let (keys, values) = generate_key_value_pairs();
     for i in 0..keys.len() {
@@ -911,6 +921,2297 @@ fn test_syncer_prefetch_prefixes() {
     assert_eq!(0, stats.sync_iterate_count, "sync_iterate count");
 }
 
+#[test]
+fn test_len() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    assert_eq!(tree.len(Context::background()).expect("len"), 0);
+    assert!(tree.is_empty(Context::background()).expect("is_empty"));
+
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    assert_eq!(tree.len(Context::background()).expect("len"), keys.len());
+    assert!(!tree.is_empty(Context::background()).expect("is_empty"));
+
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    assert_eq!(tree.len(Context::background()).expect("len"), keys.len());
+
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    // A remote tree must fetch the nodes via the syncer to count them.
+    let remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    assert!(!remote_tree
+        .is_empty(Context::background())
+        .expect("is_empty"));
+    assert_eq!(
+        remote_tree.len(Context::background()).expect("len"),
+        keys.len()
+    );
+
+    // Removing all keys should make the tree empty again.
+    for key in keys.iter() {
+        tree.remove(Context::background(), key.as_slice())
+            .expect("remove");
+    }
+    assert_eq!(tree.len(Context::background()).expect("len"), 0);
+    assert!(tree.is_empty(Context::background()).expect("is_empty"));
+}
+
+#[test]
+fn test_prewarm() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    let root = Root {
+        hash,
+        ..Default::default()
+    };
+
+    // Create a tree that is pre-warmed with all nodes from the original tree.
+    let stats = StatsCollector::new(server.read_sync());
+    let remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(root)
+        .with_prewarm(tree.clean_cached_nodes())
+        .new(Box::new(stats));
+
+    for i in 0..keys.len() {
+        let value = remote_tree
+            .get(Context::background(), keys[i].as_slice())
+            .expect("get")
+            .expect("get_some");
+        assert_eq!(values[i], value.as_slice());
+    }
+
+    {
+        let cache = remote_tree.cache.borrow();
+        let stats = cache
+            .get_read_syncer()
+            .as_any()
+            .downcast_ref::<StatsCollector>()
+            .expect("stats");
+        assert_eq!(0, stats.sync_get_count, "sync_get count");
+    }
+
+    // Nodes that do not match their hashes must not be used.
+    let mut prewarm = tree.clean_cached_nodes();
+    for (_, node) in prewarm.iter_mut() {
+        if let NodeBox::Leaf(ref mut n) = node {
+            n.value = b"tampered".to_vec();
+        }
+    }
+    let remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(root)
+        .with_prewarm(prewarm)
+        .new(server.read_sync());
+
+    for i in 0..keys.len() {
+        let value = remote_tree
+            .get(Context::background(), keys[i].as_slice())
+            .expect("get")
+            .expect("get_some");
+        assert_eq!(values[i], value.as_slice());
+    }
+}
+
+#[test]
+fn test_prewarm_bounded() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 100);
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    let root = Root {
+        hash,
+        ..Default::default()
+    };
+
+    // Prewarmed nodes are bounded by the cache capacity.
+    let nodes = tree.clean_cached_nodes();
+    assert!(nodes.len() > 20);
+    let remote_tree = Tree::make()
+        .with_capacity(10, 10)
+        .with_root(root)
+        .with_prewarm(nodes)
+        .new(server.read_sync());
+    assert_eq!(remote_tree.cache.borrow().prewarm_node_count(), 20);
+
+    // Clean nodes are kept when discarding modifications, without growing
+    // past the cache capacity.
+    let stats = StatsCollector::new(server.read_sync());
+    let mut remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(root)
+        .new(Box::new(stats));
+    let get_all = |tree: &Tree| {
+        for i in 0..keys.len() {
+            let value = tree
+                .get(Context::background(), keys[i].as_slice())
+                .expect("get")
+                .expect("get_some");
+            assert_eq!(values[i], value.as_slice());
+        }
+    };
+    get_all(&remote_tree);
+    let sync_get_count = |tree: &Tree| {
+        let cache = tree.cache.borrow();
+        cache
+            .get_read_syncer()
+            .as_any()
+            .downcast_ref::<StatsCollector>()
+            .expect("stats")
+            .sync_get_count
+    };
+    let fetched = sync_get_count(&remote_tree);
+
+    for _ in 0..3 {
+        remote_tree
+            .insert(Context::background(), b"foo", b"bar")
+            .expect("insert");
+        remote_tree.clear_pending();
+        assert!(remote_tree.cache.borrow().prewarm_node_count() <= 2 * keys.len());
+    }
+    get_all(&remote_tree);
+    assert!(
+        sync_get_count(&remote_tree) - fetched < keys.len(),
+        "clean nodes should be reused"
+    );
+}
+
+#[test]
+fn test_switch_root() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 100);
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+    let first_root = Root {
+        hash,
+        ..Default::default()
+    };
+
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply_existing(&write_log, first_root.hash, hash, Default::default(), 0);
+    let second_root = Root {
+        hash,
+        ..Default::default()
+    };
+
+    let stats = StatsCollector::new(server.read_sync());
+    let mut remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(first_root)
+        .new(Box::new(stats));
+    let get_all = |tree: &Tree| {
+        for i in 0..keys.len() {
+            let value = tree
+                .get(Context::background(), keys[i].as_slice())
+                .expect("get")
+                .expect("get_some");
+            assert_eq!(values[i], value.as_slice());
+        }
+    };
+    let sync_get_count = |tree: &Tree| {
+        let cache = tree.cache.borrow();
+        cache
+            .get_read_syncer()
+            .as_any()
+            .downcast_ref::<StatsCollector>()
+            .expect("stats")
+            .sync_get_count
+    };
+    get_all(&remote_tree);
+    assert_eq!(
+        remote_tree.get(Context::background(), b"foo").expect("get"),
+        None
+    );
+    let fetched = sync_get_count(&remote_tree);
+
+    // Subtrees shared with the previous root are not fetched again.
+    remote_tree.switch_root(second_root);
+    get_all(&remote_tree);
+    assert_eq!(
+        remote_tree.get(Context::background(), b"foo").expect("get"),
+        Some(b"bar".to_vec())
+    );
+    assert!(
+        sync_get_count(&remote_tree) - fetched < keys.len(),
+        "clean nodes should be reused"
+    );
+}
+
+#[test]
+fn test_canonical_node_order() {
+    let (keys, values) = generate_key_value_pairs();
+
+    let export = |tree: &Tree| -> (Vec<Hash>, Vec<u8>) {
+        let mut hashes = Vec::new();
+        let mut data = Vec::new();
+        for (hash, node) in tree
+            .canonical_node_order(Context::background())
+            .expect("canonical_node_order")
+        {
+            hashes.push(hash);
+            data.extend(node.borrow().marshal_binary().expect("marshal"));
+        }
+        (hashes, data)
+    };
+
+    // Insert keys in order.
+    let mut tree = Tree::new_in_memory();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    assert!(
+        tree.canonical_node_order(Context::background()).is_err(),
+        "canonical order should require a committed tree"
+    );
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    let (hashes, data) = export(&tree);
+    assert_eq!(hashes.first(), Some(&hash));
+
+    // Insert keys in reverse order, with an extra key that is later removed,
+    // and commit in multiple steps.
+    let mut other_tree = Tree::new_in_memory();
+    other_tree
+        .insert(Context::background(), b"removed key", b"removed value")
+        .expect("insert");
+    for i in (keys.len() / 2..keys.len()).rev() {
+        other_tree
+            .insert(
+                Context::background(),
+                keys[i].as_slice(),
+                values[i].as_slice(),
+            )
+            .expect("insert");
+    }
+    Tree::commit(
+        &mut other_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    other_tree
+        .remove(Context::background(), b"removed key")
+        .expect("remove");
+    for i in (0..keys.len() / 2).rev() {
+        other_tree
+            .insert(
+                Context::background(),
+                keys[i].as_slice(),
+                values[i].as_slice(),
+            )
+            .expect("insert");
+    }
+    let (_, other_hash) = Tree::commit(
+        &mut other_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert_eq!(hash, other_hash);
+    assert_eq!(export(&other_tree), (hashes.clone(), data.clone()));
+
+    // A remote tree with a cold cache must produce the same order.
+    let server = ProtocolServer::new();
+    server.apply(&write_log, hash, Default::default(), 0);
+    let remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    assert_eq!(export(&remote_tree), (hashes, data));
+}
+
+/// A read syncer which always returns the same proof.
+struct StaticProofSyncer(Proof);
+
+impl ReadSync for StaticProofSyncer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn sync_get(&mut self, _ctx: Context, _request: GetRequest) -> Result<ProofResponse> {
+        Ok(ProofResponse {
+            proof: self.0.clone(),
+        })
+    }
+
+    fn sync_get_prefixes(
+        &mut self,
+        _ctx: Context,
+        _request: GetPrefixesRequest,
+    ) -> Result<ProofResponse> {
+        Err(SyncerError::Unsupported.into())
+    }
+
+    fn sync_iterate(&mut self, _ctx: Context, _request: IterateRequest) -> Result<ProofResponse> {
+        Err(SyncerError::Unsupported.into())
+    }
+}
+
+#[test]
+fn test_get_with_proof() {
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    assert!(
+        tree.get_with_proof(Context::background(), keys[0].as_slice())
+            .is_err(),
+        "proofs should require a committed tree"
+    );
+    let (_, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    let root = Root {
+        hash,
+        ..Default::default()
+    };
+
+    let mut lookups: Vec<(Vec<u8>, Option<Vec<u8>>)> = keys
+        .iter()
+        .cloned()
+        .zip(values.iter().cloned().map(Some))
+        .collect();
+    lookups.push((b"missing key".to_vec(), None));
+    lookups.push((b"key".to_vec(), None));
+    lookups.push((b"key 10000".to_vec(), None));
+
+    for (key, expected) in lookups {
+        let (value, proof) = tree
+            .get_with_proof(Context::background(), key.as_slice())
+            .expect("get_with_proof");
+        assert_eq!(value, expected);
+
+        // The proof must verify against the root.
+        ProofVerifier
+            .verify_proof(Context::background(), hash, &proof)
+            .expect("proof should verify");
+
+        // The proof must be sufficient to perform the same lookup.
+        let proof_tree = Tree::make()
+            .with_root(root)
+            .new(Box::new(StaticProofSyncer(proof)));
+        let value = proof_tree
+            .get(Context::background(), key.as_slice())
+            .expect("get");
+        assert_eq!(value, expected);
+    }
+}
+
+#[test]
+fn test_get_many() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    // Request keys in reverse order, including duplicates and missing keys.
+    let mut lookups: Vec<Vec<u8>> = Vec::new();
+    let mut expected: Vec<Option<Vec<u8>>> = Vec::new();
+    for i in (100..200).rev() {
+        lookups.push(keys[i].clone());
+        expected.push(Some(values[i].clone()));
+    }
+    lookups.push(b"missing key".to_vec());
+    expected.push(None);
+    lookups.push(keys[150].clone());
+    expected.push(Some(values[150].clone()));
+
+    assert_eq!(
+        tree.get_many(Context::background(), &lookups)
+            .expect("get_many"),
+        expected
+    );
+
+    // Local modifications must be taken into account.
+    let mut local_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    local_tree
+        .insert(Context::background(), &keys[120], b"modified")
+        .expect("insert");
+    local_tree
+        .remove(Context::background(), &keys[130])
+        .expect("remove");
+    let mut local_expected = expected.clone();
+    local_expected[199 - 120] = Some(b"modified".to_vec());
+    local_expected[199 - 130] = None;
+    assert_eq!(
+        local_tree
+            .get_many(Context::background(), &lookups)
+            .expect("get_many"),
+        local_expected
+    );
+
+    // A remote tree should fetch the nodes for all keys together.
+    let stats = StatsCollector::new(server.read_sync());
+    let remote_tree = Tree::make()
+        .with_capacity(0, 0)
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(Box::new(stats));
+    assert_eq!(
+        remote_tree
+            .get_many(Context::background(), &lookups)
+            .expect("get_many"),
+        expected
+    );
+
+    let cache = remote_tree.cache.borrow();
+    let stats = cache
+        .get_read_syncer()
+        .as_any()
+        .downcast_ref::<StatsCollector>()
+        .expect("stats");
+    assert_eq!(0, stats.sync_get_count, "sync_get count");
+    assert!(
+        stats.sync_get_prefixes_count < 10,
+        "sync_get_prefixes count should be small"
+    );
+}
+
+#[test]
+fn test_clear_pending() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    let mut remote_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+
+    // Modify the tree and then discard the modifications.
+    remote_tree
+        .insert(Context::background(), b"new key", b"new value")
+        .expect("insert");
+    remote_tree
+        .insert(Context::background(), &keys[10], b"modified")
+        .expect("insert");
+    remote_tree
+        .remove(Context::background(), &keys[20])
+        .expect("remove");
+    remote_tree.clear_pending();
+
+    assert_eq!(
+        remote_tree
+            .get(Context::background(), b"new key")
+            .expect("get"),
+        None
+    );
+    for i in 0..keys.len() {
+        let value = remote_tree
+            .get(Context::background(), keys[i].as_slice())
+            .expect("get")
+            .expect("get_some");
+        assert_eq!(values[i], value.as_slice());
+    }
+
+    let (write_log, new_hash) = Tree::commit(
+        &mut remote_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert!(write_log.is_empty());
+    assert_eq!(new_hash, hash);
+}
+
+#[test]
+fn test_max_value_size() {
+    let mut tree = Tree::make()
+        .with_max_value_size(16)
+        .new(Box::new(NoopReadSyncer));
+
+    tree.insert(Context::background(), b"foo", &[0xaa; 16])
+        .expect("insert at the limit should succeed");
+    let result = tree.insert(Context::background(), b"bar", &[0xaa; 17]);
+    match result {
+        Err(error) => match error.downcast_ref::<TreeError>() {
+            Some(TreeError::ValueTooLarge { size: 17, max: 16 }) => {}
+            _ => panic!("unexpected error: {}", error),
+        },
+        Ok(_) => panic!("insert over the limit should fail"),
+    }
+    assert_eq!(
+        tree.get(Context::background(), b"bar").expect("get"),
+        None,
+        "value over the limit should not be inserted"
+    );
+
+    // Computed defaults are checked once they are needed.
+    tree.get_or_insert_with(Context::background(), b"foo", || vec![0xaa; 17])
+        .expect("get_or_insert_with of an existing key should succeed");
+    assert!(tree
+        .get_or_insert_with(Context::background(), b"bar", || vec![0xaa; 17])
+        .is_err());
+    assert_eq!(
+        tree.get(Context::background(), b"bar").expect("get"),
+        None,
+        "default over the limit should not be inserted"
+    );
+
+    let (write_log, _) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    assert_eq!(write_log.len(), 1);
+}
+
+#[test]
+fn test_fold() {
+    let mut tree = Tree::new_in_memory();
+
+    let count = tree
+        .fold(Context::background(), 0, |count, _, _| count + 1)
+        .expect("fold");
+    assert_eq!(count, 0, "empty tree should have no keys");
+
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+
+    // Keys should be visited in order.
+    let mut expected: Vec<(Vec<u8>, Vec<u8>)> =
+        keys.iter().cloned().zip(values.iter().cloned()).collect();
+    expected.sort();
+    let visited = tree
+        .fold(
+            Context::background(),
+            Vec::new(),
+            |mut visited, key, value| {
+                visited.push((key.to_vec(), value.to_vec()));
+                visited
+            },
+        )
+        .expect("fold");
+    assert_eq!(visited, expected);
+
+    // Folding should stop early on break.
+    let (count, last) = tree
+        .try_fold(
+            Context::background(),
+            (0, Vec::new()),
+            |(count, _), key, _| {
+                if count + 1 == 10 {
+                    ControlFlow::Break((count + 1, key.to_vec()))
+                } else {
+                    ControlFlow::Continue((count + 1, key.to_vec()))
+                }
+            },
+        )
+        .expect("try_fold");
+    assert_eq!(count, 10);
+    assert_eq!(last, expected[9].0);
+}
+
+#[test]
+fn test_monotonic_versions() {
+    let mut tree = Tree::make()
+        .with_root(Root {
+            version: 5,
+            hash: Hash::empty_hash(),
+            ..Default::default()
+        })
+        .with_monotonic_versions()
+        .new(Box::new(NoopReadSyncer));
+
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    for version in &[0, 4, 5] {
+        let result = Tree::commit(
+            &mut tree,
+            Context::background(),
+            Default::default(),
+            *version,
+        );
+        match result {
+            Err(error) => match error.downcast_ref::<TreeError>() {
+                Some(TreeError::NonMonotonicVersion {
+                    current: 5,
+                    requested,
+                }) if requested == version => {}
+                _ => panic!("unexpected error: {}", error),
+            },
+            Ok(_) => panic!("commit with a non-monotonic version should fail"),
+        }
+    }
+
+    let (write_log, _) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 6).expect("commit");
+    assert_eq!(
+        write_log.len(),
+        1,
+        "failed commits should keep pending writes"
+    );
+
+    tree.insert(Context::background(), b"foo", b"baz")
+        .expect("insert");
+    assert!(
+        Tree::commit(&mut tree, Context::background(), Default::default(), 6).is_err(),
+        "commit with the same version as the previous commit should fail"
+    );
+    Tree::commit(&mut tree, Context::background(), Default::default(), 7).expect("commit");
+}
+
+#[test]
+fn test_insert_if_absent() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    // Committed key.
+    assert!(!tree
+        .insert_if_absent(Context::background(), b"foo", b"other")
+        .expect("insert_if_absent"));
+    // New key.
+    assert!(tree
+        .insert_if_absent(Context::background(), b"moo", b"boo")
+        .expect("insert_if_absent"));
+    // Pending insert.
+    assert!(!tree
+        .insert_if_absent(Context::background(), b"moo", b"other")
+        .expect("insert_if_absent"));
+    // Pending delete.
+    tree.remove(Context::background(), b"foo").expect("remove");
+    assert!(tree
+        .insert_if_absent(Context::background(), b"foo", b"new")
+        .expect("insert_if_absent"));
+
+    assert_eq!(
+        tree.get(Context::background(), b"foo").expect("get"),
+        Some(b"new".to_vec())
+    );
+    assert_eq!(
+        tree.get(Context::background(), b"moo").expect("get"),
+        Some(b"boo".to_vec())
+    );
+
+    let (write_log, _) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    assert_eq!(
+        write_log,
+        vec![
+            LogEntry {
+                key: b"foo".to_vec(),
+                value: Some(b"new".to_vec()),
+                marker: false,
+            },
+            LogEntry {
+                key: b"moo".to_vec(),
+                value: Some(b"boo".to_vec()),
+                marker: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_replace_existing() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    tree.insert(Context::background(), b"moo", b"boo")
+        .expect("insert");
+    Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    // Missing key.
+    assert!(!tree
+        .replace_existing(Context::background(), b"goo", b"other")
+        .expect("replace_existing"));
+    assert!(!tree
+        .replace_existing(Context::background(), b"fo", b"other")
+        .expect("replace_existing"));
+    // Committed key.
+    assert!(tree
+        .replace_existing(Context::background(), b"foo", b"new")
+        .expect("replace_existing"));
+    // Pending insert.
+    tree.insert(Context::background(), b"zoo", b"zar")
+        .expect("insert");
+    assert!(tree
+        .replace_existing(Context::background(), b"zoo", b"new")
+        .expect("replace_existing"));
+    // Pending delete.
+    tree.remove(Context::background(), b"moo").expect("remove");
+    assert!(!tree
+        .replace_existing(Context::background(), b"moo", b"new")
+        .expect("replace_existing"));
+
+    assert_eq!(tree.get(Context::background(), b"goo").expect("get"), None);
+    assert_eq!(tree.get(Context::background(), b"fo").expect("get"), None);
+    assert_eq!(tree.get(Context::background(), b"moo").expect("get"), None);
+
+    let (write_log, _) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    assert_eq!(
+        write_log,
+        vec![
+            LogEntry {
+                key: b"foo".to_vec(),
+                value: Some(b"new".to_vec()),
+                marker: false,
+            },
+            LogEntry {
+                key: b"moo".to_vec(),
+                value: None,
+                marker: false,
+            },
+            LogEntry {
+                key: b"zoo".to_vec(),
+                value: Some(b"new".to_vec()),
+                marker: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_diff() {
+    let (keys, values) = generate_key_value_pairs();
+
+    let mut old_tree = Tree::new_in_memory();
+    for i in 0..keys.len() {
+        old_tree
+            .insert(
+                Context::background(),
+                keys[i].as_slice(),
+                values[i].as_slice(),
+            )
+            .expect("insert");
+    }
+    Tree::commit(&mut old_tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let write_log = Tree::diff(Context::background(), &old_tree, &old_tree).expect("diff");
+    assert!(write_log.is_empty(), "tree should not differ from itself");
+
+    let mut new_tree = Tree::new_in_memory();
+    let mut expected = Vec::new();
+    for i in 0..keys.len() {
+        match i % 10 {
+            // Removed.
+            0 => expected.push(LogEntry {
+                key: keys[i].clone(),
+                value: None,
+                marker: false,
+            }),
+            // Updated.
+            1 => {
+                new_tree
+                    .insert(Context::background(), keys[i].as_slice(), b"updated")
+                    .expect("insert");
+                expected.push(LogEntry {
+                    key: keys[i].clone(),
+                    value: Some(b"updated".to_vec()),
+                    marker: false,
+                });
+            }
+            // Unchanged.
+            _ => {
+                new_tree
+                    .insert(
+                        Context::background(),
+                        keys[i].as_slice(),
+                        values[i].as_slice(),
+                    )
+                    .expect("insert");
+            }
+        }
+    }
+    // Inserted.
+    new_tree
+        .insert(Context::background(), b"new key", b"new value")
+        .expect("insert");
+    expected.push(LogEntry {
+        key: b"new key".to_vec(),
+        value: Some(b"new value".to_vec()),
+        marker: false,
+    });
+    expected.sort_by(|a, b| a.key.cmp(&b.key));
+
+    // Uncommitted modifications.
+    let write_log = Tree::diff(Context::background(), &old_tree, &new_tree).expect("diff");
+    assert_eq!(write_log, expected);
+
+    // Committed modifications.
+    Tree::commit(&mut new_tree, Context::background(), Default::default(), 0).expect("commit");
+    let write_log = Tree::diff_iter(Context::background(), &old_tree, &new_tree)
+        .collect::<Result<WriteLog>>()
+        .expect("diff_iter");
+    assert_eq!(write_log, expected);
+
+    // Applying the diff to the old tree should result in the new tree.
+    for entry in write_log {
+        match entry.value {
+            Some(value) => old_tree
+                .insert(Context::background(), &entry.key, &value)
+                .map(|_| ()),
+            None => old_tree
+                .remove(Context::background(), &entry.key)
+                .map(|_| ()),
+        }
+        .expect("apply");
+    }
+    let (_, old_hash) =
+        Tree::commit(&mut old_tree, Context::background(), Default::default(), 0).expect("commit");
+    let (_, new_hash) =
+        Tree::commit(&mut new_tree, Context::background(), Default::default(), 0).expect("commit");
+    assert_eq!(old_hash, new_hash);
+}
+
+#[test]
+fn test_copy_subtree() {
+    let mut source = Tree::new_in_memory();
+    for key in &[&b"a"[..], b"b", b"b1", b"b2", b"ba", b"c"] {
+        source
+            .insert(Context::background(), key, key)
+            .expect("insert");
+    }
+    Tree::commit(&mut source, Context::background(), Default::default(), 0).expect("commit");
+
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"b1", b"old")
+        .expect("insert");
+    tree.insert(Context::background(), b"d", b"d")
+        .expect("insert");
+
+    let copied = tree
+        .copy_subtree(Context::background(), &source, b"b")
+        .expect("copy_subtree");
+    assert_eq!(copied, 4);
+
+    let copied = tree
+        .copy_subtree(Context::background(), &source, b"x")
+        .expect("copy_subtree");
+    assert_eq!(copied, 0);
+
+    let mut it = tree.iter(Context::background());
+    it.rewind();
+    let items: Vec<(Vec<u8>, Vec<u8>)> = it.collect();
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (b"b".to_vec(), b"b".to_vec()),
+        (b"b1".to_vec(), b"b1".to_vec()),
+        (b"b2".to_vec(), b"b2".to_vec()),
+        (b"ba".to_vec(), b"ba".to_vec()),
+        (b"d".to_vec(), b"d".to_vec()),
+    ];
+    assert_eq!(items, expected);
+}
+
+#[test]
+fn test_watch_root() {
+    let mut tree = Tree::new_in_memory();
+    let watcher = tree.watch_root();
+    let dropped = tree.watch_root();
+    drop(dropped);
+
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    let (_, first_hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    tree.insert(Context::background(), b"moo", b"boo")
+        .expect("insert");
+    let (_, second_hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 2).expect("commit");
+
+    let roots: Vec<Root> = watcher.try_iter().collect();
+    assert_eq!(
+        roots,
+        vec![
+            Root {
+                version: 1,
+                hash: first_hash,
+                ..Default::default()
+            },
+            Root {
+                version: 2,
+                hash: second_hash,
+                ..Default::default()
+            },
+        ]
+    );
+    assert!(
+        tree.root_watchers.len() == 1,
+        "dropped watcher should be removed"
+    );
+
+    // A watcher that stops reading only keeps the most recent roots.
+    for version in 3..(3 + 2 * ROOT_WATCH_CAPACITY as u64) {
+        tree.insert(Context::background(), b"foo", &version.to_be_bytes())
+            .expect("insert");
+        Tree::commit(
+            &mut tree,
+            Context::background(),
+            Default::default(),
+            version,
+        )
+        .expect("commit");
+    }
+    let versions: Vec<u64> = watcher.try_iter().map(|root| root.version).collect();
+    let last = 2 + 2 * ROOT_WATCH_CAPACITY as u64;
+    assert_eq!(
+        versions,
+        ((last + 1 - ROOT_WATCH_CAPACITY as u64)..=last).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_get_subtree_hash() {
+    let build = |items: &[(&str, &str)]| {
+        let mut tree = Tree::new_in_memory();
+        for (key, value) in items {
+            tree.insert(Context::background(), key.as_bytes(), value.as_bytes())
+                .expect("insert");
+        }
+        let (_, root_hash) =
+            Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+        (tree, root_hash)
+    };
+
+    let (tree, root_hash) = build(&[("a", "1"), ("b1", "1"), ("b2", "1"), ("c", "1")]);
+    let (other, _) = build(&[("a", "2"), ("b1", "1"), ("b2", "1"), ("c", "2")]);
+    let (changed, _) = build(&[("a", "1"), ("b1", "1"), ("b2", "2"), ("c", "1")]);
+
+    let hash = tree
+        .get_subtree_hash(Context::background(), b"b")
+        .expect("get_subtree_hash");
+    assert!(hash.is_some());
+    assert_eq!(
+        other
+            .get_subtree_hash(Context::background(), b"b")
+            .expect("get_subtree_hash"),
+        hash,
+        "subtree hash should not depend on keys outside the prefix"
+    );
+    assert_ne!(
+        changed
+            .get_subtree_hash(Context::background(), b"b")
+            .expect("get_subtree_hash"),
+        hash,
+        "subtree hash should depend on keys with the prefix"
+    );
+
+    // A prefix matching a single key.
+    let hash = tree
+        .get_subtree_hash(Context::background(), b"b2")
+        .expect("get_subtree_hash");
+    assert!(hash.is_some());
+    assert_ne!(
+        changed
+            .get_subtree_hash(Context::background(), b"b2")
+            .expect("get_subtree_hash"),
+        hash
+    );
+
+    assert_eq!(
+        tree.get_subtree_hash(Context::background(), b"x")
+            .expect("get_subtree_hash"),
+        None
+    );
+    assert_eq!(
+        tree.get_subtree_hash(Context::background(), b"b3")
+            .expect("get_subtree_hash"),
+        None
+    );
+    assert_eq!(
+        tree.get_subtree_hash(Context::background(), b"")
+            .expect("get_subtree_hash"),
+        Some(root_hash)
+    );
+
+    let mut tree = tree;
+    tree.insert(Context::background(), b"d", b"1")
+        .expect("insert");
+    assert!(tree.get_subtree_hash(Context::background(), b"b").is_err());
+}
+
+#[test]
+fn test_freeze() {
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    assert!(
+        tree.freeze(Context::background()).is_err(),
+        "freeze should require a committed tree"
+    );
+    let (_, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let frozen = tree.freeze(Context::background()).expect("freeze");
+    assert_eq!(frozen.root().hash, hash);
+
+    // The writer tree can move on without affecting the frozen tree.
+    tree.insert(Context::background(), b"new key", b"new value")
+        .expect("insert");
+    tree.remove(Context::background(), keys[0].as_slice())
+        .expect("remove");
+
+    let keys = std::sync::Arc::new(keys);
+    let values = std::sync::Arc::new(values);
+    let handles: Vec<_> = (0..4)
+        .map(|thread| {
+            let frozen = frozen.clone();
+            let keys = keys.clone();
+            let values = values.clone();
+            std::thread::spawn(move || {
+                for i in (thread..keys.len()).step_by(4) {
+                    let value = frozen.get(keys[i].as_slice()).expect("get");
+                    assert_eq!(value.as_ref(), Some(&values[i]));
+
+                    let proof = frozen.get_proof(keys[i].as_slice()).expect("get_proof");
+                    ProofVerifier
+                        .verify_proof(Context::background(), hash, &proof)
+                        .expect("proof should verify");
+                }
+                assert_eq!(frozen.get(b"new key").expect("get"), None);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("reader thread should not panic");
+    }
+
+    let items: Vec<(Vec<u8>, Vec<u8>)> = frozen.iter().expect("iter").collect();
+    let mut expected: Vec<(Vec<u8>, Vec<u8>)> =
+        keys.iter().cloned().zip(values.iter().cloned()).collect();
+    expected.sort();
+    assert_eq!(items, expected);
+}
+
+#[test]
+fn test_structural_stats() {
+    let mut tree = Tree::new_in_memory();
+    let stats = tree
+        .structural_stats(Context::background())
+        .expect("structural_stats");
+    assert_eq!(stats, TreeStats::default());
+
+    for key in &[&b"a"[..], b"b", b"b1", b"b\xff", b"c"] {
+        tree.insert(Context::background(), key, key)
+            .expect("insert");
+    }
+    Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let stats = tree
+        .structural_stats(Context::background())
+        .expect("structural_stats");
+    assert_eq!(stats.leaf_count, 5);
+    let internal_count: usize = stats.fanout.values().sum();
+    assert_eq!(stats.node_count, stats.leaf_count + internal_count);
+    assert!(stats.max_depth >= 2);
+    assert!(stats.avg_depth >= 1.0 && stats.avg_depth <= stats.max_depth as f64);
+
+    // Key "b" is stored in the leaf node of the internal node that splits
+    // "b1" and "b\xff".
+    assert_eq!(stats.fanout.get(&3), Some(&1));
+}
+
+#[test]
+fn test_subtree_size() {
+    let mut tree = Tree::new_in_memory();
+    let size = |tree: &Tree, prefix: &[u8]| {
+        tree.subtree_size(Context::background(), prefix)
+            .expect("subtree_size")
+    };
+    assert_eq!(size(&tree, b""), SubtreeSize::default());
+
+    let (keys, values) = generate_key_value_pairs_ex("a/".to_string(), 10);
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    tree.insert(Context::background(), b"b/key", b"value")
+        .expect("insert");
+    let a_bytes: usize = values.iter().map(|value| value.len()).sum();
+
+    // Uncommitted modifications are taken into account.
+    let a_size = size(&tree, b"a/");
+    assert_eq!(a_size.value_bytes, a_bytes);
+    assert!(a_size.node_count >= keys.len());
+    assert_eq!(
+        size(&tree, b"b/"),
+        SubtreeSize {
+            value_bytes: 5,
+            node_count: 1,
+        }
+    );
+    // The whole tree has one more leaf and the root internal node.
+    assert_eq!(
+        size(&tree, b""),
+        SubtreeSize {
+            value_bytes: a_bytes + 5,
+            node_count: a_size.node_count + 2,
+        }
+    );
+    assert_eq!(
+        size(&tree, b"a/key 1"),
+        SubtreeSize {
+            value_bytes: b"a/value 1".len(),
+            node_count: 1,
+        }
+    );
+
+    // Absent prefixes have a size of zero.
+    assert_eq!(size(&tree, b"c/"), SubtreeSize::default());
+    assert_eq!(size(&tree, b"a/key 10"), SubtreeSize::default());
+
+    // The size is the same when nodes have to be fetched.
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    let server = ProtocolServer::new();
+    server.apply(&write_log, hash, Default::default(), 1);
+    let remote_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            version: 1,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    assert_eq!(size(&remote_tree, b"a/"), a_size);
+}
+
+#[test]
+fn test_max_path_depth() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    assert_eq!(
+        tree.max_path_depth(Context::background())
+            .expect("max_path_depth"),
+        0
+    );
+
+    // Keys sharing ever longer prefixes form a degenerate path.
+    let mut key = Vec::new();
+    for i in 0..20u8 {
+        key.push(i);
+        tree.insert(Context::background(), &key, b"value")
+            .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let depth = tree
+        .max_path_depth(Context::background())
+        .expect("max_path_depth");
+    let stats = tree
+        .structural_stats(Context::background())
+        .expect("structural_stats");
+    assert_eq!(depth, stats.max_depth);
+    assert_eq!(depth, 19);
+    assert_eq!(
+        tree.cached_max_path_depth(Context::background())
+            .expect("cached_max_path_depth"),
+        depth
+    );
+
+    // A remote tree fetches missing nodes, unless only cached nodes are used.
+    server.apply(&write_log, hash, Default::default(), 0);
+    let remote_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    assert_eq!(
+        remote_tree
+            .cached_max_path_depth(Context::background())
+            .expect("cached_max_path_depth"),
+        0
+    );
+    assert_eq!(
+        remote_tree
+            .max_path_depth(Context::background())
+            .expect("max_path_depth"),
+        depth
+    );
+}
+
+#[test]
+fn test_insert_batch_sorted() {
+    let (keys, values) = generate_key_value_pairs();
+    let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().zip(values).collect();
+    pairs.sort();
+
+    let mut tree = Tree::new_in_memory();
+    for (key, value) in &pairs {
+        tree.insert(Context::background(), key, value)
+            .expect("insert");
+    }
+    let (_, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let mut batch_tree = Tree::new_in_memory();
+    batch_tree
+        .insert_batch_sorted(Context::background(), &pairs)
+        .expect("insert_batch_sorted");
+    let (_, batch_hash) = Tree::commit(
+        &mut batch_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert_eq!(batch_hash, hash);
+
+    // Batches into an existing tree both update existing keys and add new
+    // ones, splitting nodes along the way.
+    let mut updates: Vec<(Vec<u8>, Vec<u8>)> = pairs
+        .iter()
+        .step_by(3)
+        .map(|(key, _)| (key.clone(), b"updated".to_vec()))
+        .chain(pairs.iter().step_by(5).map(|(key, _)| {
+            let mut key = key.clone();
+            key.push(0x80);
+            (key, b"added".to_vec())
+        }))
+        .collect();
+    updates.sort();
+
+    for (key, value) in &updates {
+        tree.insert(Context::background(), key, value)
+            .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+
+    batch_tree
+        .insert_batch_sorted(Context::background(), &updates)
+        .expect("insert_batch_sorted");
+    let (batch_write_log, batch_hash) = Tree::commit(
+        &mut batch_tree,
+        Context::background(),
+        Default::default(),
+        1,
+    )
+    .expect("commit");
+    assert_eq!(batch_hash, hash);
+    assert_eq!(batch_write_log, write_log);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "keys must be in strictly ascending order")]
+fn test_insert_batch_sorted_unsorted() {
+    let mut tree = Tree::new_in_memory();
+    let _ = tree.insert_batch_sorted(Context::background(), &[(b"b", b"1"), (b"a", b"2")]);
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"present", b"value")
+        .expect("insert");
+    tree.insert(Context::background(), b"deleted", b"value")
+        .expect("insert");
+    Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    tree.remove(Context::background(), b"deleted")
+        .expect("remove");
+
+    // Present keys are returned without computing the default.
+    let value = tree
+        .get_or_insert_with(Context::background(), b"present", || {
+            panic!("default should not be computed")
+        })
+        .expect("get_or_insert_with");
+    assert_eq!(value, b"value".to_vec());
+    assert!(tree.pending_write_log.get(&b"present".to_vec()).is_none());
+
+    // Absent keys are initialized with the default.
+    let value = tree
+        .get_or_insert_with(Context::background(), b"absent", || b"default".to_vec())
+        .expect("get_or_insert_with");
+    assert_eq!(value, b"default".to_vec());
+    assert_eq!(
+        tree.get(Context::background(), b"absent").expect("get"),
+        Some(b"default".to_vec())
+    );
+
+    // Pending deletes are treated as absent.
+    let value = tree
+        .get_or_insert_with(Context::background(), b"deleted", || b"default".to_vec())
+        .expect("get_or_insert_with");
+    assert_eq!(value, b"default".to_vec());
+    assert_eq!(
+        tree.get(Context::background(), b"deleted").expect("get"),
+        Some(b"default".to_vec())
+    );
+
+    let (write_log, _) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    assert_eq!(
+        write_log,
+        vec![
+            LogEntry::new(b"absent", b"default"),
+            LogEntry::new(b"deleted", b"default"),
+        ]
+    );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_assert_pending_consistent() {
+    let mut tree = Tree::new_in_memory();
+    tree.assert_pending_consistent();
+
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 100);
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    tree.assert_pending_consistent();
+
+    Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    tree.assert_pending_consistent();
+
+    // Overwrites, rewrites of the same value, removals and markers.
+    tree.insert(Context::background(), keys[0].as_slice(), b"new")
+        .expect("insert");
+    tree.insert(
+        Context::background(),
+        keys[1].as_slice(),
+        values[1].as_slice(),
+    )
+    .expect("insert");
+    tree.remove(Context::background(), keys[2].as_slice())
+        .expect("remove");
+    tree.remove(Context::background(), b"absent")
+        .expect("remove");
+    tree.insert_marker(Context::background(), keys[3].as_slice())
+        .expect("insert_marker");
+    tree.remove(Context::background(), keys[4].as_slice())
+        .expect("remove");
+    tree.insert(Context::background(), keys[4].as_slice(), b"back")
+        .expect("insert");
+    tree.assert_pending_consistent();
+
+    tree.commit_dry_run(Context::background(), Default::default(), 2)
+        .expect("commit_dry_run");
+    tree.assert_pending_consistent();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "has no pending write")]
+fn test_assert_pending_consistent_lost_write() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    tree.pending_write_log.clear();
+    tree.assert_pending_consistent();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "does not match the tree")]
+fn test_assert_pending_consistent_stale_write() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    tree.pending_write_log
+        .get_mut(&b"foo".to_vec())
+        .expect("pending write")
+        .value = Some(b"stale".to_vec());
+    tree.assert_pending_consistent();
+}
+
+#[test]
+fn test_take_pending() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 1);
+
+    let remote = || {
+        Tree::make()
+            .with_root(Root {
+                hash,
+                version: 1,
+                ..Default::default()
+            })
+            .new(server.read_sync())
+    };
+    let execute = |tree: &mut Tree| {
+        tree.insert(Context::background(), b"new key", b"new value")
+            .expect("insert");
+        tree.insert(Context::background(), &keys[10], b"modified")
+            .expect("insert");
+        tree.remove(Context::background(), &keys[20])
+            .expect("remove");
+        tree.remove(Context::background(), b"missing key")
+            .expect("remove");
+    };
+
+    // Execute on a snapshot and transplant the writes to the canonical tree.
+    let mut snapshot = remote();
+    execute(&mut snapshot);
+    let entries = snapshot.take_pending();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(
+        snapshot
+            .get(Context::background(), &keys[10])
+            .expect("get")
+            .expect("get_some"),
+        values[10]
+    );
+
+    let mut canonical = remote();
+    canonical
+        .apply_pending_entries(Context::background(), entries)
+        .expect("apply_pending_entries");
+    let (_, canonical_hash) =
+        Tree::commit(&mut canonical, Context::background(), Default::default(), 2).expect("commit");
+
+    // The result must match executing directly on the canonical tree.
+    let mut expected = remote();
+    execute(&mut expected);
+    let (_, expected_hash) =
+        Tree::commit(&mut expected, Context::background(), Default::default(), 2).expect("commit");
+    assert_eq!(canonical_hash, expected_hash);
+    assert_ne!(canonical_hash, hash);
+
+    // Nothing remains pending on the snapshot.
+    let (write_log, snapshot_hash) =
+        Tree::commit(&mut snapshot, Context::background(), Default::default(), 2).expect("commit");
+    assert!(write_log.is_empty());
+    assert_eq!(snapshot_hash, hash);
+}
+
+#[test]
+fn test_context_deadline() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    let remote_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+
+    // Nodes are not fetched once the deadline has been exceeded.
+    let mut ctx = Context::background();
+    cancellation::set_deadline(&mut ctx, Instant::now());
+    let err = remote_tree
+        .get(ctx, b"foo")
+        .expect_err("get should fail after the deadline");
+    assert!(matches!(
+        err.downcast_ref::<TreeError>(),
+        Some(TreeError::ContextCancelled)
+    ));
+
+    assert_eq!(
+        remote_tree.get(Context::background(), b"foo").expect("get"),
+        Some(b"bar".to_vec())
+    );
+}
+
+#[test]
+fn test_any_with_prefix() {
+    let mut tree = Tree::new_in_memory();
+    for key in &["account/a/1", "account/a/2", "account/b/1", "other"] {
+        tree.insert(Context::background(), key.as_bytes(), b"value")
+            .expect("insert");
+    }
+    Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+
+    let any = |tree: &Tree, prefix: &str| {
+        tree.any_with_prefix(Context::background(), prefix.as_bytes())
+            .expect("any_with_prefix")
+    };
+    assert!(any(&tree, ""));
+    assert!(any(&tree, "account/"));
+    assert!(any(&tree, "account/a/"));
+    assert!(any(&tree, "account/b/1"));
+    assert!(!any(&tree, "account/c/"));
+    assert!(!any(&tree, "account/b/1/"));
+    assert!(!any(&tree, "z"));
+
+    // Pending inserts are taken into account.
+    tree.insert(Context::background(), b"account/c/1", b"value")
+        .expect("insert");
+    assert!(any(&tree, "account/c/"));
+
+    // So are pending removals.
+    tree.remove(Context::background(), b"account/b/1")
+        .expect("remove");
+    assert!(!any(&tree, "account/b/"));
+    assert!(any(&tree, "account/"));
+}
+
+#[test]
+fn test_commit_dry_run() {
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    let (_, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+
+    // Nothing pending yields the current root.
+    let dry_hash = tree
+        .commit_dry_run(Context::background(), Default::default(), 2)
+        .expect("commit_dry_run");
+    assert_eq!(dry_hash, hash);
+
+    tree.insert(Context::background(), b"moo", b"boo")
+        .expect("insert");
+    tree.remove(Context::background(), b"foo").expect("remove");
+    let dry_hash = tree
+        .commit_dry_run(Context::background(), Default::default(), 2)
+        .expect("commit_dry_run");
+    assert_ne!(dry_hash, hash);
+
+    // The tree still accepts writes after a dry run.
+    tree.insert(Context::background(), b"carrot", b"stick")
+        .expect("insert");
+    let dry_hash = tree
+        .commit_dry_run(Context::background(), Default::default(), 2)
+        .expect("commit_dry_run");
+
+    let (write_log, new_hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 2).expect("commit");
+    assert_eq!(new_hash, dry_hash);
+    assert_eq!(write_log.len(), 3, "pending writes should not be cleared");
+}
+
+#[test]
+fn test_verify_write_log() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    tree.insert(Context::background(), b"foo", b"bar")
+        .expect("insert");
+    let (write_log, base_hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+    server.apply(&write_log, base_hash, Default::default(), 1);
+    let base_root = Root {
+        hash: base_hash,
+        version: 1,
+        ..Default::default()
+    };
+
+    tree.insert(Context::background(), b"moo", b"boo")
+        .expect("insert");
+    tree.remove(Context::background(), b"foo").expect("remove");
+    let (write_log, new_hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 2).expect("commit");
+
+    let mut other = Tree::new_in_memory();
+    other
+        .insert(Context::background(), b"foo", b"other")
+        .expect("insert");
+    other
+        .insert(Context::background(), b"carrot", b"stick")
+        .expect("insert");
+    let (other_write_log, other_hash) =
+        Tree::commit(&mut other, Context::background(), Default::default(), 1).expect("commit");
+    server.apply(&other_write_log, other_hash, Default::default(), 1);
+
+    let verify = |base_root: Root, write_log: &WriteLog| {
+        Tree::verify_write_log(
+            Context::background(),
+            base_root,
+            write_log,
+            Root {
+                hash: new_hash,
+                version: 2,
+                ..Default::default()
+            },
+            server.read_sync(),
+        )
+    };
+
+    // A valid write log.
+    assert!(verify(base_root, &write_log).expect("verify_write_log"));
+
+    // A tampered write log.
+    let mut tampered = write_log.clone();
+    tampered[0].value = Some(b"tampered".to_vec());
+    assert!(!verify(base_root, &tampered).expect("verify_write_log"));
+
+    // A write log applied to the wrong base root.
+    let wrong_root = Root {
+        hash: other_hash,
+        version: 1,
+        ..Default::default()
+    };
+    assert!(!verify(wrong_root, &write_log).expect("verify_write_log"));
+    let unknown_root = Root {
+        hash: Hash::digest_bytes(b"unknown"),
+        version: 1,
+        ..Default::default()
+    };
+    assert!(verify(unknown_root, &write_log).is_err());
+}
+
+#[test]
+fn test_pending_root_preview() {
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 100);
+    for i in 0..50 {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+
+    // Interleave writes and previews, comparing against a dry run on an
+    // identical tree (which rehashes all modified nodes every time).
+    let mut reference = Tree::new_in_memory();
+    for i in 0..50 {
+        reference
+            .insert(
+                Context::background(),
+                keys[i].as_slice(),
+                values[i].as_slice(),
+            )
+            .expect("insert");
+    }
+    Tree::commit(&mut reference, Context::background(), Default::default(), 1).expect("commit");
+
+    let check = |tree: &Tree, reference: &Tree, version: u64| {
+        let preview = tree
+            .pending_root_preview(Context::background(), version)
+            .expect("pending_root_preview");
+        let dry_hash = reference
+            .commit_dry_run(Context::background(), Default::default(), version)
+            .expect("commit_dry_run");
+        assert_eq!(preview, dry_hash);
+        // Previewing again without writes yields the same root.
+        assert_eq!(
+            tree.pending_root_preview(Context::background(), version)
+                .expect("pending_root_preview"),
+            dry_hash
+        );
+    };
+    check(&tree, &reference, 2);
+
+    for i in 50..100 {
+        for t in &mut [&mut tree, &mut reference] {
+            t.insert(
+                Context::background(),
+                keys[i].as_slice(),
+                values[i].as_slice(),
+            )
+            .expect("insert");
+            // Remove an older key and overwrite another one.
+            t.remove(Context::background(), keys[i - 50].as_slice())
+                .expect("remove");
+            t.insert(Context::background(), keys[i - 49].as_slice(), b"new")
+                .expect("insert");
+        }
+        check(&tree, &reference, 2);
+    }
+
+    // Previews at a different version rehash everything.
+    check(&tree, &reference, 3);
+
+    let (_, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 3).expect("commit");
+    let (_, reference_hash) =
+        Tree::commit(&mut reference, Context::background(), Default::default(), 3).expect("commit");
+    assert_eq!(hash, reference_hash);
+    check(&tree, &reference, 4);
+}
+
+#[test]
+fn test_get_range_proof() {
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs_ex("".to_string(), 100);
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (_, root) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 1).expect("commit");
+
+    let mut sorted: Vec<(Vec<u8>, Vec<u8>)> =
+        keys.iter().cloned().zip(values.iter().cloned()).collect();
+    sorted.sort();
+    let expected = |start: &[u8], end: &[u8]| -> Vec<(Vec<u8>, Vec<u8>)> {
+        sorted
+            .iter()
+            .filter(|(key, _)| key.as_slice() >= start && key.as_slice() < end)
+            .cloned()
+            .collect()
+    };
+    let verify = |start: &[u8], end: &[u8], pairs: &[(Vec<u8>, Vec<u8>)], proof: &RangeProof| {
+        ProofVerifier.verify_range_proof(Context::background(), root, start, end, pairs, proof)
+    };
+
+    for (start, end) in vec![
+        // A range in the middle of the tree.
+        (b"key 20".to_vec(), b"key 40".to_vec()),
+        // Ranges at the edges of the tree.
+        (vec![], b"key 1".to_vec()),
+        (b"key 95".to_vec(), vec![0xff; 8]),
+        (vec![], vec![0xff; 8]),
+        // Empty ranges.
+        (b"key 20".to_vec(), b"key 20".to_vec()),
+        (b"key 40".to_vec(), b"key 20".to_vec()),
+        (b"zzz".to_vec(), vec![0xff; 8]),
+        (b"key 20 ".to_vec(), b"key 20a".to_vec()),
+    ] {
+        let (pairs, proof) = tree
+            .get_range_proof(Context::background(), &start, &end)
+            .expect("get_range_proof");
+        assert_eq!(pairs, expected(&start, &end));
+        verify(&start, &end, &pairs, &proof).expect("verify_range_proof");
+
+        // The same proof does not cover a larger range.
+        if !pairs.is_empty() {
+            let mut larger_start = start.clone();
+            larger_start.truncate(start.len().saturating_sub(1));
+            if larger_start != start {
+                assert!(verify(&larger_start, &end, &pairs, &proof).is_err());
+            }
+        }
+    }
+
+    let (start, end) = (b"key 20".to_vec(), b"key 40".to_vec());
+    let (pairs, proof) = tree
+        .get_range_proof(Context::background(), &start, &end)
+        .expect("get_range_proof");
+
+    // Dropping a key from the returned pairs is detected.
+    let mut dropped = pairs.clone();
+    dropped.remove(dropped.len() / 2);
+    assert!(verify(&start, &end, &dropped, &proof).is_err());
+
+    // Dropping a key from the proof (by replacing an included leaf with its
+    // hash) is detected.
+    let mut tampered = proof.clone();
+    let tree_proof = &mut tampered.proof;
+    let leaf_idx = tree_proof
+        .entries
+        .iter()
+        .rposition(|entry| match entry {
+            Some(entry) => entry[0] == 0x01 && entry[1] == NodeKind::Leaf as u8,
+            None => false,
+        })
+        .expect("proof should contain a leaf");
+    let mut leaf = NodeBox::default();
+    leaf.unmarshal_binary(&tree_proof.entries[leaf_idx].as_ref().unwrap()[1..])
+        .expect("unmarshal leaf");
+    leaf.update_hash();
+    let mut entry = vec![0x02];
+    entry.extend_from_slice(leaf.get_hash().as_ref());
+    tree_proof.entries[leaf_idx] = Some(entry.into());
+    assert!(verify(&start, &end, &pairs, &tampered).is_err());
+    let mut without_leaf = pairs.clone();
+    without_leaf.retain(|(key, _)| match leaf {
+        NodeBox::Leaf(ref n) => *key != n.key,
+        _ => true,
+    });
+    assert!(verify(&start, &end, &without_leaf, &tampered).is_err());
+
+    // Proofs for trees with uncommitted modifications are refused.
+    tree.insert(Context::background(), b"key 30", b"pending")
+        .expect("insert");
+    assert!(tree
+        .get_range_proof(Context::background(), &start, &end)
+        .is_err());
+}
+
+#[test]
+fn test_commit_single_key() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let items: Vec<(Vec<u8>, u8)> = (0..rng.gen_range(0, 50))
+            .map(|_| (vec![rng.gen_range(0, 32), rng.gen()], rng.gen()))
+            .collect();
+        let key = vec![rng.gen_range(0, 32), rng.gen()];
+        let value: Option<u8> = if rng.gen_bool(0.3) {
+            None
+        } else {
+            Some(rng.gen())
+        };
+
+        let make_tree = || {
+            let mut tree = Tree::new_in_memory();
+            for (key, value) in &items {
+                tree.insert(Context::background(), key, &[*value])
+                    .expect("insert");
+            }
+            tree.commit(Context::background(), Default::default(), 1)
+                .expect("commit");
+            match value {
+                Some(value) => tree
+                    .insert(Context::background(), &key, &[value])
+                    .map(|_| ()),
+                None => tree.remove(Context::background(), &key).map(|_| ()),
+            }
+            .expect("update");
+            tree
+        };
+
+        // Commit through the general path.
+        let tree = make_tree();
+        let ctx = Context::background().freeze();
+        let mut update_list: UpdateList<LRUCache> = UpdateList::new();
+        let pending_root = tree.cache.borrow().get_pending_root();
+        // Any single key update must take the fast path.
+        assert_eq!(
+            super::commit::single_dirty_path(&pending_root).is_some(),
+            !pending_root.borrow().clean,
+            "single key update should have a single dirty path"
+        );
+        let expected = super::commit::_commit_subtree(
+            &ctx,
+            pending_root,
+            &mut update_list,
+            Some(2),
+            &DefaultNodeHasher,
+        )
+        .expect("commit");
+
+        let mut tree = make_tree();
+        let (_, hash) = tree
+            .commit(Context::background(), Default::default(), 2)
+            .expect("commit");
+        assert_eq!(hash, expected, "single key commit should match");
+    }
+}
+
+/// Hasher producing different hashes than the default hasher.
+struct PrefixedHasher;
+
+impl NodeHasher for PrefixedHasher {
+    fn name(&self) -> &'static str {
+        "prefixed_sha512_256"
+    }
+
+    fn digest_bytes_list(&self, data: &[&[u8]]) -> Hash {
+        let mut prefixed: Vec<&[u8]> = vec![b"prefixed"];
+        prefixed.extend_from_slice(data);
+        Hash::digest_bytes_list(&prefixed)
+    }
+}
+
+#[test]
+fn test_hasher() {
+    let build = |mut tree: Tree| {
+        tree.insert(Context::background(), b"foo", b"bar")
+            .expect("insert");
+        tree.insert(Context::background(), b"moo", b"boo")
+            .expect("insert");
+        let (_, hash) =
+            Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+        (tree, hash)
+    };
+
+    let (default_tree, default_hash) = build(Tree::new_in_memory());
+    assert_eq!(default_tree.hasher_name(), "sha512_256");
+
+    let (tree, hash) = build(
+        Tree::make()
+            .with_hasher(Box::new(PrefixedHasher))
+            .new(Box::new(NoopReadSyncer)),
+    );
+    assert_eq!(tree.hasher_name(), "prefixed_sha512_256");
+    assert_ne!(
+        hash, default_hash,
+        "different hashers must produce different roots"
+    );
+    assert_eq!(
+        tree.get(Context::background(), b"foo").expect("get"),
+        Some(b"bar".to_vec())
+    );
+
+    // The same hasher produces the same root.
+    let (_, other_hash) = build(
+        Tree::make()
+            .with_hasher(Box::new(PrefixedHasher))
+            .new(Box::new(NoopReadSyncer)),
+    );
+    assert_eq!(hash, other_hash);
+}
+
+#[test]
+#[should_panic(expected = "requires a NoopReadSyncer")]
+fn test_hasher_read_syncer() {
+    // Trees backed by host storage must use the default hasher.
+    Tree::make()
+        .with_hasher(Box::new(PrefixedHasher))
+        .new(Box::new(StatsCollector::new(Box::new(NoopReadSyncer))));
+}
+
+#[test]
+fn test_adopt_root() {
+    let server = ProtocolServer::new();
+
+    let build = |entries: &[(&str, &str)], version: u64| {
+        let mut tree = Tree::new_in_memory();
+        for (key, value) in entries {
+            tree.insert(Context::background(), key.as_bytes(), value.as_bytes())
+                .expect("insert");
+        }
+        let (write_log, hash) = Tree::commit(
+            &mut tree,
+            Context::background(),
+            Default::default(),
+            version,
+        )
+        .expect("commit");
+        server.apply(&write_log, hash, Default::default(), version);
+        hash
+    };
+    let old_hash = build(&[("foo", "old"), ("removed", "value")], 1);
+    let new_hash = build(&[("foo", "new"), ("added", "value")], 5);
+
+    let mut tree = Tree::make()
+        .with_root(Root {
+            hash: old_hash,
+            version: 1,
+            ..Default::default()
+        })
+        .with_monotonic_versions()
+        .new(server.read_sync());
+    assert_eq!(
+        tree.get(Context::background(), b"foo").expect("get"),
+        Some(b"old".to_vec())
+    );
+    tree.insert(Context::background(), b"pending", b"value")
+        .expect("insert");
+
+    // Adopt a root across a version gap, discarding pending writes.
+    tree.adopt_root(
+        Context::background(),
+        Root {
+            hash: new_hash,
+            version: 5,
+            ..Default::default()
+        },
+    )
+    .expect("adopt_root");
+    let get = |tree: &Tree, key: &[u8]| tree.get(Context::background(), key).expect("get");
+    assert_eq!(get(&tree, b"foo"), Some(b"new".to_vec()));
+    assert_eq!(get(&tree, b"added"), Some(b"value".to_vec()));
+    assert_eq!(get(&tree, b"removed"), None);
+    assert_eq!(get(&tree, b"pending"), None);
+
+    // Versions continue from the adopted root.
+    assert!(Tree::commit(&mut tree, Context::background(), Default::default(), 5).is_err());
+
+    // Unreachable roots are not adopted.
+    let result = tree.adopt_root(
+        Context::background(),
+        Root {
+            hash: Hash::digest_bytes(b"unreachable"),
+            version: 7,
+            ..Default::default()
+        },
+    );
+    assert!(result.is_err());
+    assert_eq!(get(&tree, b"foo"), Some(b"new".to_vec()));
+}
+
+#[test]
+fn test_cache_stats() {
+    let server = ProtocolServer::new();
+
+    let mut tree = Tree::new_in_memory();
+    let (keys, values) = generate_key_value_pairs();
+    for i in 0..keys.len() {
+        tree.insert(
+            Context::background(),
+            keys[i].as_slice(),
+            values[i].as_slice(),
+        )
+        .expect("insert");
+    }
+    let (write_log, hash) =
+        Tree::commit(&mut tree, Context::background(), Default::default(), 0).expect("commit");
+    server.apply(&write_log, hash, Default::default(), 0);
+
+    let remote_tree = Tree::make()
+        .with_root(Root {
+            hash,
+            ..Default::default()
+        })
+        .new(server.read_sync());
+    let stats = remote_tree.cache_stats();
+    assert_eq!(stats, CacheStats::default());
+    assert_eq!(stats.hit_rate(), None);
+
+    // The first lookup needs to fetch nodes.
+    remote_tree
+        .get(Context::background(), &keys[0])
+        .expect("get");
+    let stats = remote_tree.cache_stats();
+    assert!(stats.misses > 0, "first lookup should miss");
+    assert!(stats.node_count > 0, "fetched nodes should be cached");
+    assert!(stats.value_bytes >= values[0].len());
+
+    // Repeating the lookup is served from memory.
+    remote_tree
+        .get(Context::background(), &keys[0])
+        .expect("get");
+    let repeated = remote_tree.cache_stats();
+    assert_eq!(repeated.misses, stats.misses);
+    assert!(repeated.hits > stats.hits, "repeated lookup should hit");
+    let hit_rate = repeated.hit_rate().unwrap();
+    assert!(hit_rate > 0.0 && hit_rate < 1.0);
+}
+
+#[test]
+fn test_markers() {
+    let check = |tree: &Tree, marker: bool| {
+        assert!(tree
+            .contains_key(Context::background(), b"key")
+            .expect("contains_key"));
+        assert!(!tree
+            .contains_key(Context::background(), b"other")
+            .expect("contains_key"));
+        let expected = if marker { None } else { Some(Vec::new()) };
+        assert_eq!(
+            tree.get(Context::background(), b"key").expect("get"),
+            expected
+        );
+        assert_eq!(
+            tree.get_many(Context::background(), &[b"key".to_vec()])
+                .expect("get_many"),
+            vec![expected]
+        );
+    };
+
+    let mut marker_tree = Tree::new_in_memory();
+    marker_tree
+        .insert_marker(Context::background(), b"key")
+        .expect("insert_marker");
+    let mut empty_tree = Tree::new_in_memory();
+    empty_tree
+        .insert(Context::background(), b"key", b"")
+        .expect("insert");
+    check(&marker_tree, true);
+    check(&empty_tree, false);
+
+    // A marker and an empty value must produce different roots.
+    let (marker_log, marker_hash) = Tree::commit(
+        &mut marker_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    let (empty_log, empty_hash) = Tree::commit(
+        &mut empty_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert_ne!(marker_hash, empty_hash);
+    assert_eq!(marker_log, vec![LogEntry::new_marker(b"key")]);
+    assert_eq!(marker_log[0].kind(), LogEntryKind::InsertMarker);
+    assert_eq!(empty_log[0].kind(), LogEntryKind::Insert);
+    check(&marker_tree, true);
+    check(&empty_tree, false);
+
+    // Markers round-trip through the write log.
+    let mut applied_tree = Tree::new_in_memory();
+    applied_tree
+        .apply_pending_entries(Context::background(), marker_log)
+        .expect("apply_pending_entries");
+    let (_, applied_hash) = Tree::commit(
+        &mut applied_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert_eq!(applied_hash, marker_hash);
+
+    // Markers round-trip through the read syncer.
+    let (value, proof) = marker_tree
+        .get_with_proof(Context::background(), b"key")
+        .expect("get_with_proof");
+    assert_eq!(value, None);
+    ProofVerifier
+        .verify_proof(Context::background(), marker_hash, &proof)
+        .expect("proof should verify");
+    let proof_tree = Tree::make()
+        .with_root(Root {
+            hash: marker_hash,
+            ..Default::default()
+        })
+        .new(Box::new(StaticProofSyncer(proof)));
+    check(&proof_tree, true);
+
+    // Replacing the marker with an empty value yields the other root.
+    marker_tree
+        .insert(Context::background(), b"key", b"")
+        .expect("insert");
+    check(&marker_tree, false);
+    let (_, hash) = Tree::commit(
+        &mut marker_tree,
+        Context::background(),
+        Default::default(),
+        0,
+    )
+    .expect("commit");
+    assert_eq!(hash, empty_hash);
+
+    // Markers can be removed like any other key.
+    empty_tree
+        .insert_marker(Context::background(), b"key")
+        .expect("insert_marker");
+    check(&empty_tree, true);
+    empty_tree
+        .remove(Context::background(), b"key")
+        .expect("remove");
+    assert!(!empty_tree
+        .contains_key(Context::background(), b"key")
+        .expect("contains_key"));
+}
+
 #[test]
 fn test_value_eviction() {
     let mut tree = Tree::make()
@@ -0,0 +1,93 @@
+//! Root change notifications.
+use std::{
+    ops::Deref,
+    sync::{Arc, Weak},
+};
+
+use crossbeam::channel;
+
+use crate::storage::mkvs::tree::*;
+
+/// Maximum number of roots queued for a subscriber. Once reached, the oldest
+/// roots are dropped.
+pub const ROOT_WATCH_CAPACITY: usize = 16;
+
+/// Receiving end of a root change subscription, see `RootWatchers`.
+///
+/// Dropping the receiver unsubscribes.
+pub struct RootReceiver {
+    rx: channel::Receiver<Root>,
+    _subscribed: Arc<()>,
+}
+
+impl Deref for RootReceiver {
+    type Target = channel::Receiver<Root>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+struct RootWatcher {
+    tx: channel::Sender<Root>,
+    rx: channel::Receiver<Root>,
+    subscribed: Weak<()>,
+}
+
+/// Subscribers to root changes.
+///
+/// At most `ROOT_WATCH_CAPACITY` roots are queued for each subscriber, so
+/// a subscriber that stops reading only misses older roots instead of
+/// growing memory without bound.
+#[derive(Default)]
+pub struct RootWatchers {
+    watchers: Vec<RootWatcher>,
+}
+
+impl RootWatchers {
+    /// Create an empty set of subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a new subscriber.
+    pub fn subscribe(&mut self) -> RootReceiver {
+        let (tx, rx) = channel::bounded(ROOT_WATCH_CAPACITY);
+        let subscribed = Arc::new(());
+        self.watchers.push(RootWatcher {
+            tx,
+            rx: rx.clone(),
+            subscribed: Arc::downgrade(&subscribed),
+        });
+        RootReceiver {
+            rx,
+            _subscribed: subscribed,
+        }
+    }
+
+    /// Send a new root to all subscribers, dropping any that have
+    /// unsubscribed.
+    pub fn notify(&mut self, root: Root) {
+        self.watchers.retain(|watcher| {
+            if watcher.subscribed.strong_count() == 0 {
+                return false;
+            }
+            while let Err(channel::TrySendError::Full(_)) = watcher.tx.try_send(root) {
+                // Make room by dropping the oldest root.
+                let _ = watcher.rx.try_recv();
+            }
+            true
+        });
+    }
+
+    /// Return the number of subscribers. Subscribers that unsubscribed are
+    /// only removed on the next notification.
+    pub fn len(&self) -> usize {
+        self.watchers.len()
+    }
+
+    /// Check whether there are no subscribers.
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+}
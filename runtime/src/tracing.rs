@@ -1,6 +1,8 @@
 //! Tracing helpers.
 use io_context::Context;
 
+use crate::common::crypto::hash::Hash;
+
 const TRACING_SPAN_CONTEXT_KEY: &'static str = "OASIS_TRACING_SPAN_CONTEXT";
 
 /// Add a tracing span context to the provided `Context`.
@@ -12,3 +14,33 @@ pub fn add_span_context(ctx: &mut Context, span_context: Vec<u8>) {
 pub fn get_span_context(ctx: &Context) -> Option<&Vec<u8>> {
     ctx.get_value(TRACING_SPAN_CONTEXT_KEY)
 }
+
+/// A guard for an entered dispatch span. The span is exited when the guard
+/// is dropped.
+///
+/// Spans are only emitted when the `tracing` feature is enabled, otherwise
+/// this is a no-op.
+pub struct SpanGuard {
+    #[cfg(feature = "tracing")]
+    _span: ::tracing::span::EnteredSpan,
+}
+
+/// Enter a span for dispatching the request with the given identifier.
+#[allow(unused_variables)]
+pub fn dispatch_span(id: u64, operation: &'static str) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _span: ::tracing::info_span!("dispatch", id, operation).entered(),
+    }
+}
+
+/// Enter a span for dispatching the transaction batch request with the given
+/// identifier.
+#[allow(unused_variables)]
+pub fn batch_span(id: u64, operation: &'static str, round: u64, io_root: &Hash) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _span: ::tracing::info_span!("dispatch", id, operation, round, io_root = %io_root)
+            .entered(),
+    }
+}
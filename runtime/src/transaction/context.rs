@@ -1,13 +1,47 @@
 //! Runtime call context.
-use std::{any::Any, sync::Arc};
+use std::{
+    any::Any,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use io_context::Context as IoContext;
 
 use super::tags::{Tag, Tags};
-use crate::common::roothash::{Header, Message};
+use crate::{
+    common::roothash::{Header, Message},
+    consensus::ConsensusVerifier,
+    storage::mkvs::{sync::ReadSync, Root, Tree},
+};
 
 struct NoRuntimeContext;
 
+/// Source of the read-only view of the state before the batch, see
+/// `Context::previous_state`.
+#[derive(Clone)]
+pub struct PreviousStateSource {
+    root: Root,
+    read_syncer: Arc<dyn Fn() -> Box<dyn ReadSync>>,
+}
+
+impl PreviousStateSource {
+    /// Create a new previous state source for the given root, using read
+    /// syncers produced by the given function to fetch nodes.
+    pub fn new<F>(root: Root, read_syncer: F) -> Self
+    where
+        F: Fn() -> Box<dyn ReadSync> + 'static,
+    {
+        Self {
+            root,
+            read_syncer: Arc::new(read_syncer),
+        }
+    }
+
+    /// Root of the state before the batch.
+    pub fn root(&self) -> Root {
+        self.root
+    }
+}
+
 /// Transaction context.
 pub struct Context<'a> {
     /// I/O context.
@@ -21,11 +55,27 @@ pub struct Context<'a> {
     /// running the transaction.
     pub check_only: bool,
 
+    /// Verifier of consensus layer state, if one has been configured.
+    pub consensus_verifier: Option<Arc<dyn ConsensusVerifier>>,
+
+    /// Abort batch flag of the dispatcher, if any. Use `AbortPoller` to
+    /// check it from long-running transactions.
+    pub abort_batch: Option<Arc<AtomicBool>>,
+
+    /// Source of the state before the batch, if available.
+    pub previous_state_source: Option<PreviousStateSource>,
+
+    /// Lazily opened tree for the state before the batch.
+    previous_state: Option<Tree>,
+
     /// List of emitted tags for each transaction.
     tags: Vec<Tags>,
 
     /// List of messages emitted.
     messages: Vec<Message>,
+
+    /// Number of messages emitted before the current transaction.
+    transaction_messages: usize,
 }
 
 impl<'a> Context<'a> {
@@ -36,14 +86,46 @@ impl<'a> Context<'a> {
             header,
             runtime: Box::new(NoRuntimeContext),
             check_only,
+            consensus_verifier: None,
+            abort_batch: None,
+            previous_state_source: None,
+            previous_state: None,
             tags: Vec::new(),
             messages: Vec::new(),
+            transaction_messages: 0,
         }
     }
 
+    /// Return a read-only view of the state before the batch, opening it on
+    /// first use. Returns `None` if no previous state source is available.
+    ///
+    /// As the working state tree is modified in place, this is the only way
+    /// to read the original value of a key after it has been overwritten.
+    pub fn previous_state(&mut self) -> Option<&Tree> {
+        if self.previous_state.is_none() {
+            let source = self.previous_state_source.as_ref()?;
+            self.previous_state = Some(
+                Tree::make()
+                    .with_root(source.root)
+                    .new((source.read_syncer)()),
+            );
+        }
+        self.previous_state.as_ref()
+    }
+
     /// Start a new transaction.
     pub fn start_transaction(&mut self) {
         self.tags.push(Tags::new());
+        self.transaction_messages = self.messages.len();
+    }
+
+    /// Discard the tags and roothash messages emitted by the current
+    /// transaction, e.g., because the transaction failed.
+    pub(super) fn discard_transaction(&mut self) {
+        if let Some(tags) = self.tags.last_mut() {
+            tags.clear();
+        }
+        self.messages.truncate(self.transaction_messages);
     }
 
     /// Close the context and return the emitted tags and sent roothash messages.
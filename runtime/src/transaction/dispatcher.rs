@@ -1,13 +1,15 @@
 //! Runtime transaction batch dispatcher.
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context as AnyContext, Result};
+use io_context::Context as IoContext;
 use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
 
@@ -16,13 +18,21 @@ use super::{
     tags::Tags,
     types::{TxnBatch, TxnCall, TxnCheckResult, TxnOutput},
 };
-use crate::common::{cbor, crypto::hash::Hash, roothash::Message as RoothashMessage};
+use crate::{
+    cancellation,
+    common::{cbor, crypto::hash::Hash, roothash::Message as RoothashMessage},
+    storage::StorageContext,
+};
 
 /// Dispatch error.
 #[derive(Error, Debug)]
 enum DispatchError {
     #[error("method not found: {method:?}")]
     MethodNotFound { method: String },
+    #[error("namespace not found: {namespace:?}")]
+    NamespaceNotFound { namespace: String },
+    #[error("batch gas limit exceeded (limit: {limit})")]
+    GasLimitExceeded { limit: u64 },
 }
 
 /// Error indicating that performing a transaction check was successful.
@@ -78,6 +88,141 @@ where
     }
 }
 
+/// Key of the transaction tag recording the gas used by a transaction, as
+/// reported by the gas meter. The value is a big-endian encoded `u64`.
+pub const GAS_USED_TAG_KEY: &[u8] = b"gas_used";
+
+/// Gas meter used to account for the compute consumed by transactions.
+///
+/// A gas meter can be configured on the `Dispatcher` which will consult it
+/// for each transaction in a batch and record the gas used by the transaction
+/// in a transaction tag under `GAS_USED_TAG_KEY`.
+pub trait GasMeter {
+    /// Called before the first transaction in a batch is dispatched.
+    fn start_batch(&self) {}
+
+    /// Called before each transaction is dispatched.
+    fn start_transaction(&self);
+
+    /// Called after each transaction has been dispatched, returning the
+    /// amount of gas used by the transaction.
+    fn end_transaction(&self) -> u64;
+
+    /// Maximum amount of gas that can be used by an executed batch.
+    ///
+    /// Once the gas used by the batch reaches the limit, the remaining
+    /// transactions are not dispatched and the batch fails, so that the
+    /// scheduler can split it into smaller batches. A batch whose last
+    /// transaction reaches the limit still succeeds.
+    fn batch_limit(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Helper for checking whether the current batch should be aborted, i.e.
+/// whether the abort batch flag has been set or the batch context has been
+/// cancelled.
+///
+/// `MethodDispatcher` checks for aborts before each transaction, so abort
+/// latency is bounded by the longest transaction. Transactions doing a lot of
+/// work (e.g., long loops) should poll for aborts themselves, roughly every
+/// millisecond of work, and fail once an abort has been requested:
+///
+/// ```rust,ignore
+/// let mut abort = AbortPoller::new(ctx).check_every(100);
+/// for item in items {
+///     abort.check()?;
+///     process(item);
+/// }
+/// ```
+///
+/// Checking is cheap, but `check_every` and `check_interval` can be used to
+/// reduce its cost further in tight loops. Once an abort has been observed,
+/// all further polls report it.
+pub struct AbortPoller {
+    abort_batch: Option<Arc<AtomicBool>>,
+    io_ctx: Arc<IoContext>,
+    check_every: usize,
+    check_interval: Option<Duration>,
+    polls: usize,
+    last_check: Instant,
+    aborted: bool,
+}
+
+impl AbortPoller {
+    /// Create a new abort poller for the batch of the given context, which
+    /// checks for aborts on every poll.
+    pub fn new(ctx: &Context) -> Self {
+        Self::with_flag(ctx.abort_batch.clone(), ctx.io_ctx.clone())
+    }
+
+    fn with_flag(abort_batch: Option<Arc<AtomicBool>>, io_ctx: Arc<IoContext>) -> Self {
+        Self {
+            abort_batch,
+            io_ctx,
+            check_every: 1,
+            check_interval: None,
+            polls: 0,
+            last_check: Instant::now(),
+            aborted: false,
+        }
+    }
+
+    /// Only check for aborts on every `n`-th poll.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn check_every(mut self, n: usize) -> Self {
+        assert!(n > 0, "check interval must be non-zero");
+        self.check_every = n;
+        self
+    }
+
+    /// Only check for aborts if at least the given time has passed since the
+    /// last check. When combined with `check_every`, the time is only looked
+    /// up on every `n`-th poll.
+    pub fn check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = Some(interval);
+        self
+    }
+
+    /// Return true iff the batch should be aborted.
+    pub fn poll(&mut self) -> bool {
+        if self.aborted {
+            return true;
+        }
+
+        self.polls += 1;
+        if self.polls % self.check_every != 0 {
+            return false;
+        }
+        if let Some(interval) = self.check_interval {
+            let now = Instant::now();
+            if now.duration_since(self.last_check) < interval {
+                return false;
+            }
+            self.last_check = now;
+        }
+
+        self.aborted = self
+            .abort_batch
+            .as_ref()
+            .map(|b| b.load(Ordering::SeqCst))
+            .unwrap_or(false)
+            || cancellation::is_cancelled(&self.io_ctx);
+        self.aborted
+    }
+
+    /// Return an error iff the batch should be aborted.
+    pub fn check(&mut self) -> Result<()> {
+        if self.poll() {
+            return Err(anyhow!("batch aborted"));
+        }
+        Ok(())
+    }
+}
+
 /// Descriptor of a runtime API method.
 #[derive(Clone, Debug)]
 pub struct MethodDescriptor {
@@ -183,6 +328,16 @@ pub trait Dispatcher {
     fn finalize(&self, new_storage_root: Hash);
     /// Configure abort batch flag.
     fn set_abort_batch_flag(&mut self, abort_batch: Arc<AtomicBool>);
+    /// Configure gas meter.
+    ///
+    /// Dispatchers that don't support gas metering ignore the meter.
+    fn set_gas_meter(&mut self, _gas_meter: Arc<dyn GasMeter>) {}
+    /// Whether executed batches may only return results for a prefix of the
+    /// batch, e.g., because the batch has been aborted. The runtime dispatcher
+    /// rejects such results otherwise.
+    fn partial_batches(&self) -> bool {
+        false
+    }
 }
 
 /// No-op dispatcher.
@@ -233,6 +388,12 @@ pub struct MethodDispatcher {
     finalizer: Option<Box<dyn Finalizer>>,
     /// Abort batch flag.
     abort_batch: Option<Arc<AtomicBool>>,
+    /// Whether aborted batches return results for the processed prefix.
+    partial_batches: bool,
+    /// Whether failed transactions are isolated from the rest of the batch.
+    isolate_transactions: bool,
+    /// Gas meter.
+    gas_meter: Option<Arc<dyn GasMeter>>,
 }
 
 impl MethodDispatcher {
@@ -244,6 +405,9 @@ impl MethodDispatcher {
             ctx_initializer: None,
             finalizer: None,
             abort_batch: None,
+            partial_batches: false,
+            isolate_transactions: false,
+            gas_meter: None,
         }
     }
 
@@ -276,9 +440,40 @@ impl MethodDispatcher {
         self.finalizer = Some(Box::new(finalizer));
     }
 
+    /// Configure whether aborted batches should return the results of the
+    /// transactions processed before the abort instead of failing.
+    ///
+    /// This only applies to executed batches, aborted checks always fail.
+    pub fn set_partial_batches(&mut self, enabled: bool) {
+        self.partial_batches = enabled;
+    }
+
+    /// Configure whether executed transactions that fail should have no
+    /// effects on the rest of the batch.
+    ///
+    /// Failed transactions always produce an error output. When enabled, any
+    /// state changes made by a failed transaction are also undone and the
+    /// tags and roothash messages it emitted are discarded, so the batch
+    /// commits as if the transaction only produced its error output.
+    ///
+    /// Executed batches must be dispatched within a storage context.
+    pub fn set_isolate_transactions(&mut self, enabled: bool) {
+        self.isolate_transactions = enabled;
+    }
+
     /// Dispatches a raw runtime invocation request.
     fn dispatch(&self, call: &Vec<u8>, ctx: &mut Context) -> Vec<u8> {
-        let rsp = match self.dispatch_fallible(call, ctx) {
+        let result = if self.isolate_transactions && !ctx.check_only {
+            let result = StorageContext::atomically(|| self.dispatch_fallible(call, ctx));
+            if result.is_err() {
+                ctx.discard_transaction();
+            }
+            result
+        } else {
+            self.dispatch_fallible(call, ctx)
+        };
+
+        let rsp = match result {
             Ok(response) => TxnOutput::Success(response),
             Err(error) => match error.downcast::<CheckOnlySuccess>() {
                 Ok(check_result) => TxnOutput::Success(cbor::to_value(check_result.0)),
@@ -308,6 +503,7 @@ impl Dispatcher for MethodDispatcher {
         batch: &TxnBatch,
         mut ctx: Context,
     ) -> Result<(TxnBatch, Vec<Tags>, Vec<RoothashMessage>)> {
+        ctx.abort_batch = self.abort_batch.clone();
         if let Some(ref ctx_init) = self.ctx_initializer {
             ctx_init.init(&mut ctx);
         }
@@ -317,19 +513,42 @@ impl Dispatcher for MethodDispatcher {
             handler.start_batch(&mut ctx);
         }
 
+        if let Some(ref gas_meter) = self.gas_meter {
+            gas_meter.start_batch();
+        }
+        let gas_limit = match self.gas_meter {
+            Some(ref gas_meter) if !ctx.check_only => gas_meter.batch_limit(),
+            _ => None,
+        };
+        let mut gas_used: u64 = 0;
+
         // Process batch.
+        let mut abort = AbortPoller::new(&ctx);
         let mut vec = Vec::new();
         for call in batch.iter() {
-            if self
-                .abort_batch
-                .as_ref()
-                .map(|b| b.load(Ordering::SeqCst))
-                .unwrap_or(false)
-            {
+            if let Some(gas_limit) = gas_limit {
+                if gas_used >= gas_limit {
+                    return Err(DispatchError::GasLimitExceeded { limit: gas_limit }.into());
+                }
+            }
+
+            if abort.poll() {
+                if self.partial_batches && !ctx.check_only {
+                    break;
+                }
                 return Err(anyhow!("batch aborted"));
             }
             ctx.start_transaction();
-            vec.push(self.dispatch(call, &mut ctx));
+            if let Some(ref gas_meter) = self.gas_meter {
+                gas_meter.start_transaction();
+                vec.push(self.dispatch(call, &mut ctx));
+
+                let tx_gas_used = gas_meter.end_transaction();
+                gas_used = gas_used.saturating_add(tx_gas_used);
+                ctx.emit_txn_tag(GAS_USED_TAG_KEY, tx_gas_used.to_be_bytes());
+            } else {
+                vec.push(self.dispatch(call, &mut ctx));
+            }
         }
         let outputs = TxnBatch::new(vec);
 
@@ -352,14 +571,232 @@ impl Dispatcher for MethodDispatcher {
     fn set_abort_batch_flag(&mut self, abort_batch: Arc<AtomicBool>) {
         self.abort_batch = Some(abort_batch);
     }
+
+    /// Configure gas meter.
+    fn set_gas_meter(&mut self, gas_meter: Arc<dyn GasMeter>) {
+        self.gas_meter = Some(gas_meter);
+    }
+
+    fn partial_batches(&self) -> bool {
+        self.partial_batches
+    }
+}
+
+/// Runtime namespace dispatcher.
+///
+/// A composite dispatcher which allows a runtime to host several logical
+/// modules, each with its own dispatcher. Every transaction is routed to the
+/// dispatcher registered for the namespace of the called method, which is
+/// the part of the method name before the first `.` (e.g., `kv` for the
+/// `kv.insert` method).
+///
+/// Transactions are executed in the same order as the inputs, each run of
+/// consecutive transactions with the same namespace being dispatched as a
+/// single sub-batch. Transactions calling a method in an unknown namespace
+/// are rejected with an error output.
+///
+/// The abort batch flag and the gas meter are passed through to all
+/// registered dispatchers, with the gas limit applying to the batch as
+/// a whole. If a dispatcher stops early (e.g., because the batch has been
+/// aborted), only the results of the processed prefix are returned.
+pub struct NamespaceDispatcher {
+    /// Registered dispatchers, keyed by namespace.
+    dispatchers: BTreeMap<String, Box<dyn Dispatcher>>,
+    /// Abort batch flag.
+    abort_batch: Option<Arc<AtomicBool>>,
+    /// Gas meter shared by all registered dispatchers.
+    gas_meter: Option<Arc<NamespaceGasMeter>>,
+}
+
+impl NamespaceDispatcher {
+    /// Create a new runtime namespace dispatcher instance.
+    pub fn new() -> NamespaceDispatcher {
+        NamespaceDispatcher {
+            dispatchers: BTreeMap::new(),
+            abort_batch: None,
+            gas_meter: None,
+        }
+    }
+
+    /// Register a dispatcher for the given namespace.
+    pub fn add_dispatcher(&mut self, namespace: &str, mut dispatcher: Box<dyn Dispatcher>) {
+        if let Some(ref abort_batch) = self.abort_batch {
+            dispatcher.set_abort_batch_flag(abort_batch.clone());
+        }
+        if let Some(ref gas_meter) = self.gas_meter {
+            dispatcher.set_gas_meter(gas_meter.clone());
+        }
+        self.dispatchers.insert(namespace.to_owned(), dispatcher);
+    }
+
+    /// Determine the namespace of the given raw call.
+    fn route(&self, call: &Vec<u8>) -> Result<&str> {
+        let call: TxnCall = cbor::from_slice(call).context("unable to parse call")?;
+        let namespace = call.method.split('.').next().unwrap_or_default();
+
+        match self.dispatchers.get_key_value(namespace) {
+            Some((namespace, _)) => Ok(namespace.as_str()),
+            None => Err(DispatchError::NamespaceNotFound {
+                namespace: namespace.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Dispatcher for NamespaceDispatcher {
+    fn dispatch_batch(
+        &self,
+        batch: &TxnBatch,
+        ctx: Context,
+    ) -> Result<(TxnBatch, Vec<Tags>, Vec<RoothashMessage>)> {
+        if let Some(ref gas_meter) = self.gas_meter {
+            gas_meter.start_batch_once();
+        }
+
+        let routes: Vec<Result<&str>> = batch.iter().map(|call| self.route(call)).collect();
+
+        let header = ctx.header;
+        let check_only = ctx.check_only;
+        let io_ctx = ctx.io_ctx.clone();
+        let consensus_verifier = ctx.consensus_verifier.clone();
+        let abort_batch = ctx.abort_batch.clone();
+        let previous_state_source = ctx.previous_state_source.clone();
+        let (_, mut messages) = ctx.close();
+
+        let mut outputs = Vec::with_capacity(batch.len());
+        let mut tags = Vec::with_capacity(batch.len());
+        let mut start = 0;
+        while start < batch.len() {
+            let namespace = match routes[start] {
+                Ok(namespace) => namespace,
+                Err(ref error) => {
+                    outputs.push(cbor::to_vec(&TxnOutput::Error(format!("{}", error))));
+                    tags.push(Tags::new());
+                    start += 1;
+                    continue;
+                }
+            };
+
+            // Dispatch the run of consecutive calls to the same namespace.
+            let mut end = start + 1;
+            while end < batch.len() && matches!(routes[end], Ok(ns) if ns == namespace) {
+                end += 1;
+            }
+            let sub_batch = TxnBatch::new(batch[start..end].to_vec());
+            let mut sub_ctx = Context::new(io_ctx.clone(), header, check_only);
+            sub_ctx.consensus_verifier = consensus_verifier.clone();
+            sub_ctx.abort_batch = abort_batch.clone();
+            sub_ctx.previous_state_source = previous_state_source.clone();
+            let (sub_outputs, sub_tags, sub_messages) =
+                self.dispatchers[namespace].dispatch_batch(&sub_batch, sub_ctx)?;
+            if sub_outputs.len() > sub_batch.len() || sub_tags.len() != sub_outputs.len() {
+                return Err(anyhow!(
+                    "dispatcher for namespace {:?} returned malformed results",
+                    namespace
+                ));
+            }
+
+            let stopped = sub_outputs.len() < sub_batch.len();
+            let sub_outputs: Vec<Vec<u8>> = sub_outputs.into();
+            outputs.extend(sub_outputs);
+            tags.extend(sub_tags);
+            messages.extend(sub_messages);
+            if stopped {
+                // The dispatcher stopped early, so the remaining calls must
+                // not be executed.
+                break;
+            }
+            start = end;
+        }
+
+        Ok((TxnBatch::new(outputs), tags, messages))
+    }
+
+    fn finalize(&self, new_storage_root: Hash) {
+        for dispatcher in self.dispatchers.values() {
+            dispatcher.finalize(new_storage_root);
+        }
+    }
+
+    /// Configure abort batch flag.
+    fn set_abort_batch_flag(&mut self, abort_batch: Arc<AtomicBool>) {
+        for dispatcher in self.dispatchers.values_mut() {
+            dispatcher.set_abort_batch_flag(abort_batch.clone());
+        }
+        self.abort_batch = Some(abort_batch);
+    }
+
+    /// Configure gas meter.
+    fn set_gas_meter(&mut self, gas_meter: Arc<dyn GasMeter>) {
+        let gas_meter = Arc::new(NamespaceGasMeter {
+            inner: gas_meter,
+            used: AtomicU64::new(0),
+        });
+        for dispatcher in self.dispatchers.values_mut() {
+            dispatcher.set_gas_meter(gas_meter.clone());
+        }
+        self.gas_meter = Some(gas_meter);
+    }
+
+    fn partial_batches(&self) -> bool {
+        self.dispatchers
+            .values()
+            .any(|dispatcher| dispatcher.partial_batches())
+    }
+}
+
+/// Gas meter passed to the dispatchers of a `NamespaceDispatcher`.
+///
+/// As each run of a batch is dispatched separately, the batch is only
+/// started once and the batch limit is reduced by the gas used by
+/// previous runs.
+struct NamespaceGasMeter {
+    inner: Arc<dyn GasMeter>,
+    used: AtomicU64,
+}
+
+impl NamespaceGasMeter {
+    fn start_batch_once(&self) {
+        self.used.store(0, Ordering::SeqCst);
+        self.inner.start_batch();
+    }
+}
+
+impl GasMeter for NamespaceGasMeter {
+    fn start_batch(&self) {
+        // Started by the namespace dispatcher, see `start_batch_once`.
+    }
+
+    fn start_transaction(&self) {
+        self.inner.start_transaction();
+    }
+
+    fn end_transaction(&self) -> u64 {
+        let tx_gas_used = self.inner.end_transaction();
+        let used = self.used.load(Ordering::SeqCst).saturating_add(tx_gas_used);
+        self.used.store(used, Ordering::SeqCst);
+        tx_gas_used
+    }
+
+    fn batch_limit(&self) -> Option<u64> {
+        self.inner
+            .batch_limit()
+            .map(|limit| limit.saturating_sub(self.used.load(Ordering::SeqCst)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
     use io_context::Context as IoContext;
     use serde::{Deserialize, Serialize};
 
-    use crate::common::{cbor, roothash::Header};
+    use crate::{
+        common::{cbor, roothash::Header},
+        storage::KeyValue,
+    };
 
     use super::*;
 
@@ -371,6 +808,26 @@ mod tests {
         number: u32,
     }
 
+    struct NoopKeyValue;
+
+    impl KeyValue for NoopKeyValue {
+        fn get(&self, _key: Vec<u8>) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn insert(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Encode a call of the given method.
+    fn make_call<T: Serialize>(method: &str, args: T) -> Vec<u8> {
+        cbor::to_vec(&TxnCall {
+            method: method.to_owned(),
+            args: cbor::to_value(args),
+        })
+    }
+
     /// Register a dummy method.
     fn register_dummy_method(dispatcher: &mut MethodDispatcher) {
         // Register dummy runtime method.
@@ -430,4 +887,441 @@ mod tests {
             _ => panic!("txn call should return success"),
         }
     }
+
+    #[test]
+    fn test_dispatcher_cancelled() {
+        let mut dispatcher = MethodDispatcher::new();
+        register_dummy_method(&mut dispatcher);
+
+        let call = TxnCall {
+            method: "dummy".to_owned(),
+            args: cbor::to_value(Complex {
+                text: "hello".to_owned(),
+                number: 21,
+            }),
+        };
+        let batch = TxnBatch::new(vec![cbor::to_vec(&call)]);
+
+        let header = Header {
+            timestamp: TEST_TIMESTAMP,
+            ..Default::default()
+        };
+        let mut io_ctx = IoContext::background();
+        let signal = cancellation::add_cancel_signal(&mut io_ctx);
+        let io_ctx = io_ctx.freeze();
+
+        // Dispatching under a non-cancelled context should succeed.
+        let ctx = Context::new(io_ctx.clone(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_ok());
+
+        // Cancelling the parent context should abort the batch.
+        signal.cancel();
+        let ctx = Context::new(IoContext::create_child(&io_ctx).freeze(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+    }
+
+    #[test]
+    fn test_dispatcher_partial_batch() {
+        let abort_batch = Arc::new(AtomicBool::new(false));
+        let mut dispatcher = MethodDispatcher::new();
+        {
+            let abort_batch = abort_batch.clone();
+            dispatcher.add_method(Method::new(
+                MethodDescriptor {
+                    name: "abort".to_owned(),
+                },
+                move |call: &u32, _ctx: &mut Context| -> Result<u32> {
+                    abort_batch.store(true, Ordering::SeqCst);
+                    Ok(*call)
+                },
+            ));
+        }
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "noop".to_owned(),
+            },
+            |call: &u32, _ctx: &mut Context| -> Result<u32> { Ok(*call) },
+        ));
+        dispatcher.set_abort_batch_flag(abort_batch.clone());
+
+        let batch = TxnBatch::new(vec![
+            make_call("noop", 1),
+            make_call("abort", 2),
+            make_call("noop", 3),
+        ]);
+        let header = Header::default();
+
+        // Without partial batches the whole batch should fail.
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+
+        // With partial batches the processed prefix should be returned.
+        assert!(!dispatcher.partial_batches());
+        dispatcher.set_partial_batches(true);
+        assert!(dispatcher.partial_batches());
+        abort_batch.store(false, Ordering::SeqCst);
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        let (outputs, tags, _) = dispatcher.dispatch_batch(&batch, ctx).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(tags.len(), 2);
+
+        // Checks should still fail.
+        abort_batch.store(false, Ordering::SeqCst);
+        let ctx = Context::new(IoContext::background().freeze(), &header, true);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+    }
+
+    #[test]
+    fn test_dispatcher_gas_meter() {
+        struct FixedGasMeter {
+            started: std::cell::Cell<usize>,
+        }
+
+        impl GasMeter for FixedGasMeter {
+            fn start_transaction(&self) {
+                self.started.set(self.started.get() + 1);
+            }
+
+            fn end_transaction(&self) -> u64 {
+                3
+            }
+
+            fn batch_limit(&self) -> Option<u64> {
+                Some(5)
+            }
+        }
+
+        let gas_meter = Arc::new(FixedGasMeter {
+            started: std::cell::Cell::new(0),
+        });
+        let mut dispatcher = MethodDispatcher::new();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "noop".to_owned(),
+            },
+            |call: &u32, _ctx: &mut Context| -> Result<u32> { Ok(*call) },
+        ));
+        dispatcher.set_gas_meter(gas_meter.clone());
+
+        let call = make_call("noop", 0u32);
+        let batch = TxnBatch::new(vec![call.clone(), call.clone(), call]);
+        let header = Header::default();
+
+        // A batch limit never results in partial batches.
+        assert!(!dispatcher.partial_batches());
+
+        // Executed batches should fail once the limit has been reached with
+        // transactions remaining.
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+        assert_eq!(gas_meter.started.get(), 2);
+
+        // The last transaction may reach the limit.
+        let short_batch = TxnBatch::new(batch[..2].to_vec());
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        let (outputs, tags, _) = dispatcher.dispatch_batch(&short_batch, ctx).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(tags.len(), 2);
+        for tx_tags in tags {
+            assert_eq!(tx_tags.len(), 1);
+            assert_eq!(tx_tags[0].key, GAS_USED_TAG_KEY);
+            assert_eq!(tx_tags[0].value, 3u64.to_be_bytes());
+        }
+
+        // Checks should not be limited.
+        let ctx = Context::new(IoContext::background().freeze(), &header, true);
+        let (outputs, _, _) = dispatcher.dispatch_batch(&batch, ctx).unwrap();
+        assert_eq!(outputs.len(), 3);
+    }
+
+    #[test]
+    fn test_dispatcher_isolate_transactions() {
+        use crate::storage::mkvs::Tree;
+
+        let mut dispatcher = MethodDispatcher::new();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "write".to_owned(),
+            },
+            |call: &(String, bool), ctx: &mut Context| -> Result<()> {
+                StorageContext::with_current(|mkvs, _| {
+                    mkvs.insert(IoContext::background(), call.0.as_bytes(), b"value");
+                });
+                ctx.emit_txn_tag(b"written", call.0.as_bytes());
+                if call.1 {
+                    return Err(anyhow!("write failed"));
+                }
+                Ok(())
+            },
+        ));
+        dispatcher.set_isolate_transactions(true);
+
+        let batch = TxnBatch::new(vec![
+            make_call("write", ("first", false)),
+            make_call("write", ("second", true)),
+            make_call("write", ("third", false)),
+        ]);
+        let header = Header::default();
+
+        let mut tree = Tree::new_in_memory();
+        let (outputs, tags, _) = StorageContext::enter(&mut tree, Arc::new(NoopKeyValue), || {
+            let ctx = Context::new(IoContext::background().freeze(), &header, false);
+            dispatcher.dispatch_batch(&batch, ctx).unwrap()
+        });
+
+        // The failed transaction should only produce an error output.
+        let outputs: Vec<TxnOutput> = outputs
+            .iter()
+            .map(|output| cbor::from_slice(output).unwrap())
+            .collect();
+        assert!(matches!(outputs[0], TxnOutput::Success(_)));
+        assert!(matches!(outputs[1], TxnOutput::Error(_)));
+        assert!(matches!(outputs[2], TxnOutput::Success(_)));
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags[0].len(), 1);
+        assert!(tags[1].is_empty());
+        assert_eq!(tags[2].len(), 1);
+
+        let (write_log, _) = tree
+            .commit(IoContext::background(), Default::default(), 0)
+            .unwrap();
+        let keys: Vec<Vec<u8>> = write_log.into_iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec![b"first".to_vec(), b"third".to_vec()]);
+    }
+
+    #[test]
+    fn test_dispatcher_previous_state() {
+        use crate::{
+            storage::mkvs::{sync::NoopReadSyncer, Root, Tree},
+            transaction::PreviousStateSource,
+        };
+
+        let mut dispatcher = MethodDispatcher::new();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "overwrite".to_owned(),
+            },
+            |_call: &(), ctx: &mut Context| -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+                StorageContext::with_current(|mkvs, _| {
+                    mkvs.insert(IoContext::background(), b"key", b"new");
+                });
+                let current = StorageContext::with_current(|mkvs, _| {
+                    mkvs.get(IoContext::background(), b"key")
+                });
+                let previous = ctx
+                    .previous_state()
+                    .expect("previous state should be available")
+                    .get(IoContext::background(), b"key")?;
+                Ok((current, previous))
+            },
+        ));
+
+        let batch = TxnBatch::new(vec![make_call("overwrite", ())]);
+        let header = Header::default();
+
+        // The previous state is empty.
+        let mut tree = Tree::new_in_memory();
+        let (outputs, _, _) = StorageContext::enter(&mut tree, Arc::new(NoopKeyValue), || {
+            let mut ctx = Context::new(IoContext::background().freeze(), &header, false);
+            ctx.previous_state_source = Some(PreviousStateSource::new(
+                Root {
+                    hash: Hash::empty_hash(),
+                    ..Default::default()
+                },
+                || Box::new(NoopReadSyncer),
+            ));
+            dispatcher.dispatch_batch(&batch, ctx).unwrap()
+        });
+
+        let output: TxnOutput = cbor::from_slice(&outputs[0]).unwrap();
+        match output {
+            TxnOutput::Success(value) => {
+                let (current, previous): (Option<Vec<u8>>, Option<Vec<u8>>) =
+                    cbor::from_value(value).unwrap();
+                assert_eq!(current, Some(b"new".to_vec()));
+                assert_eq!(previous, None);
+            }
+            _ => panic!("txn call should return success"),
+        }
+    }
+
+    #[test]
+    fn test_abort_poller() {
+        let abort_batch = Arc::new(AtomicBool::new(false));
+        let io_ctx = IoContext::background().freeze();
+        let mut poller =
+            AbortPoller::with_flag(Some(abort_batch.clone()), io_ctx.clone()).check_every(3);
+
+        assert!(!poller.poll());
+        abort_batch.store(true, Ordering::SeqCst);
+        // Only every third poll checks the flag.
+        assert!(!poller.poll());
+        assert!(poller.poll());
+        // Aborts are sticky.
+        abort_batch.store(false, Ordering::SeqCst);
+        assert!(poller.poll());
+        assert!(poller.check().is_err());
+
+        // Cancelling the context aborts as well.
+        let mut io_ctx = IoContext::background();
+        let signal = cancellation::add_cancel_signal(&mut io_ctx);
+        let mut poller = AbortPoller::with_flag(None, io_ctx.freeze());
+        assert!(poller.check().is_ok());
+        signal.cancel();
+        assert!(poller.check().is_err());
+
+        // With a check interval, the flag is not checked before it elapses.
+        let abort_batch = Arc::new(AtomicBool::new(true));
+        let mut poller =
+            AbortPoller::with_flag(Some(abort_batch), IoContext::background().freeze())
+                .check_interval(Duration::from_secs(3600));
+        assert!(!poller.poll());
+    }
+
+    #[test]
+    fn test_namespace_dispatcher() {
+        let mut first = MethodDispatcher::new();
+        first.add_method(Method::new(
+            MethodDescriptor {
+                name: "first.double".to_owned(),
+            },
+            |call: &u32, ctx: &mut Context| -> Result<u32> {
+                ctx.emit_txn_tag(b"first", b"tag");
+                Ok(call * 2)
+            },
+        ));
+        let mut second = MethodDispatcher::new();
+        second.add_method(Method::new(
+            MethodDescriptor {
+                name: "second.triple".to_owned(),
+            },
+            |call: &u32, _ctx: &mut Context| -> Result<u32> { Ok(call * 3) },
+        ));
+
+        let mut dispatcher = NamespaceDispatcher::new();
+        dispatcher.add_dispatcher("first", Box::new(first));
+        dispatcher.add_dispatcher("second", Box::new(second));
+
+        let batch = TxnBatch::new(vec![
+            make_call("second.triple", 1),
+            make_call("first.double", 2),
+            make_call("third.nothing", 3),
+            make_call("second.triple", 4),
+        ]);
+
+        let header = Header::default();
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        let (outputs, tags, messages) = dispatcher.dispatch_batch(&batch, ctx).unwrap();
+        assert_eq!(outputs.len(), batch.len());
+        assert_eq!(tags.len(), batch.len());
+        assert!(messages.is_empty());
+
+        let outputs: Vec<TxnOutput> = outputs
+            .iter()
+            .map(|output| cbor::from_slice(output).unwrap())
+            .collect();
+        let expect_success = |output: &TxnOutput, expected: u32| match output {
+            TxnOutput::Success(value) => {
+                let value: u32 = cbor::from_value(value.clone()).unwrap();
+                assert_eq!(value, expected);
+            }
+            _ => panic!("txn call should return success"),
+        };
+        expect_success(&outputs[0], 3);
+        expect_success(&outputs[1], 4);
+        match outputs[2] {
+            TxnOutput::Error(_) => {}
+            _ => panic!("txn call to unknown namespace should return an error"),
+        }
+        expect_success(&outputs[3], 12);
+
+        assert!(tags[0].is_empty());
+        assert_eq!(tags[1].len(), 1);
+        assert!(tags[2].is_empty());
+        assert!(tags[3].is_empty());
+    }
+
+    #[test]
+    fn test_namespace_dispatcher_order() {
+        struct FixedGasMeter;
+
+        impl GasMeter for FixedGasMeter {
+            fn start_transaction(&self) {}
+
+            fn end_transaction(&self) -> u64 {
+                2
+            }
+
+            fn batch_limit(&self) -> Option<u64> {
+                Some(9)
+            }
+        }
+
+        // Both namespaces record the order in which calls are executed and
+        // emit a tag for each call.
+        let executed = Rc::new(RefCell::new(Vec::new()));
+        let make_dispatcher = |name: &'static str| {
+            let executed = executed.clone();
+            let mut dispatcher = MethodDispatcher::new();
+            dispatcher.add_method(Method::new(
+                MethodDescriptor {
+                    name: format!("{}.record", name),
+                },
+                move |call: &u32, ctx: &mut Context| -> Result<u32> {
+                    executed.borrow_mut().push(*call);
+                    ctx.emit_txn_tag(b"call", call.to_be_bytes());
+                    Ok(*call)
+                },
+            ));
+            Box::new(dispatcher)
+        };
+        let mut dispatcher = NamespaceDispatcher::new();
+        dispatcher.add_dispatcher("first", make_dispatcher("first"));
+        dispatcher.add_dispatcher("second", make_dispatcher("second"));
+
+        let batch = TxnBatch::new(vec![
+            make_call("second.record", 0),
+            make_call("first.record", 1),
+            make_call("first.record", 2),
+            make_call("third.nothing", 3),
+            make_call("second.record", 4),
+            make_call("first.record", 5),
+            make_call("second.record", 6),
+        ]);
+        let header = Header::default();
+
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        let (outputs, tags, _) = dispatcher.dispatch_batch(&batch, ctx).unwrap();
+        assert_eq!(*executed.borrow(), vec![0, 1, 2, 4, 5, 6]);
+        assert_eq!(outputs.len(), batch.len());
+        assert_eq!(tags.len(), batch.len());
+        for (index, (output, tx_tags)) in outputs.iter().zip(tags).enumerate() {
+            match cbor::from_slice(output).unwrap() {
+                TxnOutput::Success(value) => {
+                    let value: u32 = cbor::from_value(value).unwrap();
+                    assert_eq!(value as usize, index);
+                    assert_eq!(tx_tags.len(), 1);
+                    assert_eq!(tx_tags[0].value, value.to_be_bytes());
+                }
+                TxnOutput::Error(_) => {
+                    assert_eq!(index, 3);
+                    assert!(tx_tags.is_empty());
+                }
+            }
+        }
+
+        // The gas limit applies to the batch as a whole, failing the batch
+        // once it has been reached.
+        dispatcher.set_gas_meter(Arc::new(FixedGasMeter));
+        executed.borrow_mut().clear();
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+        assert_eq!(*executed.borrow(), vec![0, 1, 2, 4, 5]);
+
+        // The abort flag is passed through.
+        let abort_batch = Arc::new(AtomicBool::new(true));
+        dispatcher.set_abort_batch_flag(abort_batch);
+        let ctx = Context::new(IoContext::background().freeze(), &header, false);
+        assert!(dispatcher.dispatch_batch(&batch, ctx).is_err());
+    }
 }
@@ -9,4 +9,4 @@ pub mod tree;
 pub mod types;
 
 // Re-exports.
-pub use self::context::Context;
+pub use self::context::{Context, PreviousStateSource};
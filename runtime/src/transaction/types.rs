@@ -1,13 +1,16 @@
 //! Transaction protocol types.
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     ops::{Deref, DerefMut},
 };
 
 use serde::{Deserialize, Serialize};
 
 use super::rwset::ReadWriteSet;
-use crate::common::cbor::Value;
+use crate::common::{
+    cbor::{self, Value},
+    crypto::hash::Hash,
+};
 
 /// Transaction call.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -71,6 +74,25 @@ impl TxnBatch {
     pub fn new(txs: Vec<Vec<u8>>) -> TxnBatch {
         TxnBatch(txs)
     }
+
+    /// Return the hash of the first transaction that appears more than once
+    /// in the batch (if any).
+    ///
+    /// Transaction artifacts are keyed by transaction hash, so batches with
+    /// duplicate transactions cannot be executed.
+    pub fn find_duplicate(&self) -> Option<Hash> {
+        let mut seen = HashSet::new();
+        self.0
+            .iter()
+            .map(|tx| Hash::digest_bytes(tx))
+            .find(|tx_hash| !seen.insert(*tx_hash))
+    }
+
+    /// Return the hash of the batch, which commits to the order of the
+    /// transactions.
+    pub fn hash(&self) -> Hash {
+        Hash::digest_bytes(&cbor::to_vec(self))
+    }
 }
 
 impl Deref for TxnBatch {
@@ -115,7 +137,7 @@ impl Into<VecDeque<Vec<u8>>> for TxnBatch {
 mod tests {
     use super::*;
 
-    use crate::common::{cbor, crypto::hash::Hash};
+    use crate::common::cbor;
 
     #[test]
     fn test_consistent_hash() {
@@ -125,5 +147,18 @@ mod tests {
             h,
             Hash::from("c451dd4fd065b815e784aac6b300e479b2167408f0eebbb95a8bd36b9e71e34d")
         );
+        assert_eq!(batch.hash(), h);
+
+        let reordered = TxnBatch(vec![b"bar".to_vec(), b"foo".to_vec(), b"aaa".to_vec()]);
+        assert_ne!(reordered.hash(), h, "hash should commit to the order");
+    }
+
+    #[test]
+    fn test_find_duplicate() {
+        let batch = TxnBatch(vec![b"foo".to_vec(), b"bar".to_vec()]);
+        assert_eq!(batch.find_duplicate(), None);
+
+        let batch = TxnBatch(vec![b"foo".to_vec(), b"bar".to_vec(), b"foo".to_vec()]);
+        assert_eq!(batch.find_duplicate(), Some(Hash::digest_bytes(b"foo")));
     }
 }
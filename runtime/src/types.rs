@@ -1,4 +1,10 @@
 //! Types used by the worker-host protocol.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use rustc_hex::ToHex;
 use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes;
 
@@ -12,12 +18,12 @@ use crate::{
         runtime::RuntimeId,
         sgx::avr::AVR,
     },
-    storage::mkvs::{sync, WriteLog},
+    storage::mkvs::{compose_write_logs, sync, WriteLog},
     transaction::types::TxnBatch,
 };
 
 /// Computed batch.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ComputedBatch {
     /// Compute results header.
     pub header: ComputeResultsHeader,
@@ -28,6 +34,106 @@ pub struct ComputedBatch {
     /// If this runtime uses a TEE, then this is the signature of the batch's
     /// BatchSigMessage with the node's RAK for this runtime.
     pub rak_sig: Signature,
+    /// Whether the batch was aborted and only contains the results of a
+    /// prefix of the input batch.
+    ///
+    /// In this case both the I/O and the state root only reflect the
+    /// transactions in that prefix. Omitted from the encoding if false.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub partial: bool,
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+impl ComputedBatch {
+    /// Report where this batch diverges from another batch computed for the
+    /// same inputs, e.g., when debugging a discrepancy between two nodes.
+    ///
+    /// The RAK signatures are not compared as they differ between nodes.
+    pub fn diff(&self, other: &ComputedBatch) -> BatchDiff {
+        let (a, b) = (&self.header, &other.header);
+        let mut header_fields = Vec::new();
+        if a.round != b.round {
+            header_fields.push("round");
+        }
+        if a.previous_hash != b.previous_hash {
+            header_fields.push("previous_hash");
+        }
+        if a.io_root != b.io_root {
+            header_fields.push("io_root");
+        }
+        if a.state_root != b.state_root {
+            header_fields.push("state_root");
+        }
+        if a.messages != b.messages {
+            header_fields.push("messages");
+        }
+
+        BatchDiff {
+            header_fields,
+            partial: self.partial != other.partial,
+            state_keys: diverging_keys(&self.state_write_log, &other.state_write_log),
+        }
+    }
+}
+
+/// Return the keys for which applying the given write logs results in
+/// different values.
+fn diverging_keys(a: &WriteLog, b: &WriteLog) -> Vec<Vec<u8>> {
+    let apply = |log: &WriteLog| -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        compose_write_logs(Vec::new(), log.clone())
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect()
+    };
+    let (a, b) = (apply(a), apply(b));
+    let keys: BTreeSet<&Vec<u8>> = a.keys().chain(b.keys()).collect();
+
+    keys.into_iter()
+        .filter(|key| a.get(*key) != b.get(*key))
+        .cloned()
+        .collect()
+}
+
+/// Differences between two computed batches, see `ComputedBatch::diff`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchDiff {
+    /// Names of the compute results header fields that differ.
+    pub header_fields: Vec<&'static str>,
+    /// Whether exactly one of the batches is partial.
+    pub partial: bool,
+    /// Keys (in order) where the resulting state diverges.
+    pub state_keys: Vec<Vec<u8>>,
+}
+
+impl BatchDiff {
+    /// Return true iff the batches do not diverge.
+    pub fn is_empty(&self) -> bool {
+        self.header_fields.is_empty() && !self.partial && self.state_keys.is_empty()
+    }
+}
+
+impl fmt::Display for BatchDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "batches do not diverge");
+        }
+        if !self.header_fields.is_empty() {
+            writeln!(f, "header fields differ: {}", self.header_fields.join(", "))?;
+        }
+        if self.partial {
+            writeln!(f, "only one of the batches is partial")?;
+        }
+        if !self.state_keys.is_empty() {
+            writeln!(f, "state diverges at {} key(s):", self.state_keys.len())?;
+            for key in &self.state_keys {
+                writeln!(f, "  {}", key.to_hex::<String>())?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Storage sync request.
@@ -115,6 +221,10 @@ pub enum Body {
         io_root: Hash,
         inputs: TxnBatch,
         block: Block,
+        /// Optional hash of the inputs in scheduler order (see `TxnBatch::hash`),
+        /// allowing misordered inputs to be rejected before execution.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        inputs_hash: Option<Hash>,
     },
     RuntimeExecuteTxBatchResponse {
         batch: ComputedBatch,
@@ -123,7 +233,14 @@ pub enum Body {
         #[serde(with = "serde_bytes")]
         signed_policy_raw: Vec<u8>,
     },
-    RuntimeKeyManagerPolicyUpdateResponse {},
+    RuntimeKeyManagerPolicyUpdateResponse {
+        #[serde(default)]
+        unchanged: bool,
+    },
+    RuntimeConsensusSyncRequest {
+        height: u64,
+    },
+    RuntimeConsensusSyncResponse {},
 
     // Host interface.
     HostRPCCallRequest {
@@ -210,3 +327,84 @@ pub struct Message {
     #[serde(with = "serde_bytes")]
     pub span_context: Vec<u8>,
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{common::cbor, storage::mkvs::LogEntry};
+
+    use super::*;
+
+    fn computed_batch(state_root: Hash, state_write_log: WriteLog) -> ComputedBatch {
+        ComputedBatch {
+            header: ComputeResultsHeader {
+                round: 1,
+                state_root: Some(state_root),
+                ..Default::default()
+            },
+            io_write_log: WriteLog::new(),
+            state_write_log,
+            rak_sig: Signature::default(),
+            partial: false,
+        }
+    }
+
+    #[test]
+    fn test_computed_batch_partial_encoding() {
+        let has_partial = |batch: &ComputedBatch| match cbor::to_value(batch) {
+            cbor::Value::Map(fields) => {
+                fields.contains_key(&cbor::Value::Text("partial".to_owned()))
+            }
+            _ => panic!("computed batch should encode as a map"),
+        };
+
+        // Complete batches keep the encoding used before partial batches.
+        let mut batch = computed_batch(Hash::digest_bytes(b"a"), WriteLog::new());
+        assert!(!has_partial(&batch));
+        let decoded: ComputedBatch = cbor::from_slice(&cbor::to_vec(&batch)).unwrap();
+        assert!(!decoded.partial);
+
+        batch.partial = true;
+        assert!(has_partial(&batch));
+        let decoded: ComputedBatch = cbor::from_slice(&cbor::to_vec(&batch)).unwrap();
+        assert!(decoded.partial);
+    }
+
+    #[test]
+    fn test_computed_batch_diff() {
+        let a = computed_batch(
+            Hash::digest_bytes(b"a"),
+            vec![
+                LogEntry::new(b"same", b"value"),
+                LogEntry::new(b"changed", b"a"),
+                LogEntry::new(b"only a", b"value"),
+            ],
+        );
+        assert!(a.diff(&a).is_empty());
+
+        let b = computed_batch(
+            Hash::digest_bytes(b"b"),
+            vec![
+                LogEntry::new(b"changed", b"b"),
+                LogEntry::new(b"same", b"value"),
+                LogEntry {
+                    key: b"only b".to_vec(),
+                    value: None,
+                    marker: false,
+                },
+            ],
+        );
+        let diff = a.diff(&b);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.header_fields, vec!["state_root"]);
+        assert!(!diff.partial);
+        assert_eq!(
+            diff.state_keys,
+            vec![b"changed".to_vec(), b"only a".to_vec(), b"only b".to_vec()]
+        );
+        assert_eq!(
+            format!("{}", diff),
+            "header fields differ: state_root\n\
+             state diverges at 3 key(s):\n  6368616e676564\n  6f6e6c792061\n  6f6e6c792062\n"
+        );
+    }
+}